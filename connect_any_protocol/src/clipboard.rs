@@ -0,0 +1,142 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Deserializer, Serialize};
+use strum_macros::{Display, EnumString};
+
+use crate::message::DeviceIdentity;
+
+#[derive(Deserialize, Serialize, EnumString, Display, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardDataType {
+    Text,
+    Image,
+    None,
+}
+
+/// 没有指定频道的剪切板都属于这个频道
+pub const DEFAULT_CHANNEL: &str = "default";
+
+// WsMessage 是用 `type` 区分的枚举, serde 缓存消息内容时不支持 u128, 所以先按 u64 读取
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    u64::deserialize(deserializer).map(u128::from)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Clipboard {
+    // 服务器保存后分配的 id, 客户端上传时为 0
+    #[serde(default)]
+    pub id: u64,
+    pub data: String,
+    #[serde(rename = "type")]
+    pub clipboard_type: ClipboardDataType,
+    // 客户端复制的时间, 只作为参考, 排序使用服务器的 `seq`
+    #[serde(deserialize_with = "deserialize_date")]
+    pub date: u128,
+    // 服务器收到的时间 (毫秒), 由服务器填写
+    #[serde(default)]
+    pub received_at: u64,
+    // 服务器分配的递增序号, 越大越新, 由服务器填写
+    #[serde(default)]
+    pub seq: u64,
+    // 客户端时间比服务器时间超前太多, 由服务器填写
+    #[serde(default)]
+    pub future_date: bool,
+    // 上传这条剪切板的设备, 由服务器填写
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<DeviceIdentity>,
+    // 固定的剪切板不会被自动清除
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub favorite: bool,
+    // 敏感内容 (密码, 密钥等), 客户端可以根据密码管理器的提示设置, 服务器也会自动检测
+    #[serde(default)]
+    pub sensitive: bool,
+    // 只发送给这些设备 (device id), 为空时同步给所有设备
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<u64>>,
+    // 发布到的群组, 为空时是个人剪切板
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<u64>,
+    // 个人剪切板所在的频道, 为空时是 `DEFAULT_CHANNEL`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    // 去重时间内相同内容被复制的次数, 由服务器填写
+    #[serde(default)]
+    pub copy_count: u64,
+}
+
+impl Clipboard {
+    pub fn new(data: String, clipboard_type: ClipboardDataType) -> Self {
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        Clipboard {
+            id: 0,
+            data,
+            clipboard_type,
+            date,
+            received_at: 0,
+            seq: 0,
+            future_date: false,
+            source: None,
+            pinned: false,
+            favorite: false,
+            sensitive: false,
+            targets: None,
+            group_id: None,
+            channel: None,
+            copy_count: 0,
+        }
+    }
+
+    pub fn empty() -> Self {
+        Clipboard {
+            id: 0,
+            data: String::new(),
+            clipboard_type: ClipboardDataType::None,
+            date: 0,
+            received_at: 0,
+            seq: 0,
+            future_date: false,
+            source: None,
+            pinned: false,
+            favorite: false,
+            sensitive: false,
+            targets: None,
+            group_id: None,
+            channel: None,
+            copy_count: 0,
+        }
+    }
+
+    /// 敏感内容用 `*` 代替, 用于日志和历史列表
+    pub fn redacted(&self) -> Self {
+        let mut clipboard = self.clone();
+
+        if clipboard.sensitive {
+            clipboard.data = "*".repeat(clipboard.data.chars().count().min(8));
+        }
+
+        clipboard
+    }
+}
+
+impl fmt::Display for Clipboard {
+    // 这个 trait 要求 `fmt` 使用与下面的函数完全一致的函数签名
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let duration = UNIX_EPOCH + std::time::Duration::from_millis(self.date as u64);
+        let datetime = DateTime::<Local>::from(duration);
+        let formatted_datetime = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+        let output = match self.clipboard_type {
+            _ if self.sensitive => String::from("Sensitive"),
+            ClipboardDataType::Text => self.data.clone(),
+            ClipboardDataType::Image => String::from("Image"),
+            ClipboardDataType::None => String::from("None"),
+        };
+        write!(f, "Clipboard[{}]: {}", formatted_datetime, output)
+    }
+}
@@ -0,0 +1,45 @@
+//! connect-any 服务器与客户端共用的 WebSocket 协议定义
+//!
+//! 连接建立后客户端必须先发送 [`WsMessage::Hello`], 服务器协商出双方都支持的协议版本后回复
+//! [`WsMessage::Welcome`]; 版本不兼容时服务器发送 [`WsMessage::Error`] 并以
+//! [`close_code::UNSUPPORTED_VERSION`] 关闭连接. 之后的所有消息都是 JSON 文本帧, 格式见 [`WsMessage`].
+
+pub mod clipboard;
+pub mod message;
+
+pub use clipboard::{Clipboard, ClipboardDataType, DEFAULT_CHANNEL};
+pub use message::{DeviceIdentity, ErrorCode, PresenceDevice, WsMessage};
+
+/// 当前协议版本
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 服务器仍然兼容的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// 服务器关闭 WebSocket 时使用的 close code
+pub mod close_code {
+    /// 第一条消息不是合法的 `hello`
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    /// 双方没有共同支持的协议版本
+    pub const UNSUPPORTED_VERSION: u16 = 4001;
+    /// 设备未注册或不属于任何用户
+    pub const UNAUTHORIZED: u16 = 4003;
+    /// 被管理员强制断开
+    pub const DISCONNECTED: u16 = 4004;
+    /// 服务器正在关闭, 客户端可以稍后重新连接
+    pub const GOING_AWAY: u16 = 1001;
+    /// 单条消息超过了服务器的上限
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    /// 发送消息太频繁, 需要等一会儿再重新连接
+    pub const RATE_LIMITED: u16 = 4029;
+}
+
+/// 根据客户端支持的版本范围 `[min, max]` 选出双方都支持的最高版本
+pub fn negotiate_version(min: u32, max: u32) -> Option<u32> {
+    let version = max.min(PROTOCOL_VERSION);
+
+    if version >= min.max(MIN_PROTOCOL_VERSION) {
+        Some(version)
+    } else {
+        None
+    }
+}
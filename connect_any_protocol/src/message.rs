@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+
+use crate::clipboard::Clipboard;
+
+/// 设备标识, 与 HTTP 接口里的 `device` 字段格式一致
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PresenceDevice {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub online: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    UnsupportedVersion,
+    Unauthorized,
+    Internal,
+    /// 剪切板内容超过了服务器的上限
+    PayloadTooLarge,
+}
+
+/// WebSocket 上传输的所有消息, 以 `type` 字段区分
+///
+/// 握手:
+///
+/// ```json
+/// {"type": "hello", "protocol_version": 1, "min_protocol_version": 1,
+///  "device": {"name": "laptop", "type": "Linux"}}
+/// {"type": "welcome", "protocol_version": 1, "server_version": "0.1.0"}
+/// ```
+///
+/// `hello` 里的 `groups` 是要订阅的群组 id, 个人剪切板总是会订阅
+///
+/// ```json
+/// {"type": "hello", "protocol_version": 1, "device": {"name": "laptop", "type": "Linux"}, "groups": [1, 3]}
+/// ```
+///
+/// 剪切板推送 (双向) 与确认:
+///
+/// ```json
+/// {"type": "clip_push", "id": "0190...", "clip": {"data": "hi", "type": "Text", "date": 1710000000000}}
+/// {"type": "clip_ack", "id": "0190..."}
+/// ```
+///
+/// 同一个频道 (群组) 里服务器序号 `seq` 最大的剪切板是当前剪切板, 所有设备最终都同步到这一条:
+/// 客户端只应用比本地当前序号更大的 `clip_push`, 服务器确认上传时在 `clip_ack` 里带上分配的序号,
+/// 被其他设备的剪切板取代时会收到 `clip_superseded`
+///
+/// ```json
+/// {"type": "clip_ack", "id": "0190...", "clip_id": 42, "seq": 108}
+/// {"type": "clip_superseded", "clip_id": 42, "current_id": 43, "current_seq": 109}
+/// ```
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsMessage {
+    /// 客户端发送的第一条消息, 声明自己支持的协议版本范围
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        min_protocol_version: Option<u32>,
+        device: DeviceIdentity,
+        #[serde(default)]
+        groups: Vec<u64>,
+    },
+    /// 服务器对 `Hello` 的回复, 带上协商后的协议版本
+    Welcome {
+        protocol_version: u32,
+        server_version: String,
+    },
+    /// 新的剪切板内容, `id` 由发送方生成, 用于 `ClipAck`
+    ClipPush {
+        id: String,
+        clip: Clipboard,
+    },
+    /// 确认收到 `id` 对应的 `ClipPush`, 服务器确认上传时带上保存后的 id 和序号
+    ClipAck {
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clip_id: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// 设备上传的 `clip_id` 不再是当前剪切板, 被其他设备上传的 `current_id` 取代
+    ClipSuperseded {
+        clip_id: u64,
+        current_id: u64,
+        current_seq: u64,
+    },
+    /// 修改剪切板的固定/收藏状态, 为空的项保持不变
+    ClipUpdate {
+        id: u64,
+        #[serde(default)]
+        pinned: Option<bool>,
+        #[serde(default)]
+        favorite: Option<bool>,
+    },
+    /// 服务器通知所有设备剪切板状态已修改
+    ClipUpdated {
+        clip: Clipboard,
+    },
+    /// 删除剪切板
+    ClipDelete {
+        id: u64,
+    },
+    /// 服务器通知所有设备剪切板已删除, 客户端应该从本地历史中移除
+    ClipDeleted {
+        id: u64,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// 请求序号 (`seq`) 小于 `before` 的最近 `limit` 条剪切板记录, `before` 为空时从最新的开始
+    ///
+    /// `group_id` 不为空时请求群组的历史记录, `channel` 为空时返回设备订阅的所有频道
+    HistoryRequest {
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        before: Option<u64>,
+        #[serde(default)]
+        group_id: Option<u64>,
+        #[serde(default)]
+        channel: Option<String>,
+    },
+    /// 从新到旧排列, `next_cursor` 作为下一页请求的 `before`, 没有更多记录时为空
+    HistoryResponse {
+        clips: Vec<Clipboard>,
+        next_cursor: Option<u64>,
+    },
+    /// 用户所有设备的在线状态, 有设备上下线时由服务器推送
+    Presence {
+        devices: Vec<PresenceDevice>,
+    },
+}
+
+impl WsMessage {
+    pub fn error(code: ErrorCode, message: &str) -> Self {
+        WsMessage::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
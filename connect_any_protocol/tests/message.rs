@@ -0,0 +1,97 @@
+use connect_any_protocol::{
+    negotiate_version, ClipboardDataType, DeviceIdentity, WsMessage, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+
+fn round_trip(message: &WsMessage) -> WsMessage {
+    serde_json::from_str(&serde_json::to_string(message).unwrap()).unwrap()
+}
+
+#[test]
+fn clip_push_round_trips_with_date() {
+    let text = r#"{"type": "clip_push", "id": "0190", "clip": {"data": "hi", "type": "Text", "date": 1710000000000}}"#;
+    let message: WsMessage = serde_json::from_str(text).unwrap();
+
+    let WsMessage::ClipPush { id, mut clip } = message else {
+        panic!("unexpected message {:?}", message);
+    };
+    assert_eq!(id, "0190");
+    assert_eq!(clip.date, 1_710_000_000_000);
+    assert_eq!(clip.clipboard_type, ClipboardDataType::Text);
+    assert_eq!(clip.channel, None);
+
+    // 服务器填写的字段也要原样传回去
+    clip.seq = 108;
+    clip.source = Some(DeviceIdentity {
+        name: "laptop".to_string(),
+        device_type: "Linux".to_string(),
+    });
+    clip.targets = Some(vec![2, 3]);
+    clip.channel = Some("work".to_string());
+
+    let WsMessage::ClipPush { id, clip: pushed } = round_trip(&WsMessage::ClipPush { id, clip })
+    else {
+        panic!("clip_push changed type");
+    };
+    assert_eq!(id, "0190");
+    assert_eq!(pushed.date, 1_710_000_000_000);
+    assert_eq!(pushed.data, "hi");
+    assert_eq!(pushed.seq, 108);
+    assert_eq!(pushed.source.unwrap().name, "laptop");
+    assert_eq!(pushed.targets, Some(vec![2, 3]));
+    assert_eq!(pushed.channel.as_deref(), Some("work"));
+}
+
+#[test]
+fn negotiates_protocol_version() {
+    let hello = WsMessage::Hello {
+        protocol_version: PROTOCOL_VERSION + 1,
+        min_protocol_version: Some(MIN_PROTOCOL_VERSION),
+        device: DeviceIdentity {
+            name: "laptop".to_string(),
+            device_type: "Linux".to_string(),
+        },
+        groups: vec![1, 3],
+    };
+    let WsMessage::Hello {
+        protocol_version,
+        min_protocol_version,
+        groups,
+        ..
+    } = round_trip(&hello)
+    else {
+        panic!("hello changed type");
+    };
+    assert_eq!(groups, vec![1, 3]);
+
+    // 客户端更新时使用服务器支持的最高版本
+    assert_eq!(
+        negotiate_version(min_protocol_version.unwrap(), protocol_version),
+        Some(PROTOCOL_VERSION)
+    );
+    assert_eq!(
+        negotiate_version(MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION),
+        Some(MIN_PROTOCOL_VERSION)
+    );
+
+    // 没有共同支持的版本
+    assert_eq!(
+        negotiate_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2),
+        None
+    );
+    assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION - 1), None);
+
+    // 旧客户端不发送 `min_protocol_version`
+    let hello: WsMessage = serde_json::from_str(
+        r#"{"type": "hello", "protocol_version": 1, "device": {"name": "laptop", "type": "Linux"}}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        hello,
+        WsMessage::Hello {
+            min_protocol_version: None,
+            ref groups,
+            ..
+        } if groups.is_empty()
+    ));
+}
@@ -1,5 +1,6 @@
 use proc_macro::TokenStream;
 use quote::quote;
+use syn::spanned::Spanned;
 
 #[proc_macro_derive(ToSqlMacro)]
 pub fn hello_macro_derive(input: TokenStream) -> TokenStream {
@@ -23,3 +24,294 @@ fn impl_tosql_macro(ast: &syn::DeriveInput) -> TokenStream {
     };
     gen.into()
 }
+
+/// 用 `FromStr` 从文字列读取, 和 `ToSqlMacro` 配对使用
+#[proc_macro_derive(FromSqlMacro)]
+pub fn from_sql_macro_derive(input: TokenStream) -> TokenStream {
+    let ast: syn::DeriveInput = syn::parse(input).unwrap();
+
+    impl_fromsql_macro(&ast)
+}
+
+fn impl_fromsql_macro(ast: &syn::DeriveInput) -> TokenStream {
+    let name = &ast.ident;
+    let gen = quote! {
+        impl rusqlite::types::FromSql for #name {
+            #[inline]
+            fn column_result(
+                value: rusqlite::types::ValueRef<'_>,
+            ) -> rusqlite::types::FromSqlResult<Self> {
+                value
+                    .as_str()?
+                    .parse()
+                    .map_err(|err| rusqlite::types::FromSqlError::Other(Box::new(err)))
+            }
+        }
+    };
+    gen.into()
+}
+
+/// 为对应一张表的结构体生成 sql 语句和行的映射
+///
+/// ```ignore
+/// #[derive(Table)]
+/// #[table(name = "devices")]
+/// struct DatabaseDevice {
+///     #[table(primary_key)]
+///     id: u64,
+///     name: String,
+///     #[table(column = "type")]
+///     device_type: DeviceType,
+/// }
+/// ```
+///
+/// 没有标记 `primary_key` 时使用名为 `id` 的字段, 主键由数据库分配, 不出现在 insert 语句里
+///
+/// 标记了 `primary_key` 的字段由调用者提供, 会写入 insert 语句,
+/// 由数据库分配时标记为 `#[table(primary_key, auto)]`
+#[proc_macro_derive(Table, attributes(table))]
+pub fn table_derive(input: TokenStream) -> TokenStream {
+    let ast: syn::DeriveInput = syn::parse(input).unwrap();
+
+    match impl_table(&ast) {
+        Ok(gen) => gen.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+struct Column {
+    ident: syn::Ident,
+    name: String,
+    primary_key: bool,
+    // 主键由数据库分配, 不出现在 insert 语句里
+    auto: bool,
+}
+
+// 读取 `#[table(...)]` 里的所有项
+fn table_args(attrs: &[syn::Attribute]) -> syn::Result<Vec<syn::NestedMeta>> {
+    let mut args = Vec::new();
+
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("table")) {
+        match attr.parse_meta()? {
+            syn::Meta::List(list) => args.extend(list.nested),
+            meta => return Err(syn::Error::new(meta.span(), "expected #[table(...)]")),
+        }
+    }
+
+    Ok(args)
+}
+
+fn string_value(meta: &syn::MetaNameValue) -> syn::Result<String> {
+    match &meta.lit {
+        syn::Lit::Str(lit) if !lit.value().is_empty() => Ok(lit.value()),
+        lit => Err(syn::Error::new(lit.span(), "expected a non-empty string")),
+    }
+}
+
+fn table_name(ast: &syn::DeriveInput) -> syn::Result<String> {
+    let mut name = None;
+
+    for arg in table_args(&ast.attrs)? {
+        match arg {
+            syn::NestedMeta::Meta(syn::Meta::NameValue(meta)) if meta.path.is_ident("name") => {
+                if name.is_some() {
+                    return Err(syn::Error::new(meta.span(), "duplicate table name"));
+                }
+                name = Some(string_value(&meta)?);
+            }
+            arg => {
+                return Err(syn::Error::new(
+                    arg.span(),
+                    "unknown table attribute, expected `name = \"...\"`",
+                ))
+            }
+        }
+    }
+
+    name.ok_or_else(|| {
+        syn::Error::new(
+            ast.ident.span(),
+            "missing #[table(name = \"...\")] on the struct",
+        )
+    })
+}
+
+fn table_column(field: &syn::Field) -> syn::Result<Column> {
+    let ident = field.ident.clone().unwrap();
+    let mut column = Column {
+        name: ident.to_string(),
+        ident,
+        primary_key: false,
+        auto: false,
+    };
+
+    for arg in table_args(&field.attrs)? {
+        match arg {
+            syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("primary_key") => {
+                column.primary_key = true;
+            }
+            syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("auto") => {
+                column.auto = true;
+            }
+            syn::NestedMeta::Meta(syn::Meta::NameValue(meta)) if meta.path.is_ident("column") => {
+                column.name = string_value(&meta)?;
+            }
+            arg => return Err(syn::Error::new(
+                arg.span(),
+                "unknown column attribute, expected `primary_key`, `auto` or `column = \"...\"`",
+            )),
+        }
+    }
+
+    if column.auto && !column.primary_key {
+        return Err(syn::Error::new(
+            column.ident.span(),
+            "`auto` can only be used with `primary_key`",
+        ));
+    }
+
+    Ok(column)
+}
+
+fn impl_table(ast: &syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &ast.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => {
+            return Err(syn::Error::new(
+                ast.ident.span(),
+                "Table can only be derived for structs with named fields",
+            ))
+        }
+    };
+
+    let table = table_name(ast)?;
+    let mut columns = fields
+        .iter()
+        .map(table_column)
+        .collect::<syn::Result<Vec<Column>>>()?;
+
+    let marked: Vec<&Column> = columns.iter().filter(|column| column.primary_key).collect();
+    if marked.len() > 1 {
+        return Err(syn::Error::new(
+            marked[1].ident.span(),
+            "only one field can be the primary key",
+        ));
+    }
+    if marked.is_empty() {
+        match columns.iter_mut().find(|column| column.ident == "id") {
+            Some(column) => {
+                column.primary_key = true;
+                column.auto = true;
+            }
+            None => {
+                return Err(syn::Error::new(
+                    ast.ident.span(),
+                    "missing primary key, add #[table(primary_key)] or an `id` field",
+                ))
+            }
+        }
+    }
+
+    let key = columns.iter().find(|column| column.primary_key).unwrap();
+    let values: Vec<&Column> = columns
+        .iter()
+        .filter(|column| !column.primary_key)
+        .collect();
+
+    let inserts: Vec<&Column> = columns.iter().filter(|column| !column.auto).collect();
+
+    let names: Vec<&str> = columns.iter().map(|column| column.name.as_str()).collect();
+    let value_names: Vec<&str> = values.iter().map(|column| column.name.as_str()).collect();
+    let placeholders: Vec<String> = (1..=values.len()).map(|i| format!("?{}", i)).collect();
+
+    let insert_sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table,
+        inserts
+            .iter()
+            .map(|column| column.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", "),
+        (1..=inserts.len())
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    let update_sql = format!(
+        "UPDATE {} SET {} WHERE {} = ?{}",
+        table,
+        value_names
+            .iter()
+            .zip(placeholders.iter())
+            .map(|(name, placeholder)| format!("{} = {}", name, placeholder))
+            .collect::<Vec<String>>()
+            .join(", "),
+        key.name,
+        values.len() + 1
+    );
+    let select_sql = format!(
+        "SELECT {} FROM {} WHERE {} = ?1",
+        names.join(", "),
+        table,
+        key.name
+    );
+
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let key_ident = &key.ident;
+    let key_name = &key.name;
+    let value_idents: Vec<&syn::Ident> = values.iter().map(|column| &column.ident).collect();
+    let idents: Vec<&syn::Ident> = columns.iter().map(|column| &column.ident).collect();
+    let insert_idents: Vec<&syn::Ident> = inserts.iter().map(|column| &column.ident).collect();
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            pub const TABLE_NAME: &'static str = #table;
+            pub const COLUMNS: &'static [&'static str] = &[#(#names),*];
+            pub const PRIMARY_KEY: &'static str = #key_name;
+            pub const INSERT_SQL: &'static str = #insert_sql;
+            pub const UPDATE_SQL: &'static str = #update_sql;
+            pub const SELECT_BY_ID_SQL: &'static str = #select_sql;
+
+            /// 按列名读取一行, 查询里需要包含 `COLUMNS` 里所有的列
+            pub fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                Ok(Self {
+                    #(#idents: row.get(#names)?,)*
+                })
+            }
+
+            /// 插入除了数据库分配的主键以外的列, 返回这一行的 rowid
+            pub fn insert(&self, conn: &rusqlite::Connection) -> rusqlite::Result<i64> {
+                conn.execute(Self::INSERT_SQL, rusqlite::params![#(self.#insert_idents),*])?;
+
+                Ok(conn.last_insert_rowid())
+            }
+
+            /// 按主键更新其他所有的列, 返回这一行是否存在
+            pub fn update(&self, conn: &rusqlite::Connection) -> rusqlite::Result<bool> {
+                let changed = conn.execute(
+                    Self::UPDATE_SQL,
+                    rusqlite::params![#(self.#value_idents,)* self.#key_ident],
+                )?;
+
+                Ok(changed > 0)
+            }
+
+            pub fn select_by_id<K: rusqlite::ToSql>(
+                conn: &rusqlite::Connection,
+                id: K,
+            ) -> rusqlite::Result<Option<Self>> {
+                let mut stmt = conn.prepare(Self::SELECT_BY_ID_SQL)?;
+                let mut rows = stmt.query([id])?;
+
+                match rows.next()? {
+                    Some(row) => Ok(Some(Self::from_row(row)?)),
+                    None => Ok(None),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,154 @@
+use rusqlite::Connection;
+use rustsqlite_derive::{FromSqlMacro, Table, ToSqlMacro};
+use strum_macros::{Display, EnumString};
+
+#[derive(EnumString, Display, ToSqlMacro, FromSqlMacro, Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Laptop,
+    Phone,
+}
+
+#[derive(Table, Debug, PartialEq)]
+#[table(name = "devices")]
+struct Device {
+    id: u64,
+    name: String,
+    #[table(column = "type")]
+    kind: Kind,
+}
+
+#[derive(Table, Debug, PartialEq)]
+#[table(name = "settings")]
+struct Setting {
+    #[table(primary_key)]
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Table, Debug, PartialEq)]
+#[table(name = "counters")]
+struct Counter {
+    #[table(primary_key, auto)]
+    counter_id: u64,
+    count: i64,
+}
+
+fn connection() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE devices (id INTEGER PRIMARY KEY, name TEXT NOT NULL, type TEXT NOT NULL);
+         CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT);
+         CREATE TABLE counters (counter_id INTEGER PRIMARY KEY, count INTEGER NOT NULL);",
+    )
+    .unwrap();
+    conn
+}
+
+#[test]
+fn generates_sql() {
+    assert_eq!(Device::TABLE_NAME, "devices");
+    assert_eq!(Device::COLUMNS, &["id", "name", "type"]);
+    assert_eq!(Device::PRIMARY_KEY, "id");
+    assert_eq!(
+        Device::INSERT_SQL,
+        "INSERT INTO devices (name, type) VALUES (?1, ?2)"
+    );
+    assert_eq!(
+        Device::UPDATE_SQL,
+        "UPDATE devices SET name = ?1, type = ?2 WHERE id = ?3"
+    );
+    assert_eq!(
+        Device::SELECT_BY_ID_SQL,
+        "SELECT id, name, type FROM devices WHERE id = ?1"
+    );
+
+    assert_eq!(Setting::PRIMARY_KEY, "key");
+    assert_eq!(
+        Setting::INSERT_SQL,
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)"
+    );
+
+    assert_eq!(Counter::PRIMARY_KEY, "counter_id");
+    assert_eq!(
+        Counter::INSERT_SQL,
+        "INSERT INTO counters (count) VALUES (?1)"
+    );
+}
+
+#[test]
+fn maps_rows() {
+    let conn = connection();
+
+    let mut device = Device {
+        id: 0,
+        name: "laptop".to_string(),
+        kind: Kind::Laptop,
+    };
+    device.id = device.insert(&conn).unwrap() as u64;
+    assert_eq!(
+        Device::select_by_id(&conn, device.id).unwrap(),
+        Some(device)
+    );
+
+    let phone = Device {
+        id: 1,
+        name: "phone".to_string(),
+        kind: Kind::Phone,
+    };
+    assert!(phone.update(&conn).unwrap());
+    assert_eq!(Device::select_by_id(&conn, 1).unwrap(), Some(phone));
+    assert_eq!(Device::select_by_id(&conn, 2).unwrap(), None);
+
+    // 不认识的文字列读取失败
+    conn.execute("UPDATE devices SET type = 'Tablet'", [])
+        .unwrap();
+    assert!(Device::select_by_id(&conn, 1).is_err());
+}
+
+#[test]
+fn uses_marked_primary_key() {
+    let conn = connection();
+
+    let mut setting = Setting {
+        key: "theme".to_string(),
+        value: None,
+    };
+    setting.insert(&conn).unwrap();
+    assert_eq!(
+        Setting::select_by_id(&conn, "theme").unwrap(),
+        Some(setting)
+    );
+    // 主键已经存在时插入失败
+    assert!(Setting {
+        key: "theme".to_string(),
+        value: None,
+    }
+    .insert(&conn)
+    .is_err());
+
+    setting = Setting {
+        key: "theme".to_string(),
+        value: Some("dark".to_string()),
+    };
+    assert!(setting.update(&conn).unwrap());
+    assert_eq!(
+        Setting::select_by_id(&conn, "theme").unwrap(),
+        Some(setting)
+    );
+
+    let mut counter = Counter {
+        counter_id: 0,
+        count: 3,
+    };
+    counter.counter_id = counter.insert(&conn).unwrap() as u64;
+    assert_eq!(
+        Counter::select_by_id(&conn, counter.counter_id).unwrap(),
+        Some(counter)
+    );
+}
+
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
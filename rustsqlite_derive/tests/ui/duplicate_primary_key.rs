@@ -0,0 +1,12 @@
+use rustsqlite_derive::Table;
+
+#[derive(Table)]
+#[table(name = "users")]
+struct User {
+    #[table(primary_key)]
+    id: u64,
+    #[table(primary_key)]
+    name: String,
+}
+
+fn main() {}
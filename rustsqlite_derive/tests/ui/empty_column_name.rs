@@ -0,0 +1,11 @@
+use rustsqlite_derive::Table;
+
+#[derive(Table)]
+#[table(name = "devices")]
+struct Device {
+    id: u64,
+    #[table(column = "")]
+    device_type: String,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use rustsqlite_derive::Table;
+
+#[derive(Table)]
+#[table(name = "users")]
+struct User {
+    user_id: u64,
+    name: String,
+}
+
+fn main() {}
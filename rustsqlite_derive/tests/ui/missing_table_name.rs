@@ -0,0 +1,9 @@
+use rustsqlite_derive::Table;
+
+#[derive(Table)]
+struct User {
+    id: u64,
+    name: String,
+}
+
+fn main() {}
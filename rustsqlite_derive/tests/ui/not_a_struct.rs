@@ -0,0 +1,10 @@
+use rustsqlite_derive::Table;
+
+#[derive(Table)]
+#[table(name = "types")]
+enum DeviceType {
+    Linux,
+    Mac,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use rustsqlite_derive::Table;
+
+#[derive(Table)]
+#[table(name = "users")]
+struct User {
+    id: u64,
+    #[table(rename = "user_name")]
+    name: String,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+CREATE TABLE clips (
+id integer primary key autoincrement,
+user_id integer,
+device_id integer,
+type text,
+data text,
+date integer,
+CONSTRAINT fk_users FOREIGN KEY (user_id) REFERENCES users(id),
+CONSTRAINT fk_devices FOREIGN KEY (device_id) REFERENCES devices(id)
+);
+
+CREATE INDEX clips_user_id ON clips (user_id, id);
@@ -0,0 +1,24 @@
+CREATE VIRTUAL TABLE clips_fts USING fts5 (
+data,
+content = 'clips',
+content_rowid = 'id',
+tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO clips_fts (rowid, data) SELECT id, data FROM clips WHERE type == 'Text';
+
+CREATE TRIGGER clips_fts_insert AFTER INSERT ON clips WHEN new.type == 'Text' BEGIN
+INSERT INTO clips_fts (rowid, data) VALUES (new.id, new.data);
+END;
+
+CREATE TRIGGER clips_fts_delete AFTER DELETE ON clips WHEN old.type == 'Text' BEGIN
+INSERT INTO clips_fts (clips_fts, rowid, data) VALUES ('delete', old.id, old.data);
+END;
+
+CREATE TRIGGER clips_fts_update_delete AFTER UPDATE OF data, type ON clips WHEN old.type == 'Text' BEGIN
+INSERT INTO clips_fts (clips_fts, rowid, data) VALUES ('delete', old.id, old.data);
+END;
+
+CREATE TRIGGER clips_fts_update_insert AFTER UPDATE OF data, type ON clips WHEN new.type == 'Text' BEGIN
+INSERT INTO clips_fts (rowid, data) VALUES (new.id, new.data);
+END;
@@ -0,0 +1,15 @@
+ALTER TABLE clips ADD COLUMN size integer NOT NULL DEFAULT 0;
+
+ALTER TABLE clips ADD COLUMN blob text;
+
+ALTER TABLE clips ADD COLUMN pinned integer NOT NULL DEFAULT 0;
+
+UPDATE clips SET size = length(CAST(data AS BLOB));
+
+CREATE TABLE retention_policies (
+user_id integer primary key,
+max_count integer,
+max_age_days integer,
+max_bytes integer,
+CONSTRAINT fk_users FOREIGN KEY (user_id) REFERENCES users(id)
+);
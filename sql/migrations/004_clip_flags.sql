@@ -0,0 +1 @@
+ALTER TABLE clips ADD COLUMN favorite integer NOT NULL DEFAULT 0;
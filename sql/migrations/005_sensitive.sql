@@ -0,0 +1,27 @@
+ALTER TABLE clips ADD COLUMN sensitive integer NOT NULL DEFAULT 0;
+
+DROP TRIGGER clips_fts_insert;
+
+DROP TRIGGER clips_fts_delete;
+
+DROP TRIGGER clips_fts_update_delete;
+
+DROP TRIGGER clips_fts_update_insert;
+
+CREATE TRIGGER clips_fts_insert AFTER INSERT ON clips WHEN new.type == 'Text' and new.sensitive == 0 BEGIN
+INSERT INTO clips_fts (rowid, data) VALUES (new.id, new.data);
+END;
+
+CREATE TRIGGER clips_fts_delete AFTER DELETE ON clips WHEN old.type == 'Text' and old.sensitive == 0 BEGIN
+INSERT INTO clips_fts (clips_fts, rowid, data) VALUES ('delete', old.id, old.data);
+END;
+
+CREATE TRIGGER clips_fts_update_delete AFTER UPDATE OF data, type, sensitive ON clips WHEN old.type == 'Text' and old.sensitive == 0 BEGIN
+INSERT INTO clips_fts (clips_fts, rowid, data) VALUES ('delete', old.id, old.data);
+END;
+
+CREATE TRIGGER clips_fts_update_insert AFTER UPDATE OF data, type, sensitive ON clips WHEN new.type == 'Text' and new.sensitive == 0 BEGIN
+INSERT INTO clips_fts (rowid, data) VALUES (new.id, new.data);
+END;
+
+ALTER TABLE retention_policies ADD COLUMN sensitive_ttl_secs integer;
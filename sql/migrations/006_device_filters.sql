@@ -0,0 +1,5 @@
+CREATE TABLE device_filters (
+device_id integer primary key,
+rules text,
+CONSTRAINT fk_devices FOREIGN KEY (device_id) REFERENCES devices(id)
+);
@@ -0,0 +1,2 @@
+-- 定向发送的目标设备 id, json 数组, 为空时表示同步给所有设备
+ALTER TABLE clips ADD COLUMN targets text;
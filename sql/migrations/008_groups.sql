@@ -0,0 +1,20 @@
+CREATE TABLE clip_groups (
+id integer primary key autoincrement,
+name text NOT NULL UNIQUE,
+owner_id integer,
+CONSTRAINT fk_users FOREIGN KEY (owner_id) REFERENCES users(id)
+);
+
+CREATE TABLE group_members (
+group_id integer,
+user_id integer,
+role text NOT NULL,
+PRIMARY KEY (group_id, user_id),
+CONSTRAINT fk_groups FOREIGN KEY (group_id) REFERENCES clip_groups(id),
+CONSTRAINT fk_users FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- 发布到群组的剪切板, 为空时是个人剪切板
+ALTER TABLE clips ADD COLUMN group_id integer REFERENCES clip_groups(id);
+
+CREATE INDEX clips_group_id ON clips (group_id, id);
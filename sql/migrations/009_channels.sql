@@ -0,0 +1,20 @@
+-- 用户自己创建的频道, default 频道不需要创建
+CREATE TABLE channels (
+id integer primary key autoincrement,
+user_id integer,
+name text NOT NULL,
+UNIQUE (user_id, name),
+CONSTRAINT fk_users FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- 设备订阅的频道, 没有记录时只订阅 default 频道
+CREATE TABLE device_channels (
+device_id integer,
+channel text NOT NULL,
+PRIMARY KEY (device_id, channel),
+CONSTRAINT fk_devices FOREIGN KEY (device_id) REFERENCES devices(id)
+);
+
+ALTER TABLE clips ADD COLUMN channel text NOT NULL DEFAULT 'default';
+
+CREATE INDEX clips_channel ON clips (user_id, channel, id);
@@ -0,0 +1,7 @@
+-- 剪切板内容的 sha256, 用于去重
+ALTER TABLE clips ADD COLUMN hash text;
+
+-- 去重时间内相同内容被复制的次数
+ALTER TABLE clips ADD COLUMN copy_count integer NOT NULL DEFAULT 1;
+
+CREATE INDEX clips_hash ON clips (user_id, hash);
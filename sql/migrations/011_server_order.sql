@@ -0,0 +1,22 @@
+-- 服务器收到剪切板的时间 (毫秒), date 是客户端上传的时间
+ALTER TABLE clips ADD COLUMN received_at integer NOT NULL DEFAULT 0;
+
+-- 服务器分配的递增序号, 历史记录和最新的剪切板都按序号排序
+ALTER TABLE clips ADD COLUMN seq integer NOT NULL DEFAULT 0;
+
+-- 客户端时间比服务器时间超前太多
+ALTER TABLE clips ADD COLUMN future_date integer NOT NULL DEFAULT 0;
+
+-- 以前的剪切板按照收到的顺序保存, 没有服务器时间时使用客户端时间
+UPDATE clips SET received_at = date, seq = id;
+
+-- 只有一行, 记录最后分配的序号, 删除剪切板后序号也不会重复
+CREATE TABLE clip_sequence (
+    id integer PRIMARY KEY CHECK (id = 1),
+    value integer NOT NULL
+);
+
+INSERT INTO clip_sequence (id, value) SELECT 1, coalesce(max(id), 0) FROM clips;
+
+CREATE INDEX clips_user_seq ON clips (user_id, seq);
+CREATE INDEX clips_group_seq ON clips (group_id, seq);
@@ -0,0 +1,65 @@
+-- 用户名和设备的 (名字, 类型) 不能重复, 一个设备只属于一个用户, 同时注册时由数据库保证
+
+-- 之前并发注册可能留下重复的记录, 合并到 id 最小的那一条
+CREATE TEMP TABLE merged_users AS
+SELECT users.id AS id, keep.id AS keep_id FROM users
+JOIN (SELECT name, MIN(id) AS id FROM users GROUP BY name) keep
+ON users.name = keep.name AND users.id != keep.id;
+
+UPDATE user_device SET user_id = (SELECT keep_id FROM merged_users WHERE id = user_device.user_id)
+WHERE user_id IN (SELECT id FROM merged_users);
+
+UPDATE clips SET user_id = (SELECT keep_id FROM merged_users WHERE id = clips.user_id)
+WHERE user_id IN (SELECT id FROM merged_users);
+
+UPDATE clip_groups SET owner_id = (SELECT keep_id FROM merged_users WHERE id = clip_groups.owner_id)
+WHERE owner_id IN (SELECT id FROM merged_users);
+
+-- 保留的用户已经有的频道, 群组成员和保留策略不覆盖
+UPDATE OR IGNORE channels SET user_id = (SELECT keep_id FROM merged_users WHERE id = channels.user_id)
+WHERE user_id IN (SELECT id FROM merged_users);
+DELETE FROM channels WHERE user_id IN (SELECT id FROM merged_users);
+
+UPDATE OR IGNORE group_members SET user_id = (SELECT keep_id FROM merged_users WHERE id = group_members.user_id)
+WHERE user_id IN (SELECT id FROM merged_users);
+DELETE FROM group_members WHERE user_id IN (SELECT id FROM merged_users);
+
+UPDATE OR IGNORE retention_policies SET user_id = (SELECT keep_id FROM merged_users WHERE id = retention_policies.user_id)
+WHERE user_id IN (SELECT id FROM merged_users);
+DELETE FROM retention_policies WHERE user_id IN (SELECT id FROM merged_users);
+
+DELETE FROM users WHERE id IN (SELECT id FROM merged_users);
+
+DROP TABLE merged_users;
+
+CREATE TEMP TABLE merged_devices AS
+SELECT devices.id AS id, keep.id AS keep_id FROM devices
+JOIN (SELECT name, type, MIN(id) AS id FROM devices GROUP BY name, type) keep
+ON devices.name = keep.name AND devices.type = keep.type AND devices.id != keep.id;
+
+UPDATE user_device SET device_id = (SELECT keep_id FROM merged_devices WHERE id = user_device.device_id)
+WHERE device_id IN (SELECT id FROM merged_devices);
+
+UPDATE clips SET device_id = (SELECT keep_id FROM merged_devices WHERE id = clips.device_id)
+WHERE device_id IN (SELECT id FROM merged_devices);
+
+UPDATE OR IGNORE device_filters SET device_id = (SELECT keep_id FROM merged_devices WHERE id = device_filters.device_id)
+WHERE device_id IN (SELECT id FROM merged_devices);
+DELETE FROM device_filters WHERE device_id IN (SELECT id FROM merged_devices);
+
+UPDATE OR IGNORE device_channels SET device_id = (SELECT keep_id FROM merged_devices WHERE id = device_channels.device_id)
+WHERE device_id IN (SELECT id FROM merged_devices);
+DELETE FROM device_channels WHERE device_id IN (SELECT id FROM merged_devices);
+
+DELETE FROM devices WHERE id IN (SELECT id FROM merged_devices);
+
+DROP TABLE merged_devices;
+
+-- 一个设备属于多个用户时只保留最早的关系
+DELETE FROM user_device WHERE id NOT IN (SELECT MIN(id) FROM user_device GROUP BY device_id);
+
+CREATE UNIQUE INDEX users_name ON users(name);
+
+CREATE UNIQUE INDEX devices_name_type ON devices(name, type);
+
+CREATE UNIQUE INDEX user_device_device ON user_device(device_id);
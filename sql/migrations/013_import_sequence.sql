@@ -0,0 +1,7 @@
+-- 导入的历史记录使用比所有剪切板都小的序号, 从 import_value 往下分配, 不会修改已有剪切板的序号
+-- 已有的序号整体加上 2^40 给导入的剪切板留出空间, 顺序不变, 和 `IMPORT_SEQ_BASE` 一致
+UPDATE clips SET seq = seq + 1099511627776;
+
+UPDATE clip_sequence SET value = value + 1099511627776;
+
+ALTER TABLE clip_sequence ADD COLUMN import_value integer NOT NULL DEFAULT 1099511627776;
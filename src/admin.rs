@@ -0,0 +1,102 @@
+use axum::http::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+use crate::config::AdminConfig;
+use crate::datalayer::blob::blob_usage;
+use crate::datalayer::database::{DatabaseUser, DatabaseUserStorage};
+use crate::datalayer::storage::Storage;
+use crate::utils::{ba_error, sha256_hex, BDEResult};
+
+/// 管理员角色, `Viewer` 只能查看, `Admin` 还可以断开连接和修改用户数据
+#[derive(Deserialize, Serialize, Display, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AdminRole {
+    #[default]
+    Admin,
+    Viewer,
+}
+
+impl AdminRole {
+    pub fn can_manage(&self) -> bool {
+        matches!(self, AdminRole::Admin)
+    }
+}
+
+/// 配置文件里的管理员账号, 和设备分开认证
+#[derive(Deserialize, Debug, Clone)]
+pub struct AdminAccount {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub role: AdminRole,
+}
+
+/// 用 `Authorization: Bearer <token>` 认证管理员, `manage` 为 true 时需要 `Admin` 角色
+pub fn authenticate(
+    config: &AdminConfig,
+    headers: &HeaderMap,
+    manage: bool,
+) -> BDEResult<AdminAccount> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ba_error("admin token required"))?;
+
+    // 比较 hash, 避免逐字节比较泄露 token 的前缀
+    let hash = sha256_hex(token.as_bytes());
+
+    let account = config
+        .accounts
+        .iter()
+        .find(|account| !account.token.is_empty() && sha256_hex(account.token.as_bytes()) == hash)
+        .ok_or_else(|| ba_error("invalid admin token"))?;
+
+    if manage && !account.role.can_manage() {
+        return Err(ba_error("no permission"));
+    }
+
+    Ok(account.clone())
+}
+
+#[derive(Serialize)]
+pub struct StorageReport {
+    pub users: Vec<DatabaseUserStorage>,
+    // blob 目录里的文件, 相同的图片只保存一份, 所以可能比用户的 blob_bytes 之和小
+    pub blob_files: usize,
+    pub blob_bytes: u64,
+}
+
+pub fn storage_report(storage: &dyn Storage) -> BDEResult<StorageReport> {
+    let (blob_files, blob_bytes) = blob_usage()?;
+
+    Ok(StorageReport {
+        users: storage.get_user_storage()?,
+        blob_files,
+        blob_bytes,
+    })
+}
+
+fn check_user(storage: &dyn Storage, user_id: u64) -> BDEResult<DatabaseUser> {
+    storage
+        .get_user(user_id)?
+        .ok_or_else(|| ba_error("not find user"))
+}
+
+/// 删除用户的个人剪切板, 不再被引用的 blob 由清理任务删除
+pub fn purge_user_history(
+    storage: &dyn Storage,
+    user_id: u64,
+    keep_pinned: bool,
+) -> BDEResult<usize> {
+    check_user(storage, user_id)?;
+
+    storage.delete_user_clips(user_id, keep_pinned)
+}
+
+/// 设备用名字和类型登录, 删除用户所有的设备后需要重新注册, 返回删除的设备 id
+pub fn reset_user_devices(storage: &dyn Storage, user_id: u64) -> BDEResult<Vec<u64>> {
+    check_user(storage, user_id)?;
+
+    storage.delete_user_devices(user_id)
+}
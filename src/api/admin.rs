@@ -0,0 +1,313 @@
+use axum::{
+    debug_handler,
+    extract::{Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use connect_any_protocol::close_code;
+use serde::{Deserialize, Serialize};
+
+use super::return_base_res;
+use crate::admin::{authenticate, purge_user_history, reset_user_devices, storage_report};
+use crate::backup::run_backup;
+use crate::datalayer::database::DatabaseDeviceOwner;
+use crate::state::AppState;
+use crate::utils::ba_error;
+use crate::utils::database::run_blocking;
+
+// 管理接口列表每页默认和最多返回的条数
+const DEFAULT_ADMIN_LIMIT: usize = 50;
+const MAX_ADMIN_LIMIT: usize = 500;
+
+fn page_limit(limit: Option<usize>) -> usize {
+    limit
+        .unwrap_or(DEFAULT_ADMIN_LIMIT)
+        .clamp(1, MAX_ADMIN_LIMIT)
+}
+
+#[derive(Deserialize)]
+pub struct InputAdminUsers {
+    // 按名字模糊查找, 为空时返回所有用户
+    q: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// `GET /admin/users?q=..`
+#[debug_handler]
+pub async fn list_users(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(payload): Query<InputAdminUsers>,
+) -> impl IntoResponse {
+    let handler = move || {
+        authenticate(&state.config.admin, &headers, false)?;
+
+        state.storage.search_users(
+            payload.q.as_deref().unwrap_or_default(),
+            page_limit(payload.limit),
+            payload.offset.unwrap_or(0),
+        )
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputAdminDevices {
+    q: Option<String>,
+    user_id: Option<u64>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct AdminDevice {
+    #[serde(flatten)]
+    device: DatabaseDeviceOwner,
+    // 在线的 websocket 连接数
+    sessions: usize,
+}
+
+/// `GET /admin/devices?q=..&user_id=..`
+#[debug_handler]
+pub async fn list_devices(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(payload): Query<InputAdminDevices>,
+) -> impl IntoResponse {
+    let handler = || async {
+        authenticate(&state.config.admin, &headers, false)?;
+
+        let storage = state.storage.clone();
+        let devices = run_blocking(move || {
+            storage.search_devices(
+                payload.q.as_deref().unwrap_or_default(),
+                payload.user_id,
+                page_limit(payload.limit),
+                payload.offset.unwrap_or(0),
+            )
+        })
+        .await?;
+
+        let ws_sessions = state.ws_sessions.lock().await;
+
+        Ok(devices
+            .into_iter()
+            .map(|device| AdminDevice {
+                sessions: ws_sessions
+                    .values()
+                    .filter(|session| session.device_id == device.id)
+                    .count(),
+                device,
+            })
+            .collect::<Vec<AdminDevice>>())
+    };
+
+    Json(return_base_res(handler().await))
+}
+
+/// `GET /admin/storage`, 每个用户占用的空间
+#[debug_handler]
+pub async fn get_storage(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let handler = move || {
+        authenticate(&state.config.admin, &headers, false)?;
+
+        storage_report(state.storage.as_ref())
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputAdminSessions {
+    user_id: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    id: String,
+    user_id: u64,
+    device_id: u64,
+    addr: String,
+    connected_at: u64,
+}
+
+/// `GET /admin/sessions?user_id=..`, 在线的 websocket 连接
+#[debug_handler]
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(payload): Query<InputAdminSessions>,
+) -> impl IntoResponse {
+    let handler = || async {
+        authenticate(&state.config.admin, &headers, false)?;
+
+        let ws_sessions = state.ws_sessions.lock().await;
+
+        let mut sessions: Vec<SessionInfo> = ws_sessions
+            .iter()
+            .filter(|(_, session)| payload.user_id.is_none_or(|id| session.user_id == id))
+            .map(|(id, session)| SessionInfo {
+                id: id.clone(),
+                user_id: session.user_id,
+                device_id: session.device_id,
+                addr: session.addr.to_string(),
+                connected_at: session.connected_at,
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(sessions)
+    };
+
+    Json(return_base_res(handler().await))
+}
+
+#[derive(Deserialize)]
+pub struct InputAdminDisconnect {
+    // 至少指定一项, 同时指定时要全部匹配
+    session_id: Option<String>,
+    device_id: Option<u64>,
+    user_id: Option<u64>,
+}
+
+/// 强制断开 websocket 连接, 返回断开的数量
+#[debug_handler]
+pub async fn disconnect(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<InputAdminDisconnect>,
+) -> impl IntoResponse {
+    let handler = || async {
+        let admin = authenticate(&state.config.admin, &headers, true)?;
+
+        if payload.session_id.is_none() && payload.device_id.is_none() && payload.user_id.is_none()
+        {
+            return Err(ba_error("session_id, device_id or user_id is required"));
+        }
+
+        let count = state
+            .disconnect_sessions(
+                |id, session| {
+                    payload
+                        .session_id
+                        .as_deref()
+                        .is_none_or(|session_id| session_id == id)
+                        && payload
+                            .device_id
+                            .is_none_or(|device_id| session.device_id == device_id)
+                        && payload
+                            .user_id
+                            .is_none_or(|user_id| session.user_id == user_id)
+                },
+                close_code::DISCONNECTED,
+            )
+            .await;
+
+        tracing::info!("admin ({}) disconnected {} sessions", admin.name, count);
+
+        Ok(count)
+    };
+
+    Json(return_base_res(handler().await))
+}
+
+#[derive(Deserialize)]
+pub struct InputAdminUser {
+    user_id: u64,
+}
+
+/// 删除用户所有的设备并断开连接, 设备需要重新注册, 返回删除的设备 id
+#[debug_handler]
+pub async fn reset_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<InputAdminUser>,
+) -> impl IntoResponse {
+    let handler = || async {
+        let admin = authenticate(&state.config.admin, &headers, true)?;
+
+        let (storage, user_id) = (state.storage.clone(), payload.user_id);
+        let device_ids =
+            run_blocking(move || reset_user_devices(storage.as_ref(), user_id)).await?;
+
+        state
+            .disconnect_sessions(
+                |_, session| session.user_id == payload.user_id,
+                close_code::DISCONNECTED,
+            )
+            .await;
+        state.reset_user_data(payload.user_id).await;
+
+        tracing::info!(
+            "admin ({}) reset {} devices of user {}",
+            admin.name,
+            device_ids.len(),
+            payload.user_id
+        );
+
+        Ok(device_ids)
+    };
+
+    Json(return_base_res(handler().await))
+}
+
+#[derive(Deserialize)]
+pub struct InputAdminPurge {
+    user_id: u64,
+    // 为 true 时保留固定的剪切板
+    keep_pinned: Option<bool>,
+}
+
+/// 删除用户的个人历史记录, 返回删除的数量
+#[debug_handler]
+pub async fn purge_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<InputAdminPurge>,
+) -> impl IntoResponse {
+    let handler = || async {
+        let admin = authenticate(&state.config.admin, &headers, true)?;
+
+        let (storage, user_id) = (state.storage.clone(), payload.user_id);
+        let keep_pinned = payload.keep_pinned.unwrap_or(false);
+        let purged =
+            run_blocking(move || purge_user_history(storage.as_ref(), user_id, keep_pinned))
+                .await?;
+
+        state.reset_user_data(payload.user_id).await;
+
+        tracing::info!(
+            "admin ({}) purged {} clips of user {}",
+            admin.name,
+            purged,
+            payload.user_id
+        );
+
+        Ok(purged)
+    };
+
+    Json(return_base_res(handler().await))
+}
+
+/// 立即保存一份包含数据库和 blob 的快照, 并按配置轮换旧的快照
+#[debug_handler]
+pub async fn backup(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let handler = move || {
+        let admin = authenticate(&state.config.admin, &headers, true)?;
+
+        let backup = state
+            .backup
+            .as_ref()
+            .ok_or_else(|| ba_error("backup is not available"))?;
+        let snapshot = run_backup(backup.as_ref(), &state.config.backup)?;
+
+        tracing::info!("admin ({}) saved backup {}", admin.name, snapshot.path);
+
+        Ok(snapshot)
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
@@ -0,0 +1,113 @@
+use axum::{
+    debug_handler,
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{find_target_device, return_base_res, return_bool_res};
+use crate::datalayer::channel::{
+    create_channel, delete_channel, device_channels, set_device_channels, user_channels,
+};
+use crate::datalayer::{InputDevice, User};
+use crate::state::AppState;
+use crate::utils::database::run_blocking;
+
+#[derive(Serialize)]
+pub struct ChannelInfo {
+    // 用户所有的频道
+    channels: Vec<String>,
+    // 当前设备订阅的频道
+    subscribed: Vec<String>,
+}
+
+#[debug_handler]
+pub async fn get_channels(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        Ok(ChannelInfo {
+            channels: user_channels(state.storage.as_ref(), user.id)?,
+            subscribed: device_channels(state.storage.as_ref(), now_device.id)?,
+        })
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputChannel {
+    device: InputDevice,
+    name: String,
+}
+
+#[debug_handler]
+pub async fn add_channel(
+    State(state): State<AppState>,
+    Json(payload): Json<InputChannel>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        create_channel(state.storage.as_ref(), user.id, payload.name.as_str())
+    };
+
+    Json(return_bool_res(run_blocking(handler).await))
+}
+
+/// 删除频道会同时删除频道里的剪切板
+#[debug_handler]
+pub async fn remove_channel(
+    State(state): State<AppState>,
+    Json(payload): Json<InputChannel>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        delete_channel(state.storage.as_ref(), user.id, payload.name.as_str())
+    };
+
+    Json(return_bool_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputSubscribeChannels {
+    device: InputDevice,
+    // 要修改的设备, 为空时表示当前设备
+    device_id: Option<u64>,
+    channels: Vec<String>,
+}
+
+/// 替换设备订阅的频道
+#[debug_handler]
+pub async fn subscribe_channels(
+    State(state): State<AppState>,
+    Json(payload): Json<InputSubscribeChannels>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let device_id = find_target_device(&user, now_device.id, payload.device_id)?;
+
+        set_device_channels(
+            state.storage.as_ref(),
+            user.id,
+            device_id,
+            &payload.channels,
+        )
+    };
+
+    Json(return_bool_res(run_blocking(handler).await))
+}
@@ -0,0 +1,154 @@
+use axum::{
+    debug_handler,
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+
+use super::{return_base_res, return_bool_res};
+use crate::datalayer::group::{
+    create_group, group_members, member_role, remove_group_member, set_group_member, user_groups,
+    GroupRole,
+};
+use crate::datalayer::storage::Storage;
+use crate::datalayer::{InputDevice, User};
+use crate::state::AppState;
+use crate::utils::database::run_blocking;
+use crate::utils::{ba_error, BDEResult};
+
+fn find_member_user(storage: &dyn Storage, name: String) -> BDEResult<User> {
+    User::find_user(storage, name)?.ok_or_else(|| ba_error("not find user"))
+}
+
+#[derive(Deserialize)]
+pub struct InputAddGroup {
+    device: InputDevice,
+    name: String,
+}
+
+#[debug_handler]
+pub async fn add_group(
+    State(state): State<AppState>,
+    Json(payload): Json<InputAddGroup>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        create_group(state.storage.as_ref(), user.id, payload.name.as_str())
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+/// 当前用户加入的所有群组
+#[debug_handler]
+pub async fn get_groups(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        user_groups(state.storage.as_ref(), user.id)
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputGroupMembers {
+    group_id: u64,
+}
+
+#[debug_handler]
+pub async fn get_group_members(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+    Query(payload): Query<InputGroupMembers>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        member_role(state.storage.as_ref(), payload.group_id, user.id)?;
+
+        group_members(state.storage.as_ref(), payload.group_id)
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputSetGroupMember {
+    device: InputDevice,
+    group_id: u64,
+    user_name: String,
+    role: GroupRole,
+}
+
+/// 添加成员或者修改成员的角色
+#[debug_handler]
+pub async fn set_member(
+    State(state): State<AppState>,
+    Json(payload): Json<InputSetGroupMember>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+        let member = find_member_user(state.storage.as_ref(), payload.user_name.clone())?;
+
+        set_group_member(
+            state.storage.as_ref(),
+            payload.group_id,
+            user.id,
+            member.id,
+            payload.role,
+        )
+    };
+
+    Json(return_bool_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputRemoveGroupMember {
+    device: InputDevice,
+    group_id: u64,
+    user_name: String,
+}
+
+#[debug_handler]
+pub async fn remove_member(
+    State(state): State<AppState>,
+    Json(payload): Json<InputRemoveGroupMember>,
+) -> impl IntoResponse {
+    let group_id = payload.group_id;
+    let storage = state.storage.clone();
+    let handler = move || {
+        let now_device = payload.device.parse(storage.as_ref())?;
+
+        let user = User::find_user_from_device(storage.as_ref(), &now_device)?;
+        let member = find_member_user(storage.as_ref(), payload.user_name.clone())?;
+
+        remove_group_member(storage.as_ref(), group_id, user.id, member.id)?;
+
+        Ok(member.id)
+    };
+
+    let (res, member_id) = match run_blocking(handler).await {
+        Ok(member_id) => (return_bool_res(Ok(())), Some(member_id)),
+        Err(err) => (return_bool_res(Err(err)), None),
+    };
+
+    if let Some(member_id) = member_id {
+        state.leave_group(group_id, member_id).await;
+    }
+
+    Json(res)
+}
@@ -0,0 +1,71 @@
+use axum::{
+    debug_handler,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use super::return_base_res;
+use crate::state::AppState;
+use crate::utils::database::run_blocking;
+use crate::utils::{unavailable_error, BDEResult};
+
+#[derive(Serialize)]
+pub struct HealthStatus {
+    database: bool,
+    shutting_down: bool,
+}
+
+// 负载均衡只看 http 状态码, 不能访问时返回 503
+fn health_response(res: BDEResult<HealthStatus>) -> Response {
+    let status = match res {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(return_base_res(res))).into_response()
+}
+
+async fn check_database(state: &AppState) -> BDEResult<()> {
+    let storage = state.storage.clone();
+
+    run_blocking(move || storage.ping())
+        .await
+        .map_err(|err| unavailable_error(format!("database unavailable: {}", err).as_str()))
+}
+
+/// `GET /healthz`, 进程在运行并且数据库可以访问
+#[debug_handler]
+pub async fn healthz(State(state): State<AppState>) -> impl IntoResponse {
+    let handler = || async {
+        check_database(&state).await?;
+
+        Ok(HealthStatus {
+            database: true,
+            shutting_down: state.is_shutting_down(),
+        })
+    };
+
+    health_response(handler().await)
+}
+
+/// `GET /readyz`, 可以接受新的请求, 开始关闭后返回 503 让负载均衡不再转发
+#[debug_handler]
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let handler = || async {
+        if state.is_shutting_down() {
+            return Err(unavailable_error("server is shutting down"));
+        }
+
+        check_database(&state).await?;
+
+        Ok(HealthStatus {
+            database: true,
+            shutting_down: false,
+        })
+    };
+
+    health_response(handler().await)
+}
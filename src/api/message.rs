@@ -1,17 +1,35 @@
-use axum::{debug_handler, extract::State, response::IntoResponse, Json};
+use axum::{
+    debug_handler,
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
 use serde::Deserialize;
 
-use crate::bark::send_bark;
-use crate::state::AppState;
-use crate::{
-    datalayer::{clipboard::Clipboard, Device, InputDevice},
-    state::ClipboardData,
+use crate::datalayer::channel::{check_channel, history_channels, user_channels};
+use crate::datalayer::clipboard::{
+    current_clipboard, find_clipboards, get_clipboard, latest_clipboard, search_clipboards,
+    ClipFilter, Clipboard, ClipboardDataType,
 };
+use crate::datalayer::filter::get_device_filter;
+use crate::datalayer::group::{group_devices, member_role};
+use crate::datalayer::InputDevice;
+use crate::metrics::{record_clip, Metrics};
+use crate::state::{AppState, ClipboardData};
+use crate::transfer::{export_csv, export_jsonl, import_clipboards, parse_import, TransferFormat};
+use crate::utils::database::run_blocking;
 
-use crate::datalayer::{DeviceType, User};
+use crate::datalayer::User;
+use crate::utils::ba_error;
 
 use super::{return_base_res, return_bool_res};
 
+// 历史记录每页默认和最多返回的条数
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+const MAX_HISTORY_LIMIT: usize = 100;
+
 #[derive(Deserialize)]
 pub struct InputAddMessage {
     device: InputDevice,
@@ -24,53 +42,19 @@ pub async fn add_message(
     Json(payload): Json<InputAddMessage>,
 ) -> impl IntoResponse {
     let handler = || async {
-        let now_device = payload.device.parse()?;
-
-        let user = User::find_user_from_device(&now_device)?;
-
-        let mut clipboard_datas = state.clipboard_datas.lock().await;
-
-        let clipboard_data = clipboard_datas
-            .entry(user.id)
-            .or_insert(ClipboardData::new());
-
-        // 超过 100 条剪切板自动清除
-        if clipboard_data.data.len() >= 100 {
-            clipboard_data.data.remove(0);
-        }
-
-        // TODO: 根据时间排序
-        clipboard_data.data.push(payload.message.clone());
-
-        // websocket
-        let ws_tx = clipboard_data.ws_tx.clone();
-        if let Err(err) = ws_tx.send(payload.message.clone()) {
-            tracing::error!("send websocket error: {}", err);
-        }
+        let now_device = state.parse_device(payload.device).await?;
 
-        // TODO: 如果 websocket 发送了, 就不添加进入需要更新的设备列表
-        let mut need_update_devices: Vec<Device> = Vec::new();
-
-        for device in user.devices.into_iter() {
-            if device != now_device {
-                need_update_devices.push(device);
-                //iPhone use bark to send message
-                // if device.device_type == DeviceType::Ios {
-                //     if let Err(err) = send_bark(device.notification, now_device.name.clone(), now_device.device_type, payload.message.data.clone()).await {
-                //         tracing::error!("send bark error: {}", err);
-                //     }
-                // } else {
-                //     need_update_devices.push(device);
-                // }
-            }
-        }
+        let user = state.find_user(&now_device).await?;
 
-        clipboard_data.devices = need_update_devices;
+        let clipboard = state
+            .add_clipboard(user, &now_device, payload.message.clone())
+            .await?;
 
-        tracing::info!(
-            "device ({}) add message: {}",
-            now_device.name,
-            payload.message
+        tracing::info!("device ({}) add message: {}", now_device.name, clipboard);
+        record_clip(
+            &Metrics::global().clips_received,
+            &clipboard,
+            "/message/addmessage",
         );
 
         Ok(())
@@ -82,6 +66,8 @@ pub async fn add_message(
 #[derive(Deserialize)]
 pub struct InputMessageUpdateBase {
     device: InputDevice,
+    // 取群组里的剪切板, 为空时取个人剪切板
+    group_id: Option<u64>,
 }
 
 #[debug_handler]
@@ -90,21 +76,81 @@ pub async fn message_update_base(
     Json(payload): Json<InputMessageUpdateBase>,
 ) -> impl IntoResponse {
     let handler = || async {
-        let now_device = payload.device.parse()?;
+        let now_device = state.parse_device(payload.device).await?;
+
+        let user = state.find_user(&now_device).await?;
+
+        let group_id = payload.group_id;
+        let clipboard_datas = match group_id {
+            Some(group_id) => {
+                // 只能取自己所在的群组
+                let (storage, user_id) = (state.storage.clone(), user.id);
+                run_blocking(move || member_role(storage.as_ref(), group_id, user_id)).await?;
+
+                &state.group_datas
+            }
+            None => &state.clipboard_datas,
+        };
+        let key = group_id.unwrap_or(user.id);
 
-        let user = User::find_user_from_device(&now_device)?;
+        // 只在读写内存里的待取设备时持有锁, 数据库操作都在锁外
+        let pending = clipboard_datas
+            .lock()
+            .await
+            .entry(key)
+            .or_insert(ClipboardData::new())
+            .devices
+            .contains(&now_device);
 
-        let mut clipboard_datas = state.clipboard_datas.lock().await;
+        if pending {
+            let storage = state.storage.clone();
+            let (user_id, device_id) = (user.id, now_device.id);
+            let devices = user.devices;
+            let (latest, filter, devices) = run_blocking(move || {
+                // 群组的剪切板可能来自其他成员的设备
+                let devices = match group_id {
+                    Some(group_id) => group_devices(storage.as_ref(), group_id)?,
+                    None => devices,
+                };
 
-        let clipboard_data = clipboard_datas
-            .entry(user.id)
-            .or_insert(ClipboardData::new());
+                Ok((
+                    latest_clipboard(storage.as_ref(), user_id, group_id, device_id)?,
+                    get_device_filter(storage.as_ref(), device_id)?,
+                    devices,
+                ))
+            })
+            .await?;
 
-        if let Some(data) = clipboard_data.data.last() {
-            if let Some(index) = clipboard_data.devices.iter().position(|x| x == &now_device) {
-                clipboard_data.devices.remove(index);
+            if let Some(data) = latest {
+                if let Some(clipboard_data) = clipboard_datas.lock().await.get_mut(&key) {
+                    clipboard_data
+                        .devices
+                        .retain(|device| device != &now_device);
+                }
 
-                return Ok(data.clone());
+                // 敏感内容不推送给配置里屏蔽的设备类型
+                let blocked = data.sensitive
+                    && state
+                        .config
+                        .sensitive
+                        .block_device_types
+                        .contains(&now_device.device_type);
+
+                // 接收规则可能在上传后修改过, 或者有接收时间段, 取的时候再检查一次
+                let from_device = devices
+                    .iter()
+                    .find(|device| data.source.as_ref() == Some(&device.identity()))
+                    .map_or(0, |device| device.id);
+                let allowed = filter.allows(&data, from_device, Utc::now());
+
+                if !blocked && allowed {
+                    record_clip(
+                        &Metrics::global().clips_delivered,
+                        &data,
+                        "/message/updatebase",
+                    );
+                    return Ok(data);
+                }
             }
         }
 
@@ -113,3 +159,323 @@ pub async fn message_update_base(
 
     Json(return_base_res(handler().await))
 }
+
+#[derive(Deserialize)]
+pub struct InputUpdateMessage {
+    device: InputDevice,
+    id: u64,
+    pinned: Option<bool>,
+    favorite: Option<bool>,
+}
+
+#[debug_handler]
+pub async fn update_message(
+    State(state): State<AppState>,
+    Json(payload): Json<InputUpdateMessage>,
+) -> impl IntoResponse {
+    let handler = || async {
+        let now_device = state.parse_device(payload.device).await?;
+
+        let user = state.find_user(&now_device).await?;
+
+        state
+            .update_clipboard(user.id, payload.id, payload.pinned, payload.favorite)
+            .await
+    };
+
+    Json(return_base_res(handler().await))
+}
+
+#[derive(Deserialize)]
+pub struct InputDeleteMessage {
+    device: InputDevice,
+    id: u64,
+}
+
+#[debug_handler]
+pub async fn delete_message(
+    State(state): State<AppState>,
+    Json(payload): Json<InputDeleteMessage>,
+) -> impl IntoResponse {
+    let handler = || async {
+        let now_device = state.parse_device(payload.device).await?;
+
+        let user = state.find_user(&now_device).await?;
+
+        state.delete_clipboard(user.id, payload.id).await?;
+
+        tracing::info!("device ({}) delete message {}", now_device.name, payload.id);
+
+        Ok(())
+    };
+
+    Json(return_bool_res(handler().await))
+}
+
+#[derive(Deserialize)]
+pub struct InputMessageHistory {
+    before: Option<u64>,
+    limit: Option<usize>,
+    clip_type: Option<String>,
+    device_id: Option<u64>,
+    from: Option<u64>,
+    to: Option<u64>,
+    pinned: Option<bool>,
+    favorite: Option<bool>,
+    // 只返回这个设备能收到的剪切板
+    recipient: Option<u64>,
+    // 查看群组的历史记录
+    group_id: Option<u64>,
+    // 为空时返回设备订阅的所有频道
+    channel: Option<String>,
+}
+
+/// `GET /message/history?name=..&type=..`, 设备信息和过滤条件都放在 query 里
+#[debug_handler]
+pub async fn message_history(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+    Query(payload): Query<InputMessageHistory>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let clip_type = match payload.clip_type {
+            Some(clip_type) => Some(clip_type.parse::<ClipboardDataType>()?),
+            None => None,
+        };
+
+        let channels = match payload.group_id {
+            Some(group_id) => {
+                member_role(state.storage.as_ref(), group_id, user.id)?;
+                None
+            }
+            None => Some(history_channels(
+                state.storage.as_ref(),
+                now_device.id,
+                payload.channel.clone(),
+            )?),
+        };
+
+        let filter = ClipFilter {
+            before: payload.before,
+            clip_type,
+            device_id: payload.device_id,
+            from: payload.from,
+            to: payload.to,
+            pinned: payload.pinned,
+            favorite: payload.favorite,
+            recipient: payload.recipient,
+            group_id: payload.group_id,
+            channels,
+            untargeted: false,
+            limit: payload
+                .limit
+                .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                .clamp(1, MAX_HISTORY_LIMIT),
+        };
+
+        find_clipboards(state.storage.as_ref(), user.id, &filter)
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[debug_handler]
+pub async fn get_message(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(device): Query<InputDevice>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        if let Some(clipboard) = get_clipboard(state.storage.as_ref(), user.id, id)? {
+            Ok(clipboard)
+        } else {
+            Err(ba_error("not find message"))
+        }
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputCurrentMessage {
+    group_id: Option<u64>,
+    // 为空时是默认频道
+    channel: Option<String>,
+}
+
+/// `GET /message/current?name=..&type=..`, 频道或者群组当前的剪切板, 没有时返回空剪切板
+#[debug_handler]
+pub async fn current_message(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+    Query(payload): Query<InputCurrentMessage>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let channel = match payload.group_id {
+            Some(group_id) => {
+                member_role(state.storage.as_ref(), group_id, user.id)?;
+                None
+            }
+            None => check_channel(state.storage.as_ref(), user.id, payload.channel.as_deref())?,
+        };
+
+        let current = current_clipboard(
+            state.storage.as_ref(),
+            user.id,
+            payload.group_id,
+            channel.as_deref(),
+        )?;
+
+        match current {
+            Some(current) => Ok(get_clipboard(state.storage.as_ref(), user.id, current.id)?
+                .unwrap_or(Clipboard::empty())),
+            None => Ok(Clipboard::empty()),
+        }
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputMessageSearch {
+    q: String,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// `GET /message/search?name=..&type=..&q=..`, q 支持 `"短语"` 和 `前缀*`
+#[debug_handler]
+pub async fn message_search(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+    Query(payload): Query<InputMessageSearch>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        search_clipboards(
+            state.storage.as_ref(),
+            user.id,
+            payload.q.as_str(),
+            payload
+                .limit
+                .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                .clamp(1, MAX_HISTORY_LIMIT),
+            payload.offset.unwrap_or(0),
+        )
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputMessageExport {
+    // 为空时是 jsonl
+    format: Option<TransferFormat>,
+}
+
+/// `GET /message/export?name=..&type=..&format=jsonl|csv`, 返回文件, 出错时返回 json
+#[debug_handler]
+pub async fn export_messages(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+    Query(payload): Query<InputMessageExport>,
+) -> Response {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let (content_type, extension, body) = match payload.format.unwrap_or_default() {
+            TransferFormat::Jsonl => (
+                "application/x-ndjson",
+                "jsonl",
+                export_jsonl(state.storage.as_ref(), &user)?,
+            ),
+            TransferFormat::Csv => (
+                "text/csv; charset=utf-8",
+                "csv",
+                export_csv(state.storage.as_ref(), &user)?,
+            ),
+            _ => return Err(ba_error("format can only be imported")),
+        };
+
+        tracing::info!("device ({}) export messages", now_device.name);
+
+        Ok((content_type, extension, body))
+    };
+
+    match run_blocking(handler).await {
+        Ok((content_type, extension, body)) => (
+            [
+                (header::CONTENT_TYPE, content_type.to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"clipboard.{}\"", extension),
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(err) => Json(return_base_res::<()>(Err(err))).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InputMessageImport {
+    device: InputDevice,
+    format: TransferFormat,
+    // 导入文件的内容
+    data: String,
+}
+
+/// 导入历史记录, 不会推送给设备, 返回导入和跳过的数量
+#[debug_handler]
+pub async fn import_messages(
+    State(state): State<AppState>,
+    Json(payload): Json<InputMessageImport>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let clips = parse_import(payload.format, &payload.data)?;
+
+        let report = import_clipboards(
+            state.storage.as_ref(),
+            &user,
+            &now_device,
+            &user_channels(state.storage.as_ref(), user.id)?,
+            &state.classifier,
+            &state.config.limits,
+            &state.config.retention.default,
+            clips,
+        )?;
+
+        tracing::info!(
+            "device ({}) imported {} messages, {} duplicates",
+            now_device.name,
+            report.imported,
+            report.duplicates
+        );
+
+        Ok(report)
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
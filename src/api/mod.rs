@@ -1,9 +1,28 @@
-use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::Duration;
 
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, MatchedPath, Query, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http_body_util::{LengthLimitError, Limited};
+use serde::{Deserialize, Serialize};
+
+pub mod admin;
+pub mod channel;
+pub mod group;
+pub mod health;
 pub mod message;
 pub mod user;
 
-use crate::utils::BDEResult;
+use crate::datalayer::User;
+use crate::state::AppState;
+use crate::tls::ClientCertificate;
+use crate::utils::{ba_error, error_code, rate_limit_error, too_large_error, BDEResult, BDError};
 
 #[derive(Serialize)]
 pub struct BaseRes<T> {
@@ -19,6 +38,17 @@ pub struct BoolRes {
     data: bool,
 }
 
+/// 找到同一个用户下的目标设备, 为空时是当前设备
+fn find_target_device(user: &User, now_device_id: u64, device_id: Option<u64>) -> BDEResult<u64> {
+    let device_id = device_id.unwrap_or(now_device_id);
+
+    if user.devices.iter().any(|device| device.id == device_id) {
+        Ok(device_id)
+    } else {
+        Err(ba_error("not find device"))
+    }
+}
+
 fn return_base_res<T>(res: BDEResult<T>) -> BaseRes<T> {
     match res {
         Ok(data) => BaseRes {
@@ -27,7 +57,7 @@ fn return_base_res<T>(res: BDEResult<T>) -> BaseRes<T> {
             data: Some(data),
         },
         Err(err) => BaseRes {
-            code: 401,
+            code: error_code(&err),
             msg: err.to_string(),
             data: None,
         },
@@ -42,9 +72,205 @@ fn return_bool_res(res: BDEResult<()>) -> BoolRes {
             data: true,
         },
         Err(err) => BoolRes {
-            code: 401,
+            code: error_code(&err),
             msg: err.to_string(),
             data: false,
         },
     }
 }
+
+// 请求里的当前设备, GET 在 query 里, 其他在 json body 的 `device` 里
+#[derive(Deserialize, Clone, PartialEq)]
+struct RequestDevice {
+    name: String,
+    #[serde(rename = "type")]
+    device_type: String,
+}
+
+#[derive(Deserialize)]
+struct RequestBodyDevice {
+    device: Option<RequestDevice>,
+}
+
+// 和 `Json` 一样接受大小写不同的类型和 `application/*+json`
+fn is_json_content_type(value: &str) -> bool {
+    let mime = value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+// 找到请求里的设备并放到 extension 里, 后面的中间件不用再读一次 body
+//
+// 和 handler 使用同一个来源, query 和 body 里的设备不一致时拒绝请求
+async fn request_device(request: Request) -> Result<(Request, Option<RequestDevice>), Response> {
+    if let Some(device) = request.extensions().get::<Option<RequestDevice>>() {
+        let device = device.clone();
+        return Ok((request, device));
+    }
+
+    let query_device = Query::<RequestDevice>::try_from_uri(request.uri())
+        .ok()
+        .map(|Query(device)| device);
+
+    let is_json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_json_content_type);
+
+    let (mut request, body_device) = if is_json {
+        // 读出整个 body 找到设备, 再放回请求里交给后面的 handler
+        let (parts, body) = request.into_parts();
+        let bytes = match to_bytes(body, usize::MAX).await {
+            Ok(bytes) => bytes,
+            Err(err) => return Err(body_error_response(err)),
+        };
+        let device = serde_json::from_slice::<RequestBodyDevice>(&bytes)
+            .ok()
+            .and_then(|body| body.device);
+
+        (Request::from_parts(parts, Body::from(bytes)), device)
+    } else {
+        (request, None)
+    };
+
+    if query_device.is_some() && body_device.is_some() && query_device != body_device {
+        return Err(Json(return_bool_res(Err(ba_error(
+            "device in query and body do not match",
+        ))))
+        .into_response());
+    }
+
+    let device = match *request.method() {
+        Method::GET | Method::HEAD => query_device,
+        _ => body_device,
+    };
+
+    request.extensions_mut().insert(device.clone());
+
+    Ok((request, device))
+}
+
+// body 超过上限时是 413, 其他的读取错误和普通错误一样
+fn body_error_response(err: axum::Error) -> Response {
+    // 上限的错误可能被包了好几层
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&err);
+    let mut too_large = false;
+    while let Some(err) = source {
+        too_large |= err.is::<LengthLimitError>();
+        source = err.source();
+    }
+
+    if too_large {
+        limit_response(too_large_error("request body is too large"), None)
+    } else {
+        Json(return_bool_res(Err(ba_error(
+            "failed to read request body",
+        ))))
+        .into_response()
+    }
+}
+
+// 超过限制时除了 json 里的 code, http 状态码也是 413 或 429, 方便代理和客户端重试
+fn limit_response(err: BDError, retry_after: Option<Duration>) -> Response {
+    let status = StatusCode::from_u16(error_code(&err) as u16).unwrap_or(StatusCode::BAD_REQUEST);
+    let mut response = (status, Json(return_bool_res(Err(err)))).into_response();
+
+    if let Some(retry_after) = retry_after {
+        let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+
+    response
+}
+
+/// 按 ip 和设备限流, 限制请求 body 的大小
+pub async fn limit_request(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limits = &state.config.limits;
+
+    if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        if let Err(retry_after) = state.limiter.ip.check(addr.ip()) {
+            return limit_response(rate_limit_error("too many requests"), Some(retry_after));
+        }
+    }
+
+    let limit = match request.extensions().get::<MatchedPath>() {
+        Some(path) => limits.body_limit(path.as_str()),
+        None => limits.max_body_bytes,
+    };
+
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return limit_response(too_large_error("request body is too large"), None);
+    }
+
+    // 没有 Content-Length 时读取 body 的过程中检查
+    let request = request.map(|body| Body::new(Limited::new(body, limit)));
+
+    let (request, device) = match request_device(request).await {
+        Ok(res) => res,
+        Err(response) => return response,
+    };
+
+    if let Some(device) = device {
+        if let Err(retry_after) = state
+            .limiter
+            .device
+            .check((device.name, device.device_type))
+        {
+            return limit_response(
+                rate_limit_error("too many requests from this device"),
+                Some(retry_after),
+            );
+        }
+    }
+
+    next.run(request).await
+}
+
+/// 使用了客户端证书的请求只能以证书对应的设备访问, 绑定了证书的设备必须提供证书,
+/// 没有设备的请求 (管理接口等) 不检查
+pub async fn check_client_cert(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let cert = request
+        .extensions()
+        .get::<ClientCertificate>()
+        .cloned()
+        .unwrap_or_default();
+
+    // 没有证书也没有绑定证书的设备时不需要检查
+    if cert.fingerprint.is_none() && state.config.tls.client_certs.is_empty() {
+        return next.run(request).await;
+    }
+
+    let (request, device) = match request_device(request).await {
+        Ok(res) => res,
+        Err(response) => return response,
+    };
+
+    if let Some(device) = device {
+        if let Err(err) = cert.check(&state.config.tls, &device.name, &device.device_type) {
+            return Json(return_bool_res(Err(err))).into_response();
+        }
+    }
+
+    next.run(request).await
+}
@@ -1,10 +1,22 @@
-use axum::{debug_handler, extract::Query, response::IntoResponse, Json};
-use serde::Deserialize;
+use axum::{
+    debug_handler,
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use connect_any_protocol::close_code;
+use serde::{Deserialize, Serialize};
 
-use super::{return_base_res, return_bool_res};
+use super::{find_target_device, return_base_res, return_bool_res};
+use crate::datalayer::filter::{get_device_filter, set_device_filter, DeviceFilter};
 use crate::datalayer::DeviceType;
-use crate::datalayer::User;
+use crate::datalayer::{InputDevice, User};
+use crate::retention::{
+    get_user_policy, plan_purge, set_user_policy, PurgeCandidate, RetentionPolicy,
+};
+use crate::state::AppState;
 use crate::utils::ba_error;
+use crate::utils::database::run_blocking;
 
 #[derive(Deserialize)]
 pub struct InputAddDevice {
@@ -21,22 +33,25 @@ pub struct InputAddUser {
 }
 
 #[debug_handler]
-pub async fn add_user(Json(payload): Json<InputAddUser>) -> impl IntoResponse {
-    let handler = || {
-        let mut user = User::build(payload.name)?;
-
+pub async fn add_user(
+    State(state): State<AppState>,
+    Json(payload): Json<InputAddUser>,
+) -> impl IntoResponse {
+    let handler = move || {
         let device_type: DeviceType = payload.device.device_type.parse()?;
 
-        user.add_device(
-            payload.device.name,
+        User::register(
+            state.storage.as_ref(),
+            &payload.name,
+            &payload.device.name,
             device_type,
-            payload.device.notification,
+            &payload.device.notification,
         )?;
 
         Ok(())
     };
 
-    Json(return_bool_res(handler()))
+    Json(return_bool_res(run_blocking(handler).await))
 }
 
 #[derive(Deserialize)]
@@ -45,14 +60,188 @@ pub struct InputGetUser {
 }
 
 #[debug_handler]
-pub async fn get_user_device(username: Query<InputGetUser>) -> impl IntoResponse {
-    let handler = || {
-        if let Some(user) = User::find_user(username.name.clone())? {
+pub async fn get_user_device(
+    State(state): State<AppState>,
+    username: Query<InputGetUser>,
+) -> impl IntoResponse {
+    let handler = move || {
+        if let Some(user) = User::find_user(state.storage.as_ref(), username.name.clone())? {
             Ok(user)
         } else {
             Err(ba_error("not find user"))
         }
     };
 
-    Json(return_base_res(handler()))
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputDeleteDevice {
+    device: InputDevice,
+    // 同一个用户的设备, 可以是当前设备
+    device_id: u64,
+}
+
+/// 删除设备并断开它的连接, 历史记录会保留
+#[debug_handler]
+pub async fn delete_device(
+    State(state): State<AppState>,
+    Json(payload): Json<InputDeleteDevice>,
+) -> impl IntoResponse {
+    let handler = || async {
+        let now_device = state.parse_device(payload.device).await?;
+
+        let user = state.find_user(&now_device).await?;
+
+        let device_id = find_target_device(&user, now_device.id, Some(payload.device_id))?;
+
+        let user_id = user.id;
+        let storage = state.storage.clone();
+        if !run_blocking(move || storage.delete_device(user_id, device_id)).await? {
+            return Err(ba_error("not find device"));
+        }
+
+        state
+            .disconnect_sessions(
+                |_, session| session.device_id == device_id,
+                close_code::DISCONNECTED,
+            )
+            .await;
+
+        Ok(())
+    };
+
+    Json(return_bool_res(handler().await))
+}
+
+#[derive(Serialize)]
+pub struct RetentionInfo {
+    // 用户自己设置的规则
+    policy: RetentionPolicy,
+    // 合并服务器默认规则后实际生效的规则
+    effective: RetentionPolicy,
+}
+
+#[debug_handler]
+pub async fn get_retention(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let policy = get_user_policy(state.storage.as_ref(), user.id)?;
+        let effective = policy.merge(&state.config.retention.default);
+
+        Ok(RetentionInfo { policy, effective })
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputSetRetention {
+    device: InputDevice,
+    policy: RetentionPolicy,
+}
+
+#[debug_handler]
+pub async fn set_retention(
+    State(state): State<AppState>,
+    Json(payload): Json<InputSetRetention>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        set_user_policy(state.storage.as_ref(), user.id, &payload.policy)
+    };
+
+    Json(return_bool_res(run_blocking(handler).await))
+}
+
+#[derive(Serialize)]
+pub struct RetentionDryRun {
+    effective: RetentionPolicy,
+    purge: Vec<PurgeCandidate>,
+    purge_bytes: u64,
+}
+
+/// 不删除任何内容, 只返回按当前规则会被清除的剪切板
+#[debug_handler]
+pub async fn retention_dry_run(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let effective = get_user_policy(state.storage.as_ref(), user.id)?
+            .merge(&state.config.retention.default);
+        let purge = plan_purge(state.storage.as_ref(), user.id, &effective)?;
+        let purge_bytes = purge.iter().map(|candidate| candidate.size).sum();
+
+        Ok(RetentionDryRun {
+            effective,
+            purge,
+            purge_bytes,
+        })
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputDeviceFilterTarget {
+    // 要查看或修改的设备, 为空时表示当前设备
+    device_id: Option<u64>,
+}
+
+#[debug_handler]
+pub async fn get_device_filter_rules(
+    State(state): State<AppState>,
+    Query(device): Query<InputDevice>,
+    Query(target): Query<InputDeviceFilterTarget>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let device_id = find_target_device(&user, now_device.id, target.device_id)?;
+
+        get_device_filter(state.storage.as_ref(), device_id)
+    };
+
+    Json(return_base_res(run_blocking(handler).await))
+}
+
+#[derive(Deserialize)]
+pub struct InputSetDeviceFilter {
+    device: InputDevice,
+    device_id: Option<u64>,
+    filter: DeviceFilter,
+}
+
+#[debug_handler]
+pub async fn set_device_filter_rules(
+    State(state): State<AppState>,
+    Json(payload): Json<InputSetDeviceFilter>,
+) -> impl IntoResponse {
+    let handler = move || {
+        let now_device = payload.device.parse(state.storage.as_ref())?;
+
+        let user = User::find_user_from_device(state.storage.as_ref(), &now_device)?;
+
+        let device_id = find_target_device(&user, now_device.id, payload.device_id)?;
+
+        set_device_filter(state.storage.as_ref(), device_id, &payload.filter)
+    };
+
+    Json(return_bool_res(run_blocking(handler).await))
 }
@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rusqlite::backup::{Backup as SqliteBackup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::config::BackupConfig;
+use crate::datalayer::blob::{export_blobs, import_blobs};
+use crate::utils::database::Database;
+use crate::utils::{ba_error, BDEResult};
+
+// 快照目录的名字是前缀加上创建时间, 按名字排序就是按时间排序
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+// 还没写完的快照, 不会被恢复或者轮换
+const SNAPSHOT_TMP_SUFFIX: &str = ".tmp";
+const SNAPSHOT_DATABASE: &str = "data.db";
+const SNAPSHOT_BLOBS: &str = "blobs";
+// 数据库正在被写入时, 等一会儿再复制
+const BACKUP_RETRY: Duration = Duration::from_millis(50);
+
+/// 可以保存快照的数据, 只有 sqlite 的数据库支持, `MemoryStorage` 不能备份
+pub trait Backup: Send + Sync + Debug {
+    /// 把所有数据一致地复制到 `dest` 的 sqlite 文件, 服务器运行时也可以使用
+    fn backup(&self, dest: &Path) -> BDEResult<()>;
+}
+
+impl Backup for Database {
+    fn backup(&self, dest: &Path) -> BDEResult<()> {
+        backup_database(&*self.get()?, dest)
+    }
+}
+
+/// 一次备份的结果
+#[derive(Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub path: String,
+    pub database_bytes: u64,
+    pub blobs: usize,
+    // 数据库里引用了但是文件已经被删除的 blob
+    pub missing_blobs: Vec<String>,
+    // 轮换时删除的旧快照数量
+    pub rotated: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RestoreReport {
+    // 恢复之前自动保存的当前数据
+    pub previous: String,
+    pub blobs: usize,
+}
+
+// 用 sqlite 的在线备份 API 复制整个数据库
+fn copy_database(from: &Connection, to: &mut Connection) -> BDEResult<()> {
+    let backup = SqliteBackup::new(from, to)?;
+
+    // 一步复制所有的页: 在 WAL 模式下只持有读事务, 不会阻塞写入,
+    // 也不会因为其他连接中途写入而从头开始
+    loop {
+        match backup.step(-1)? {
+            StepResult::Done => return Ok(()),
+            _ => thread::sleep(BACKUP_RETRY),
+        }
+    }
+}
+
+/// 把 `conn` 的数据库一致地复制到 `dest`, 服务器运行时也可以使用
+pub fn backup_database(conn: &Connection, dest: &Path) -> BDEResult<()> {
+    let mut dest_conn = Connection::open(dest)?;
+
+    copy_database(conn, &mut dest_conn)?;
+
+    // 快照只有一个文件, 不需要 WAL
+    dest_conn.pragma_update_and_check(None, "journal_mode", "DELETE", |row| {
+        row.get::<_, String>(0)
+    })?;
+
+    Ok(())
+}
+
+fn referenced_blobs(conn: &Connection) -> BDEResult<HashSet<String>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT blob FROM clips WHERE blob IS NOT NULL")?;
+
+    let names = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<HashSet<String>, _>>()?;
+
+    Ok(names)
+}
+
+// 先复制数据库, 再复制快照里引用的 blob, blob 按内容命名, 不会被修改
+fn write_snapshot(source: &dyn Backup, path: &Path) -> BDEResult<Snapshot> {
+    let database = path.join(SNAPSHOT_DATABASE);
+
+    source.backup(&database)?;
+
+    let names = referenced_blobs(&Connection::open_with_flags(
+        &database,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?)?;
+    let (blobs, missing_blobs) = export_blobs(&names, &path.join(SNAPSHOT_BLOBS))?;
+
+    if !missing_blobs.is_empty() {
+        tracing::warn!("backup missing {} blobs", missing_blobs.len());
+    }
+
+    Ok(Snapshot {
+        path: String::new(),
+        database_bytes: fs::metadata(&database)?.len(),
+        blobs,
+        missing_blobs,
+        rotated: 0,
+    })
+}
+
+/// 在 `dir` 下创建一个包含数据库和 blob 的快照目录
+///
+/// 先写到临时目录, 写完后再改名, 不会留下只写了一半的快照
+pub fn create_snapshot(source: &dyn Backup, dir: &Path) -> BDEResult<Snapshot> {
+    let name = format!(
+        "{}{}",
+        SNAPSHOT_PREFIX,
+        chrono::Utc::now().format("%Y%m%d-%H%M%S-%3f")
+    );
+    let tmp_path = dir.join(format!("{}{}", name, SNAPSHOT_TMP_SUFFIX));
+    let path = dir.join(name);
+
+    fs::create_dir_all(&tmp_path)?;
+
+    let mut snapshot = match write_snapshot(source, &tmp_path) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&tmp_path);
+            return Err(err);
+        }
+    };
+
+    fs::rename(&tmp_path, &path)?;
+    snapshot.path = path.to_string_lossy().to_string();
+
+    Ok(snapshot)
+}
+
+/// `dir` 下已经完成的快照, 从旧到新
+pub fn list_snapshots(dir: &Path) -> BDEResult<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if entry.file_type()?.is_dir()
+            && name.starts_with(SNAPSHOT_PREFIX)
+            && !name.ends_with(SNAPSHOT_TMP_SUFFIX)
+        {
+            snapshots.push(entry.path());
+        }
+    }
+    snapshots.sort();
+
+    Ok(snapshots)
+}
+
+/// 只保留最新的 `keep` 个快照, 为 0 时不删除, 返回删除的数量
+pub fn rotate_snapshots(dir: &Path, keep: usize) -> BDEResult<usize> {
+    let snapshots = list_snapshots(dir)?;
+
+    if keep == 0 || snapshots.len() <= keep {
+        return Ok(0);
+    }
+
+    let expired = &snapshots[..snapshots.len() - keep];
+    for path in expired {
+        fs::remove_dir_all(path)?;
+    }
+
+    Ok(expired.len())
+}
+
+/// 按照配置创建快照并轮换旧的快照
+pub fn run_backup(source: &dyn Backup, config: &BackupConfig) -> BDEResult<Snapshot> {
+    let mut snapshot = create_snapshot(source, &config.dir)?;
+    snapshot.rotated = rotate_snapshots(&config.dir, config.keep)?;
+
+    tracing::info!(
+        "backup saved to {} ({} bytes, {} blobs, {} rotated)",
+        snapshot.path,
+        snapshot.database_bytes,
+        snapshot.blobs,
+        snapshot.rotated
+    );
+
+    Ok(snapshot)
+}
+
+/// 用快照目录或者单独的数据库文件覆盖当前的数据, 需要先停止服务器
+///
+/// 恢复之前会在 `dir` 下保存一份当前的数据
+pub fn restore(database: &Database, src: &Path, dir: &Path) -> BDEResult<RestoreReport> {
+    let (src_database, blobs) = if src.is_dir() {
+        (src.join(SNAPSHOT_DATABASE), Some(src.join(SNAPSHOT_BLOBS)))
+    } else {
+        (src.to_path_buf(), None)
+    };
+
+    if !src_database.is_file() {
+        return Err(ba_error(
+            format!("backup database {} not found", src_database.display()).as_str(),
+        ));
+    }
+
+    let src_conn = Connection::open_with_flags(&src_database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let check: String = src_conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+    if check != "ok" {
+        return Err(ba_error(
+            format!("backup database is corrupted: {}", check).as_str(),
+        ));
+    }
+
+    let previous = create_snapshot(database, dir)?.path;
+
+    copy_database(&src_conn, &mut *database.get()?)?;
+
+    let blobs = match blobs {
+        Some(blobs) if blobs.is_dir() => import_blobs(&blobs)?,
+        _ => 0,
+    };
+
+    Ok(RestoreReport { previous, blobs })
+}
+
+/// 定时保存快照, 启动后等一个间隔再开始
+pub fn spawn_backup_task(source: Arc<dyn Backup>, config: BackupConfig) {
+    if !config.enabled {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let period = Duration::from_secs(config.interval_secs.max(60));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+
+            let task_config = config.clone();
+            let source = source.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                run_backup(source.as_ref(), &task_config)
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            })
+            .await;
+
+            match res {
+                Ok(Err(err)) => tracing::error!("backup error: {}", err),
+                Err(err) => tracing::error!("backup task error: {}", err),
+                Ok(Ok(())) => {}
+            }
+        }
+    });
+}
@@ -2,6 +2,7 @@ use reqwest::{Client, Url};
 
 use crate::{
     datalayer::DeviceType,
+    metrics::Metrics,
     utils::{ba_error, BDEResult},
 };
 
@@ -16,8 +17,6 @@ pub async fn send_bark(
         bark_id, device_name, device_type
     );
 
-    let client = Client::new();
-
     let url = Url::parse_with_params(
         base_url.as_str(),
         &[
@@ -27,6 +26,17 @@ pub async fn send_bark(
         ],
     )?;
 
+    let res = request_bark(url).await;
+
+    let outcome = if res.is_ok() { "success" } else { "error" };
+    Metrics::global().notifications.inc(&[("outcome", outcome)]);
+
+    res
+}
+
+async fn request_bark(url: Url) -> BDEResult<()> {
+    let client = Client::new();
+
     let res = client.get(url).send().await?;
 
     if res.status().is_success() {
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::admin::AdminAccount;
+use crate::datalayer::DeviceType;
+use crate::retention::RetentionPolicy;
+use crate::sensitive::{PatternConfig, RegexRule};
+use crate::utils::{ba_error, BDEResult};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub retention: RetentionConfig,
+    pub sensitive: SensitiveConfig,
+    pub dedup: DedupConfig,
+    pub clock: ClockConfig,
+    pub admin: AdminConfig,
+    pub backup: BackupConfig,
+    pub tls: TlsConfig,
+    pub limits: LimitsConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    // 后台清理任务的执行间隔
+    pub interval_secs: u64,
+    // 用户没有设置时使用的规则
+    pub default: RetentionPolicy,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            interval_secs: 5 * 60,
+            default: RetentionPolicy {
+                max_count: Some(100),
+                max_age_days: None,
+                max_bytes: None,
+                sensitive_ttl_secs: Some(10 * 60),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SensitiveConfig {
+    // 敏感内容不会推送给这些类型的设备
+    pub block_device_types: Vec<DeviceType>,
+    // 除了内置规则以外的自定义正则
+    pub patterns: Vec<PatternConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DedupConfig {
+    // 这段时间内复制相同的内容只更新原来的剪切板, 为 0 时不去重
+    pub window_secs: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            window_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClockConfig {
+    // 客户端时间最多可以比服务器时间超前多少秒
+    pub max_future_secs: u64,
+    // 超前太多时拒绝上传, 否则只标记 `future_date`
+    pub reject_future: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            max_future_secs: 5 * 60,
+            reject_future: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    // 管理员账号, 为空时所有管理接口都拒绝访问
+    pub accounts: Vec<AdminAccount>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BackupConfig {
+    // 为 true 时定时保存快照
+    pub enabled: bool,
+    pub interval_secs: u64,
+    // 快照保存的目录, `backup` 命令和管理接口也保存在这里
+    pub dir: PathBuf,
+    // 最多保留的快照数量, 为 0 时不删除旧的快照
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            enabled: false,
+            interval_secs: 24 * 60 * 60,
+            dir: PathBuf::from("./backups"),
+            keep: 7,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TlsConfig {
+    // 为 true 时服务器直接使用 https 和 wss
+    pub enabled: bool,
+    // PEM 格式的证书链和私钥
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // 检查证书文件是否被修改的间隔, 为 0 时只在收到 SIGHUP 时重新加载
+    pub reload_interval_secs: u64,
+    // 签发客户端证书的 CA, 设置后启用双向认证
+    pub client_ca: Option<PathBuf>,
+    // 为 true 时没有客户端证书的连接在握手时被拒绝
+    pub require_client_cert: bool,
+    // 客户端证书对应的设备
+    pub client_certs: Vec<ClientCertConfig>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert: PathBuf::from("./tls/cert.pem"),
+            key: PathBuf::from("./tls/key.pem"),
+            reload_interval_secs: 60,
+            client_ca: None,
+            require_client_cert: false,
+            client_certs: Vec::new(),
+        }
+    }
+}
+
+/// 客户端证书和设备的对应关系, 使用这个证书的连接只能以这个设备的身份访问
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientCertConfig {
+    // 证书 DER 内容的 sha256, 十六进制, 可以用
+    // `openssl x509 -in client.pem -outform der | sha256sum` 得到
+    pub fingerprint: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: DeviceType,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // 没有单独设置的接口的请求 body 上限 (字节)
+    pub max_body_bytes: usize,
+    // 按接口路径单独设置的 body 上限, 例如 `"/message/import" = 67108864`
+    pub route_body_bytes: HashMap<String, usize>,
+    // 每种剪切板内容的上限, http 和 websocket 上传都会检查
+    pub max_text_bytes: usize,
+    pub max_image_bytes: usize,
+    // websocket 单条消息的上限, 超过时用 1009 关闭连接
+    pub max_ws_message_bytes: usize,
+    // 每个设备的请求和 websocket 消息
+    pub device_rate: RateLimitConfig,
+    // 每个 ip 的请求, 包括 websocket 连接
+    pub ip_rate: RateLimitConfig,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_body_bytes: 16 * 1024 * 1024,
+            route_body_bytes: HashMap::from([("/message/import".to_string(), 64 * 1024 * 1024)]),
+            max_text_bytes: 1024 * 1024,
+            max_image_bytes: 10 * 1024 * 1024,
+            max_ws_message_bytes: 16 * 1024 * 1024,
+            device_rate: RateLimitConfig {
+                per_sec: 10.0,
+                burst: 50,
+            },
+            ip_rate: RateLimitConfig {
+                per_sec: 20.0,
+                burst: 100,
+            },
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// 接口的 body 上限, `path` 是路由里的路径
+    pub fn body_limit(&self, path: &str) -> usize {
+        self.route_body_bytes
+            .get(path)
+            .copied()
+            .unwrap_or(self.max_body_bytes)
+    }
+}
+
+/// 令牌桶: 每秒补充 `per_sec` 个, 最多攒 `burst` 个, `per_sec` 为 0 时不限制
+#[derive(Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    // 收到 SIGTERM/SIGINT 后最多等待这么久, 还没有关闭的连接会被直接断开
+    pub timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { timeout_secs: 10 }
+    }
+}
+
+impl Config {
+    /// 读取 `./config.toml`, 文件不存在时使用默认配置
+    pub fn load() -> BDEResult<Self> {
+        let config_path = PathBuf::from("./config.toml");
+
+        if !config_path.exists() {
+            return Ok(Config::default());
+        }
+
+        let config_str = fs::read_to_string(config_path)?;
+
+        let config: Config = toml::from_str(&config_str)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// 检查自定义的敏感内容正则, 启动时报告配置错误
+    pub fn validate(&self) -> BDEResult<()> {
+        for pattern in &self.sensitive.patterns {
+            if let Err(err) = RegexRule::new(&pattern.name, &pattern.pattern) {
+                return Err(ba_error(
+                    format!("invalid sensitive pattern {}: {}", pattern.name, err).as_str(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::utils::{sha256_hex, BDEResult};
+
+const ORPHAN_GRACE: Duration = Duration::from_secs(10 * 60);
+
+// 图片等大块内容不放进数据库, 单独保存在这个目录下
+fn blob_dir() -> BDEResult<PathBuf> {
+    let blob_path = PathBuf::from("./data/blobs");
+
+    if !blob_path.exists() {
+        fs::create_dir_all(blob_path.clone())?;
+    }
+
+    Ok(blob_path)
+}
+
+/// 保存内容, 返回 blob 的名字
+///
+/// 名字是内容的 sha256, 相同的内容只保存一份
+pub fn write_blob(data: &str) -> BDEResult<String> {
+    let name = sha256_hex(data.as_bytes());
+    let blob_path = blob_dir()?.join(&name);
+
+    // 已经存在时更新修改时间, 避免还没插入数据库就被当成孤立的 blob 删除
+    let reused = blob_path.exists()
+        && fs::File::options()
+            .append(true)
+            .open(&blob_path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .is_ok();
+
+    if !reused {
+        fs::write(blob_path, data)?;
+    }
+
+    Ok(name)
+}
+
+pub fn read_blob(name: &str) -> BDEResult<String> {
+    Ok(fs::read_to_string(blob_dir()?.join(name))?)
+}
+
+/// blob 文件的数量和总字节数
+pub fn blob_usage() -> BDEResult<(usize, u64)> {
+    let mut count = 0;
+    let mut bytes = 0;
+
+    for entry in fs::read_dir(blob_dir()?)? {
+        count += 1;
+        bytes += entry?.metadata()?.len();
+    }
+
+    Ok((count, bytes))
+}
+
+/// 删除没有被 `referenced` 引用的 blob, 返回删除的数量
+///
+/// 刚写入的 blob 可能还没来得及插入数据库, 所以只删除超过 `ORPHAN_GRACE` 的文件
+pub fn remove_orphan_blobs(referenced: &HashSet<String>) -> BDEResult<usize> {
+    let mut removed = 0;
+
+    for entry in fs::read_dir(blob_dir()?)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+
+        if !referenced.contains(&name) && age > ORPHAN_GRACE {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// 把 `names` 里的 blob 复制到 `dest` 目录, 返回复制的数量和已经不存在的 blob
+pub fn export_blobs(names: &HashSet<String>, dest: &Path) -> BDEResult<(usize, Vec<String>)> {
+    let blob_path = blob_dir()?;
+    let mut copied = 0;
+    let mut missing = Vec::new();
+
+    fs::create_dir_all(dest)?;
+
+    for name in names {
+        match fs::copy(blob_path.join(name), dest.join(name)) {
+            Ok(_) => copied += 1,
+            Err(err) if err.kind() == ErrorKind::NotFound => missing.push(name.clone()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok((copied, missing))
+}
+
+/// 把 `src` 目录里的 blob 复制回来, 已经存在的跳过, 返回复制的数量
+pub fn import_blobs(src: &Path) -> BDEResult<usize> {
+    let blob_path = blob_dir()?;
+    let mut copied = 0;
+
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = blob_path.join(entry.file_name());
+
+        if entry.file_type()?.is_file() && !target.exists() {
+            fs::copy(entry.path(), target)?;
+            copied += 1;
+        }
+    }
+
+    Ok(copied)
+}
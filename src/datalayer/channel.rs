@@ -0,0 +1,115 @@
+pub use connect_any_protocol::DEFAULT_CHANNEL;
+
+use super::storage::Storage;
+use crate::utils::{ba_error, BDEResult};
+
+const MAX_CHANNEL_NAME_LEN: usize = 32;
+
+/// 用户所有的频道, `DEFAULT_CHANNEL` 总是在第一个
+pub fn user_channels(storage: &dyn Storage, user_id: u64) -> BDEResult<Vec<String>> {
+    let mut channels = vec![DEFAULT_CHANNEL.to_string()];
+
+    channels.extend(storage.get_user_channels(user_id)?);
+
+    Ok(channels)
+}
+
+pub fn create_channel(storage: &dyn Storage, user_id: u64, name: &str) -> BDEResult<()> {
+    let name = name.trim();
+
+    if name.is_empty() || name.chars().count() > MAX_CHANNEL_NAME_LEN {
+        return Err(ba_error("invalid channel name"));
+    }
+
+    if user_channels(storage, user_id)?
+        .iter()
+        .any(|channel| channel == name)
+    {
+        return Err(ba_error("channel already exists"));
+    }
+
+    storage.insert_channel(user_id, name)
+}
+
+/// 删除频道和频道里所有的剪切板, 默认频道不能删除
+pub fn delete_channel(storage: &dyn Storage, user_id: u64, name: &str) -> BDEResult<()> {
+    if name == DEFAULT_CHANNEL {
+        return Err(ba_error("can not delete the default channel"));
+    }
+
+    if storage.delete_channel(user_id, name)? {
+        Ok(())
+    } else {
+        Err(ba_error("not find channel"))
+    }
+}
+
+/// 频道必须是用户的频道, 返回保存时使用的名字, 默认频道为空
+pub fn check_channel(
+    storage: &dyn Storage,
+    user_id: u64,
+    channel: Option<&str>,
+) -> BDEResult<Option<String>> {
+    match channel {
+        None | Some(DEFAULT_CHANNEL) => Ok(None),
+        Some(channel) => {
+            if user_channels(storage, user_id)?
+                .iter()
+                .any(|name| name == channel)
+            {
+                Ok(Some(channel.to_string()))
+            } else {
+                Err(ba_error("not find channel"))
+            }
+        }
+    }
+}
+
+/// 设备订阅的频道, 没有设置时只订阅默认频道
+pub fn device_channels(storage: &dyn Storage, device_id: u64) -> BDEResult<Vec<String>> {
+    let channels = storage.get_device_channels(device_id)?;
+
+    if channels.is_empty() {
+        Ok(vec![DEFAULT_CHANNEL.to_string()])
+    } else {
+        Ok(channels)
+    }
+}
+
+/// 查看历史记录时的频道, 没有指定时是设备订阅的所有频道
+pub fn history_channels(
+    storage: &dyn Storage,
+    device_id: u64,
+    channel: Option<String>,
+) -> BDEResult<Vec<String>> {
+    match channel {
+        Some(channel) => Ok(vec![channel]),
+        None => device_channels(storage, device_id),
+    }
+}
+
+pub fn set_device_channels(
+    storage: &dyn Storage,
+    user_id: u64,
+    device_id: u64,
+    channels: &[String],
+) -> BDEResult<()> {
+    if channels.is_empty() {
+        return Err(ba_error("subscribe at least one channel"));
+    }
+
+    let user_channels = user_channels(storage, user_id)?;
+
+    if !channels
+        .iter()
+        .all(|channel| user_channels.contains(channel))
+    {
+        return Err(ba_error("not find channel"));
+    }
+
+    let mut channels = channels.to_vec();
+    channels.sort();
+    channels.dedup();
+
+    storage.set_device_channels(device_id, &channels)
+}
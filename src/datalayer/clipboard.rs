@@ -1,58 +1,384 @@
-use std::fmt;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use chrono::{DateTime, Local};
-use serde::{Deserialize, Serialize};
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub enum ClipboardDataType {
-    Text,
-    Image,
-    None,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct Clipboard {
-    pub data: String,
-    #[serde(rename = "type")]
-    pub clipboard_type: ClipboardDataType,
-    pub date: u128,
-}
-
-impl Clipboard {
-    pub fn new(data: String, clipboard_type: ClipboardDataType) -> Self {
-        let date = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-
-        Clipboard {
-            data,
-            clipboard_type,
-            date,
-        }
+use serde::Serialize;
+
+pub use connect_any_protocol::{Clipboard, ClipboardDataType};
+
+use super::blob::{read_blob, write_blob};
+use super::channel::{device_channels, DEFAULT_CHANNEL};
+pub use super::database::ClipFilter;
+use super::database::DatabaseClip;
+use super::storage::Storage;
+use super::Device;
+use crate::utils::{ba_error, sha256_hex, BDEResult};
+
+#[derive(Serialize)]
+pub struct ClipboardPage {
+    pub clips: Vec<Clipboard>,
+    // 下一页请求时作为 before 传入, 没有更多记录时为空
+    pub next_cursor: Option<u64>,
+}
+
+/// 频道或者群组当前的剪切板, 所有设备最终都同步到序号最大的这一条
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentClipboard {
+    pub id: u64,
+    pub seq: u64,
+    // 上传这条剪切板的设备, 设备被删除后为空
+    pub device_id: Option<u64>,
+}
+
+/// 剪切板内容的 sha256, 类型不同的内容不算重复
+pub fn clipboard_hash(clipboard: &Clipboard) -> String {
+    let mut data = clipboard.clipboard_type.to_string().into_bytes();
+    data.push(0);
+    data.extend_from_slice(clipboard.data.as_bytes());
+
+    sha256_hex(&data)
+}
+
+pub fn save_clipboard(
+    storage: &dyn Storage,
+    user_id: u64,
+    device: &Device,
+    clipboard: Clipboard,
+) -> BDEResult<Clipboard> {
+    Ok(store_clipboard(storage, user_id, device, clipboard, None)?.0)
+}
+
+/// 保存剪切板, 服务器 `window_secs` 内收到过相同的内容时只更新原来的剪切板, 返回剪切板和是否重复
+///
+/// 查找和保存在同一个事务里, 多个设备同时上传相同的内容也只保存一份. 定向发送的剪切板不去重
+pub fn dedup_clipboard(
+    storage: &dyn Storage,
+    user_id: u64,
+    device: &Device,
+    clipboard: Clipboard,
+    window_secs: u64,
+) -> BDEResult<(Clipboard, bool)> {
+    let since = if window_secs == 0 || clipboard.targets.is_some() {
+        None
+    } else {
+        Some(clipboard.received_at.saturating_sub(window_secs * 1000))
+    };
+
+    store_clipboard(storage, user_id, device, clipboard, since)
+}
+
+/// 用 `Storage::reserve_import_seqs` 预留的 `seq` 保存导入的历史记录, 不去重
+pub fn save_imported_clipboard(
+    storage: &dyn Storage,
+    user_id: u64,
+    device: &Device,
+    mut clipboard: Clipboard,
+    seq: u64,
+) -> BDEResult<Clipboard> {
+    let (size, hash, blob) = clipboard_content(&clipboard)?;
+
+    clipboard.seq = seq;
+    clipboard.id = storage.insert_clip(user_id, device.id, &clipboard, size, blob, &hash)?;
+    clipboard.source = Some(device.identity());
+    clipboard.copy_count = 1;
+
+    Ok(clipboard)
+}
+
+// 内容的字节数和 hash, 图片保存到 blob 里, 数据库里只记录名字, 相同的内容只保存一份
+fn clipboard_content(clipboard: &Clipboard) -> BDEResult<(u64, String, Option<String>)> {
+    let blob = match clipboard.clipboard_type {
+        ClipboardDataType::Image => Some(write_blob(&clipboard.data)?),
+        _ => None,
+    };
+
+    Ok((clipboard.data.len() as u64, clipboard_hash(clipboard), blob))
+}
+
+fn store_clipboard(
+    storage: &dyn Storage,
+    user_id: u64,
+    device: &Device,
+    mut clipboard: Clipboard,
+    since: Option<u64>,
+) -> BDEResult<(Clipboard, bool)> {
+    let (size, hash, blob) = clipboard_content(&clipboard)?;
+
+    let saved = storage.save_clip(user_id, device.id, &clipboard, size, blob, &hash, since)?;
+
+    if saved.duplicate {
+        let clipboard = get_clipboard(storage, user_id, saved.id)?
+            .ok_or_else(|| ba_error("not find message"))?;
+
+        return Ok((clipboard, true));
+    }
+
+    clipboard.id = saved.id;
+    clipboard.seq = saved.seq;
+    clipboard.source = Some(device.identity());
+    clipboard.copy_count = 1;
+
+    Ok((clipboard, false))
+}
+
+/// 修改固定/收藏状态, 返回修改后的剪切板
+pub fn update_clipboard(
+    storage: &dyn Storage,
+    user_id: u64,
+    id: u64,
+    pinned: Option<bool>,
+    favorite: Option<bool>,
+) -> BDEResult<Clipboard> {
+    if !storage.update_clip_flags(user_id, id, pinned, favorite)? {
+        return Err(ba_error("not find message"));
+    }
+
+    get_clipboard(storage, user_id, id)?.ok_or_else(|| ba_error("not find message"))
+}
+
+/// 删除用户上传的剪切板, 返回剪切板所在的群组
+pub fn delete_clipboard(storage: &dyn Storage, user_id: u64, id: u64) -> BDEResult<Option<u64>> {
+    let group_id = storage
+        .get_clip(user_id, id)?
+        .and_then(|clip| clip.group_id);
+
+    if storage.delete_user_clip(user_id, id)? {
+        Ok(group_id)
+    } else {
+        Err(ba_error("not find message"))
+    }
+}
+
+fn load_clipboard(clip: DatabaseClip) -> BDEResult<Clipboard> {
+    let blob = clip.blob.clone();
+    let mut clipboard = Clipboard::from(clip);
+
+    if let Some(blob) = blob {
+        clipboard.data = read_blob(&blob)?;
+    }
+
+    Ok(clipboard)
+}
+
+pub fn get_clipboard(storage: &dyn Storage, user_id: u64, id: u64) -> BDEResult<Option<Clipboard>> {
+    match storage.get_clip(user_id, id)? {
+        Some(clip) => Ok(Some(load_clipboard(clip)?)),
+        None => Ok(None),
+    }
+}
+
+/// 设备订阅的频道里, 设备能收到的最新的剪切板, `group_id` 不为空时是群组里的
+pub fn latest_clipboard(
+    storage: &dyn Storage,
+    user_id: u64,
+    group_id: Option<u64>,
+    device_id: u64,
+) -> BDEResult<Option<Clipboard>> {
+    // 群组没有频道
+    let channels = match group_id {
+        Some(_) => None,
+        None => Some(device_channels(storage, device_id)?),
+    };
+    let filter = ClipFilter {
+        recipient: Some(device_id),
+        group_id,
+        channels,
+        limit: 1,
+        ..Default::default()
+    };
+
+    match storage.find_clips(user_id, &filter)?.into_iter().next() {
+        Some(clip) => Ok(Some(load_clipboard(clip)?)),
+        None => Ok(None),
     }
+}
+
+/// 个人频道或者群组里序号最大的非定向剪切板, `channel` 为空时是默认频道
+pub fn current_clipboard(
+    storage: &dyn Storage,
+    user_id: u64,
+    group_id: Option<u64>,
+    channel: Option<&str>,
+) -> BDEResult<Option<CurrentClipboard>> {
+    let channels = match group_id {
+        Some(_) => None,
+        None => Some(vec![channel.unwrap_or(DEFAULT_CHANNEL).to_string()]),
+    };
+    let filter = ClipFilter {
+        group_id,
+        channels,
+        untargeted: true,
+        limit: 1,
+        ..Default::default()
+    };
+
+    Ok(storage
+        .find_clips(user_id, &filter)?
+        .into_iter()
+        .next()
+        .map(|clip| CurrentClipboard {
+            id: clip.id,
+            seq: clip.seq,
+            device_id: clip.device_id,
+        }))
+}
+
+/// 历史列表, 敏感内容会被隐藏, 需要通过 `get_clipboard` 单独获取
+pub fn find_clipboards(
+    storage: &dyn Storage,
+    user_id: u64,
+    filter: &ClipFilter,
+) -> BDEResult<ClipboardPage> {
+    let clips = storage
+        .find_clips(user_id, filter)?
+        .into_iter()
+        .map(|clip| load_clipboard(clip).map(|clipboard| clipboard.redacted()))
+        .collect::<BDEResult<Vec<Clipboard>>>()?;
+
+    let next_cursor = if clips.len() >= filter.limit {
+        clips.last().map(|clip| clip.seq)
+    } else {
+        None
+    };
 
-    pub fn empty() -> Self {
-        Clipboard {
-            data: String::new(),
-            clipboard_type: ClipboardDataType::None,
-            date: 0,
+    Ok(ClipboardPage { clips, next_cursor })
+}
+
+/// 用户所有频道的个人剪切板, 从旧到新, 包括敏感内容, 用于导出
+pub fn user_clipboards(storage: &dyn Storage, user_id: u64) -> BDEResult<Vec<Clipboard>> {
+    let mut clipboards = Vec::new();
+    let mut filter = ClipFilter {
+        limit: 500,
+        ..Default::default()
+    };
+
+    loop {
+        let clips = storage.find_clips(user_id, &filter)?;
+        let done = clips.len() < filter.limit;
+
+        filter.before = clips.last().map(|clip| clip.seq);
+        for clip in clips {
+            clipboards.push(load_clipboard(clip)?);
+        }
+
+        if done || filter.before.is_none() {
+            break;
         }
     }
+    clipboards.reverse();
+
+    Ok(clipboards)
+}
+
+#[derive(Serialize)]
+pub struct ClipboardSearchResult {
+    pub clip: Clipboard,
+    // 匹配的片段, 命中的词用 <mark></mark> 包起来
+    pub snippet: String,
+}
+
+#[derive(Serialize)]
+pub struct ClipboardSearchPage {
+    pub results: Vec<ClipboardSearchResult>,
+    // 下一页请求时作为 offset 传入, 没有更多记录时为空
+    pub next_offset: Option<usize>,
 }
 
-impl fmt::Display for Clipboard {
-    // 这个 trait 要求 `fmt` 使用与下面的函数完全一致的函数签名
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let duration = UNIX_EPOCH + std::time::Duration::from_millis(self.date as u64);
-        let datetime = DateTime::<Local>::from(duration);
-        let formatted_datetime = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-        let output = match self.clipboard_type {
-            ClipboardDataType::Text => self.data.clone(),
-            ClipboardDataType::Image => String::from("Image"),
-            ClipboardDataType::None => String::from("None"),
+/// 搜索里的一个词, `prefix` 时匹配以这个词开头的内容
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchTerm {
+    pub text: String,
+    pub prefix: bool,
+}
+
+pub fn search_clipboards(
+    storage: &dyn Storage,
+    user_id: u64,
+    query: &str,
+    limit: usize,
+    offset: usize,
+) -> BDEResult<ClipboardSearchPage> {
+    let terms = parse_search_query(query);
+
+    if terms.is_empty() {
+        return Ok(ClipboardSearchPage {
+            results: Vec::new(),
+            next_offset: None,
+        });
+    }
+
+    let results: Vec<ClipboardSearchResult> = storage
+        .search_clips(user_id, &terms, limit, offset)?
+        .into_iter()
+        .map(|(clip, snippet)| ClipboardSearchResult {
+            clip: clip.into(),
+            snippet,
+        })
+        .collect();
+
+    let next_offset = if results.len() >= limit {
+        Some(offset + results.len())
+    } else {
+        None
+    };
+
+    Ok(ClipboardSearchPage {
+        results,
+        next_offset,
+    })
+}
+
+/// 解析用户输入的搜索, 支持 `"短语"` 和 `前缀*`, 多个词之间是 AND 关系, 例如 `"example com" git*`
+pub fn parse_search_query(input: &str) -> Vec<SearchTerm> {
+    let mut terms: Vec<SearchTerm> = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let mut term = String::new();
+
+        if c == '"' {
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                term.push(c);
+            }
+        } else {
+            term.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '"') {
+                term.push(c);
+            }
+        }
+
+        let prefix = if term.ends_with('*') {
+            term.pop();
+            true
+        } else {
+            chars.next_if_eq(&'*').is_some()
         };
-        write!(f, "Clipboard[{}]: {}", formatted_datetime, output)
+
+        let term = term.trim().replace('"', "");
+        if term.is_empty() {
+            continue;
+        }
+
+        terms.push(SearchTerm { text: term, prefix });
     }
+
+    terms
+}
+
+/// 把搜索的词转换成 FTS5 的 MATCH 语句, 每个词都用引号包起来, 避免特殊字符造成语法错误
+pub fn fts_match_query(terms: &[SearchTerm]) -> String {
+    terms
+        .iter()
+        .map(|term| {
+            if term.prefix {
+                format!("\"{}\"*", term.text)
+            } else {
+                format!("\"{}\"", term.text)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
 }
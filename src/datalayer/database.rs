@@ -1,97 +1,184 @@
+use std::collections::HashSet;
+
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use rustsqlite_derive::Table;
 use serde::{Deserialize, Serialize};
 
+use super::clipboard::{fts_match_query, Clipboard, ClipboardDataType, SearchTerm};
+use super::group::GroupRole;
 use super::DeviceType;
+use connect_any_protocol::{DeviceIdentity, DEFAULT_CHANNEL};
 
 use crate::utils::database::{
-    database_delete, database_insert, database_insert_no_id, database_select,
-    database_select_single, get_database_connection,
+    database_delete, database_insert, database_select, database_select_single,
+    database_select_single_name, is_unique_violation,
 };
-use crate::utils::{ba_error, BDEResult};
+use crate::utils::{ba_error, conflict_error, BDEResult, BDError};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Table, Debug, Clone)]
+#[table(name = "users")]
 pub struct DatabaseUser {
     pub id: u64,
     pub name: String,
 }
 
 impl DatabaseUser {
-    pub fn insert_user(name: String) -> BDEResult<u64> {
-        // Insert user into database
-        let id = database_insert("users", vec!["name"], (name.clone(),))?;
+    pub fn delete_user(&self, conn: &Connection) -> BDEResult<()> {
+        // Delete user from database
+        database_delete(conn, "users", format!("id == {}", self.id))
+    }
 
-        Ok(id)
+    pub fn find_user(conn: &Connection, name: String) -> BDEResult<Option<Self>> {
+        let mut stmt = conn.prepare("SELECT * FROM users WHERE name == ?")?;
+
+        Ok(stmt.query_row((name,), Self::from_row).optional()?)
     }
 
-    pub fn delete_user(&self) -> BDEResult<()> {
-        // Delete user from database
-        database_delete("users", format!("id == {}", self.id))
+    pub fn get_user(conn: &Connection, id: u64) -> BDEResult<Option<Self>> {
+        Ok(Self::select_by_id(conn, id)?)
+    }
+
+    pub fn get_all_users(conn: &Connection) -> BDEResult<Vec<Self>> {
+        database_select::<Self>(conn, "users", None)
     }
 
-    pub fn find_user(name: String) -> BDEResult<Option<Self>> {
-        // Find user in database
-        let user = database_select::<Self>("users", Some(format!("name == '{}'", name)))?;
+    /// 按名字模糊查找用户, 带上设备数量
+    pub fn search_users(
+        conn: &Connection,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseUserSummary>> {
+        let mut all_data: Vec<DatabaseUserSummary> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT users.id, users.name, (SELECT count(*) FROM user_device WHERE user_device.user_id = users.id) AS devices FROM users WHERE users.name LIKE ?1 ESCAPE '\\' ORDER BY users.id LIMIT ?2 OFFSET ?3",
+        )?;
+
+        let data_iter = serde_rusqlite::from_rows::<DatabaseUserSummary>(stmt.query((
+            like_pattern(query),
+            limit as u64,
+            offset as u64,
+        ))?);
 
-        Ok(user.into_iter().next())
+        for data in data_iter {
+            all_data.push(data?);
+        }
+
+        Ok(all_data)
     }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseUserSummary {
+    pub id: u64,
+    pub name: String,
+    pub devices: u64,
+}
+
+/// 用户剪切板占用的空间, 图片的字节数单独统计
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseUserStorage {
+    pub user_id: u64,
+    pub name: String,
+    pub clips: u64,
+    pub bytes: u64,
+    pub blob_bytes: u64,
+}
+
+/// 设备和设备所属的用户
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseDeviceOwner {
+    pub id: u64,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: DeviceType,
+    pub user_id: Option<u64>,
+    pub user_name: Option<String>,
+}
 
-    pub fn get_user(id: u64) -> BDEResult<Option<Self>> {
-        database_select_single("users", id)
+// 唯一约束冲突时返回给客户端冲突的错误码
+fn registration_error(err: rusqlite::Error, message: &str) -> BDError {
+    if is_unique_violation(&err) {
+        conflict_error(message)
+    } else {
+        err.into()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+// LIKE 查询里 `%` 和 `_` 按普通字符匹配
+fn like_pattern(query: &str) -> String {
+    let escaped = query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+
+    format!("%{}%", escaped)
+}
+
+#[derive(Serialize, Deserialize, Table, Debug, Clone, PartialEq, Eq)]
+#[table(name = "devices")]
 pub struct DatabaseDevice {
     pub id: u64,
     pub name: String,
     pub notification: String,
     #[serde(rename = "type")]
+    #[table(column = "type")]
     pub device_type: DeviceType,
 }
 
 impl DatabaseDevice {
-    pub fn insert_device(
-        name: String,
-        device_type: DeviceType,
-        notification: String,
-    ) -> BDEResult<Self> {
-        // Insert device into database
-        let id = database_insert(
-            "devices",
-            vec!["name", "notification", "type"],
-            (name.clone(), notification.clone(), device_type),
-        )?;
-
-        Ok(DatabaseDevice {
-            id,
-            name,
-            notification,
-            device_type,
-        })
+    pub fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            name: self.name.clone(),
+            device_type: self.device_type.to_string(),
+        }
     }
 
-    pub fn delete_device(&self) -> BDEResult<()> {
+    pub fn delete_device(&self, conn: &Connection) -> BDEResult<()> {
         // Delete device from database
-        database_delete("devices", format!("id == {}", self.id))
+        database_delete(conn, "devices", format!("id == {}", self.id))
     }
 
-    pub fn get_device(name: String, device_type: DeviceType) -> BDEResult<Self> {
-        if let Some(device) = DatabaseDevice::find_device(name.clone(), device_type)? {
-            Ok(device)
-        } else {
-            Err(ba_error(
-                format!("no ({}, {}) device found", name, device_type).as_str(),
-            ))
+    /// 按名字模糊查找设备, `user_id` 不为空时只查找这个用户的设备
+    pub fn search_devices(
+        conn: &Connection,
+        query: &str,
+        user_id: Option<u64>,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseDeviceOwner>> {
+        let mut all_data: Vec<DatabaseDeviceOwner> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT devices.id, devices.name, devices.type, users.id AS user_id, users.name AS user_name FROM devices LEFT JOIN user_device ON devices.id = user_device.device_id LEFT JOIN users ON users.id = user_device.user_id WHERE devices.name LIKE ?1 ESCAPE '\\' and (?2 IS NULL OR users.id == ?2) ORDER BY devices.id LIMIT ?3 OFFSET ?4",
+        )?;
+
+        let data_iter = serde_rusqlite::from_rows::<DatabaseDeviceOwner>(stmt.query((
+            like_pattern(query),
+            user_id,
+            limit as u64,
+            offset as u64,
+        ))?);
+
+        for data in data_iter {
+            all_data.push(data?);
         }
+
+        Ok(all_data)
     }
 
-    pub fn find_device(name: String, device_type: DeviceType) -> BDEResult<Option<Self>> {
-        // Find device in database
-        let devices = database_select::<Self>(
-            "devices",
-            Some(format!("name == '{}' and type == '{}'", name, device_type)),
-        )?;
+    pub fn find_device(
+        conn: &Connection,
+        name: String,
+        device_type: DeviceType,
+    ) -> BDEResult<Option<Self>> {
+        let mut stmt = conn.prepare("SELECT * FROM devices WHERE name == ? and type == ?")?;
 
-        Ok(devices.into_iter().next())
+        Ok(stmt
+            .query_row((name, device_type), Self::from_row)
+            .optional()?)
     }
 }
 
@@ -103,31 +190,452 @@ pub struct DatabaseUserDevice {
 }
 
 impl DatabaseUserDevice {
-    pub fn insert_user_device(user_id: u64, device_id: u64) -> BDEResult<()> {
-        // Insert user device into database
-        database_insert_no_id(
-            "user_device",
-            vec!["user_id", "device_id"],
+    /// 在一个事务里注册用户和设备, 用户不存在时创建, 设备已经属于其他用户时返回冲突
+    pub fn register_device(
+        conn: &mut Connection,
+        user_name: &str,
+        device_name: &str,
+        device_type: DeviceType,
+        notification: &str,
+    ) -> BDEResult<DatabaseUser> {
+        // 一开始就拿写锁, 同时注册的请求排队执行
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let user_id = tx
+            .query_row(
+                "SELECT id FROM users WHERE name == ?",
+                (user_name,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        let user_id = match user_id {
+            Some(user_id) => user_id,
+            None => {
+                tx.execute(DatabaseUser::INSERT_SQL, (user_name,))
+                    .map_err(|err| registration_error(err, "user name already exists"))?;
+                tx.last_insert_rowid() as u64
+            }
+        };
+
+        let device: Option<(u64, Option<u64>)> = tx
+            .query_row(
+                "SELECT devices.id, user_device.user_id FROM devices LEFT JOIN user_device ON devices.id = user_device.device_id WHERE devices.name == ? and devices.type == ?",
+                (device_name, device_type),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let device_id = match device {
+            Some((_, Some(owner_id))) if owner_id != user_id => {
+                return Err(conflict_error(
+                    format!(
+                        "device ({}, {}) is registered to another user",
+                        device_name, device_type
+                    )
+                    .as_str(),
+                ));
+            }
+            Some((_, Some(_))) => None,
+            // 没有属于任何用户的设备直接关联到这个用户
+            Some((device_id, None)) => Some(device_id),
+            None => {
+                tx.execute(
+                    DatabaseDevice::INSERT_SQL,
+                    (device_name, notification, device_type),
+                )
+                .map_err(|err| registration_error(err, "device already exists"))?;
+                Some(tx.last_insert_rowid() as u64)
+            }
+        };
+
+        if let Some(device_id) = device_id {
+            tx.execute(
+                "INSERT INTO user_device (user_id, device_id) VALUES (?, ?)",
+                (user_id, device_id),
+            )
+            .map_err(|err| registration_error(err, "device already exists"))?;
+        }
+
+        tx.commit()?;
+
+        Ok(DatabaseUser {
+            id: user_id,
+            name: user_name.to_string(),
+        })
+    }
+
+    pub fn delete_user_device(&self, conn: &Connection) -> BDEResult<()> {
+        // Delete user device from database
+        database_delete(conn, "user_device", format!("id == {}", self.id))
+    }
+
+    pub fn get_user_devices(conn: &Connection, user_id: u64) -> BDEResult<Vec<DatabaseDevice>> {
+        let mut stmt = conn.prepare("SELECT devices.* FROM devices JOIN user_device ON devices.id = user_device.device_id where user_device.user_id = ?")?;
+
+        let devices = stmt
+            .query_map((user_id,), DatabaseDevice::from_row)?
+            .collect::<Result<Vec<DatabaseDevice>, _>>()?;
+
+        Ok(devices)
+    }
+
+    /// 删除用户所有的设备和设备的设置, 返回删除的设备 id
+    pub fn delete_user_devices(conn: &mut Connection, user_id: u64) -> BDEResult<Vec<u64>> {
+        let tx = conn.transaction()?;
+
+        let device_ids = {
+            let mut stmt = tx.prepare("SELECT device_id FROM user_device WHERE user_id == ?")?;
+            let data_iter = serde_rusqlite::from_rows::<u64>(stmt.query((user_id,))?);
+
+            data_iter.collect::<Result<Vec<u64>, _>>()?
+        };
+
+        for device_id in device_ids.iter() {
+            Self::delete_device_rows(&tx, *device_id)?;
+        }
+
+        tx.commit()?;
+
+        Ok(device_ids)
+    }
+
+    /// 删除用户的一个设备和设备的设置, 返回设备是否存在
+    pub fn delete_device(conn: &mut Connection, user_id: u64, device_id: u64) -> BDEResult<bool> {
+        let tx = conn.transaction()?;
+
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM user_device WHERE user_id == ? and device_id == ?)",
             (user_id, device_id),
+            |row| row.get(0),
+        )?;
+
+        if exists {
+            Self::delete_device_rows(&tx, device_id)?;
+            tx.commit()?;
+        }
+
+        Ok(exists)
+    }
+
+    // 保留历史记录, 只去掉剪切板上的设备
+    fn delete_device_rows(tx: &rusqlite::Transaction, device_id: u64) -> BDEResult<()> {
+        tx.execute("DELETE FROM user_device WHERE device_id == ?", (device_id,))?;
+        tx.execute(
+            "DELETE FROM device_filters WHERE device_id == ?",
+            (device_id,),
+        )?;
+        tx.execute(
+            "DELETE FROM device_channels WHERE device_id == ?",
+            (device_id,),
         )?;
+        tx.execute(
+            "UPDATE clips SET device_id = NULL WHERE device_id == ?",
+            (device_id,),
+        )?;
+        tx.execute("DELETE FROM devices WHERE id == ?", (device_id,))?;
 
         Ok(())
     }
 
-    pub fn delete_user_device(&self) -> BDEResult<()> {
-        // Delete user device from database
-        database_delete("user_device", format!("id == {}", self.id))
+    pub fn get_device_users(conn: &Connection, device_id: u64) -> BDEResult<Option<DatabaseUser>> {
+        let mut stmt = conn.prepare("SELECT users.* FROM users JOIN user_device ON users.id = user_device.user_id where user_device.device_id = ?")?;
+
+        Ok(stmt
+            .query_row((device_id,), DatabaseUser::from_row)
+            .optional()?)
     }
+}
 
-    pub fn get_user_devices(user_id: u64) -> BDEResult<Vec<DatabaseDevice>> {
-        let mut all_data: Vec<DatabaseDevice> = Vec::new();
-        let conn = get_database_connection()?;
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseClip {
+    pub id: u64,
+    pub user_id: u64,
+    // 上传的设备被删除后为空
+    pub device_id: Option<u64>,
+    #[serde(rename = "type")]
+    pub clip_type: ClipboardDataType,
+    pub data: String,
+    // 客户端上传的时间
+    pub date: u64,
+    pub received_at: u64,
+    pub seq: u64,
+    pub future_date: bool,
+    // 内容的字节数, 图片保存在 blob 里时 data 为空
+    pub size: u64,
+    pub blob: Option<String>,
+    pub pinned: bool,
+    pub favorite: bool,
+    pub sensitive: bool,
+    // 定向发送的目标设备, json 数组
+    pub targets: Option<String>,
+    pub group_id: Option<u64>,
+    pub channel: String,
+    pub hash: Option<String>,
+    pub copy_count: u64,
+    // 从 devices 表 join 出来的上传设备
+    pub device_name: Option<String>,
+    pub device_type: Option<String>,
+}
 
-        let sql_command = format!("SELECT devices.* FROM devices JOIN user_device ON devices.id = user_device.device_id where user_device.user_id = {}", user_id);
+/// 新的剪切板的序号从这个值往上分配, 导入的历史记录从这个值往下分配
+pub const IMPORT_SEQ_BASE: u64 = 1 << 40;
 
-        let mut stmt = conn.prepare(sql_command.as_str())?;
+/// `save_clip` 保存的剪切板, `duplicate` 时是更新了原来的剪切板
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavedClip {
+    pub id: u64,
+    pub seq: u64,
+    pub duplicate: bool,
+}
+
+const CLIP_SELECT_SQL: &str = "SELECT clips.*, devices.name AS device_name, devices.type AS device_type FROM clips LEFT JOIN devices ON clips.device_id = devices.id";
+
+#[derive(Default)]
+pub struct ClipFilter {
+    // 只返回序号小于 before 的剪切板, 用于分页
+    pub before: Option<u64>,
+    pub clip_type: Option<ClipboardDataType>,
+    pub device_id: Option<u64>,
+    // 服务器收到的时间范围
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub pinned: Option<bool>,
+    pub favorite: Option<bool>,
+    // 只返回这个设备能收到的剪切板 (同步给所有设备, 自己上传的或者发送给它的)
+    pub recipient: Option<u64>,
+    // 群组的剪切板, 为空时查找用户的个人剪切板
+    pub group_id: Option<u64>,
+    // 只返回这些频道的剪切板
+    pub channels: Option<Vec<String>>,
+    // 只返回同步给所有设备的剪切板, 不包括定向发送的
+    pub untargeted: bool,
+    pub limit: usize,
+}
+
+impl DatabaseClip {
+    /// 分配下一个剪切板序号
+    pub fn next_seq(conn: &Connection) -> BDEResult<u64> {
+        let seq = conn.query_row(
+            "UPDATE clip_sequence SET value = value + 1 WHERE id == 1 RETURNING value",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(seq)
+    }
+
+    /// 为导入的历史记录预留 `count` 个比所有剪切板都小的序号, 返回第一个
+    pub fn reserve_import_seqs(conn: &Connection, count: u64) -> BDEResult<u64> {
+        let start = conn
+            .query_row(
+                "UPDATE clip_sequence SET import_value = import_value - ?1 WHERE id == 1 and import_value > ?1 RETURNING import_value",
+                (count,),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        start.ok_or_else(|| ba_error("no import sequence left"))
+    }
+
+    pub fn insert_clip(
+        conn: &Connection,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+    ) -> BDEResult<u64> {
+        let data = if blob.is_some() {
+            String::new()
+        } else {
+            clip.data.clone()
+        };
+        let targets = match &clip.targets {
+            Some(targets) => Some(serde_json::to_string(targets)?),
+            None => None,
+        };
+
+        database_insert(
+            conn,
+            "clips",
+            vec![
+                "user_id",
+                "device_id",
+                "type",
+                "data",
+                "date",
+                "received_at",
+                "seq",
+                "future_date",
+                "size",
+                "blob",
+                "sensitive",
+                "targets",
+                "group_id",
+                "channel",
+                "hash",
+            ],
+            (
+                user_id,
+                device_id,
+                clip.clipboard_type.to_string(),
+                data,
+                clip.date as u64,
+                clip.received_at,
+                clip.seq,
+                clip.future_date,
+                size,
+                blob,
+                clip.sensitive,
+                targets,
+                clip.group_id,
+                clip.channel.as_deref().unwrap_or(DEFAULT_CHANNEL),
+                hash,
+            ),
+        )
+    }
+
+    /// 在一个事务里查找 `since` 之后收到的相同内容, 找到时更新原来的剪切板, 否则插入新的剪切板
+    ///
+    /// 一开始就拿写锁, 同时上传相同内容的请求排队执行, 只会保存一份
+    #[allow(clippy::too_many_arguments)]
+    pub fn save_clip(
+        conn: &mut Connection,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+        since: Option<u64>,
+    ) -> BDEResult<SavedClip> {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let duplicate = match since {
+            Some(since) => Self::find_duplicate_clip(&tx, user_id, clip, hash, since)?,
+            None => None,
+        };
+        let seq = Self::next_seq(&tx)?;
+
+        let saved = match duplicate {
+            Some(id) => {
+                Self::bump_clip(&tx, id, clip.date as u64, clip.received_at, seq)?;
+
+                SavedClip {
+                    id,
+                    seq,
+                    duplicate: true,
+                }
+            }
+            None => {
+                let mut clip = clip.clone();
+                clip.seq = seq;
+
+                SavedClip {
+                    id: Self::insert_clip(&tx, user_id, device_id, &clip, size, blob, hash)?,
+                    seq,
+                    duplicate: false,
+                }
+            }
+        };
+
+        tx.commit()?;
+
+        Ok(saved)
+    }
+
+    /// 在同一个用户频道或者群组里找服务器在 `since` 之后收到的内容相同的最新的剪切板
+    pub fn find_duplicate_clip(
+        conn: &Connection,
+        user_id: u64,
+        clip: &Clipboard,
+        hash: &str,
+        since: u64,
+    ) -> BDEResult<Option<u64>> {
+        let mut stmt = match clip.group_id {
+            Some(_) => conn.prepare(
+                "SELECT id FROM clips WHERE group_id == ?1 and hash == ?2 and received_at >= ?3 and targets IS NULL ORDER BY seq DESC LIMIT 1",
+            )?,
+            None => conn.prepare(
+                "SELECT id FROM clips WHERE user_id == ?1 and group_id IS NULL and channel == ?4 and hash == ?2 and received_at >= ?3 and targets IS NULL ORDER BY seq DESC LIMIT 1",
+            )?,
+        };
+
+        let mut params: Vec<Value> = vec![
+            Value::Integer(clip.group_id.unwrap_or(user_id) as i64),
+            Value::Text(hash.to_string()),
+            Value::Integer(since as i64),
+        ];
+        if clip.group_id.is_none() {
+            params.push(Value::Text(
+                clip.channel
+                    .as_deref()
+                    .unwrap_or(DEFAULT_CHANNEL)
+                    .to_string(),
+            ));
+        }
+
+        let mut data_iter =
+            serde_rusqlite::from_rows::<u64>(stmt.query(rusqlite::params_from_iter(params))?);
+
+        Ok(data_iter.next().transpose()?)
+    }
+
+    /// 重复复制时更新剪切板的时间, 序号和复制次数
+    pub fn bump_clip(
+        conn: &Connection,
+        id: u64,
+        date: u64,
+        received_at: u64,
+        seq: u64,
+    ) -> BDEResult<()> {
+        conn.execute(
+            "UPDATE clips SET date = max(date, ?1), received_at = ?2, seq = ?3, copy_count = copy_count + 1 WHERE id == ?4",
+            (date, received_at, seq, id),
+        )?;
+
+        Ok(())
+    }
+
+    /// 修改剪切板的固定/收藏状态, 为空的项保持不变, 返回剪切板是否存在
+    pub fn update_clip_flags(
+        conn: &Connection,
+        user_id: u64,
+        id: u64,
+        pinned: Option<bool>,
+        favorite: Option<bool>,
+    ) -> BDEResult<bool> {
+        let changed = conn.execute(
+            "UPDATE clips SET pinned = coalesce(?1, pinned), favorite = coalesce(?2, favorite) WHERE user_id == ?3 and id == ?4",
+            (pinned, favorite, user_id, id),
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    /// 删除用户所有的个人剪切板, `keep_pinned` 时保留固定的剪切板, 返回删除的数量
+    pub fn delete_user_clips(
+        conn: &Connection,
+        user_id: u64,
+        keep_pinned: bool,
+    ) -> BDEResult<usize> {
+        let changed = conn.execute(
+            "DELETE FROM clips WHERE user_id == ?1 and group_id IS NULL and (?2 == 0 OR pinned == 0)",
+            (user_id, keep_pinned),
+        )?;
+
+        Ok(changed)
+    }
+
+    /// 每个用户的剪切板数量和字节数, 按字节数从大到小
+    pub fn get_user_storage(conn: &Connection) -> BDEResult<Vec<DatabaseUserStorage>> {
+        let mut all_data: Vec<DatabaseUserStorage> = Vec::new();
 
-        let data_iter = serde_rusqlite::from_rows::<DatabaseDevice>(stmt.query([])?);
+        let mut stmt = conn.prepare(
+            "SELECT users.id AS user_id, users.name, count(clips.id) AS clips, coalesce(sum(clips.size), 0) AS bytes, coalesce(sum(CASE WHEN clips.blob IS NOT NULL THEN clips.size ELSE 0 END), 0) AS blob_bytes FROM users LEFT JOIN clips ON clips.user_id = users.id GROUP BY users.id ORDER BY bytes DESC, users.id",
+        )?;
+
+        let data_iter = serde_rusqlite::from_rows::<DatabaseUserStorage>(stmt.query([])?);
 
         for data in data_iter {
             all_data.push(data?);
@@ -136,20 +644,565 @@ impl DatabaseUserDevice {
         Ok(all_data)
     }
 
-    pub fn get_device_users(device_id: u64) -> BDEResult<Option<DatabaseUser>> {
-        let mut all_data: Vec<DatabaseUser> = Vec::new();
-        let conn = get_database_connection()?;
+    /// 删除用户的一条剪切板, 返回剪切板是否存在
+    pub fn delete_user_clip(conn: &Connection, user_id: u64, id: u64) -> BDEResult<bool> {
+        let changed = conn.execute(
+            "DELETE FROM clips WHERE user_id == ?1 and id == ?2",
+            (user_id, id),
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    pub fn delete_clips(conn: &Connection, ids: &[u64]) -> BDEResult<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let ids = ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        database_delete(conn, "clips", format!("id IN ({})", ids))
+    }
+
+    /// 用户所有没有固定的剪切板, 按序号从新到旧
+    pub fn get_unpinned_clip_sizes(
+        conn: &Connection,
+        user_id: u64,
+    ) -> BDEResult<Vec<DatabaseClipSize>> {
+        let mut all_data: Vec<DatabaseClipSize> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, received_at AS date, size, sensitive FROM clips WHERE user_id == ? and group_id IS NULL and pinned == 0 ORDER BY seq DESC",
+        )?;
+
+        let data_iter = serde_rusqlite::from_rows::<DatabaseClipSize>(stmt.query((user_id,))?);
+
+        for data in data_iter {
+            all_data.push(data?);
+        }
+
+        Ok(all_data)
+    }
+
+    /// 群组所有没有固定的剪切板, 按序号从新到旧
+    pub fn get_unpinned_group_clip_sizes(
+        conn: &Connection,
+        group_id: u64,
+    ) -> BDEResult<Vec<DatabaseClipSize>> {
+        let mut all_data: Vec<DatabaseClipSize> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, received_at AS date, size, sensitive FROM clips WHERE group_id == ? and pinned == 0 ORDER BY seq DESC",
+        )?;
+
+        let data_iter = serde_rusqlite::from_rows::<DatabaseClipSize>(stmt.query((group_id,))?);
+
+        for data in data_iter {
+            all_data.push(data?);
+        }
+
+        Ok(all_data)
+    }
+
+    pub fn get_blob_names(conn: &Connection) -> BDEResult<HashSet<String>> {
+        let mut stmt = conn.prepare("SELECT blob FROM clips WHERE blob IS NOT NULL")?;
+
+        let data_iter = serde_rusqlite::from_rows::<String>(stmt.query([])?);
+
+        let mut names = HashSet::new();
+        for data in data_iter {
+            names.insert(data?);
+        }
+
+        Ok(names)
+    }
+
+    /// 用户自己的剪切板, 或者用户所在群组的剪切板
+    pub fn get_clip(conn: &Connection, user_id: u64, id: u64) -> BDEResult<Option<Self>> {
+        let clips = Self::query_clips(conn,
+            "(clips.user_id == ? or clips.group_id IN (SELECT group_id FROM group_members WHERE user_id == ?)) and clips.id == ?",
+            vec![
+                Value::Integer(user_id as i64),
+                Value::Integer(user_id as i64),
+                Value::Integer(id as i64),
+            ],
+            1,
+        )?;
+
+        Ok(clips.into_iter().next())
+    }
+
+    /// 按序号从新到旧查找用户或者群组的剪切板
+    pub fn find_clips(
+        conn: &Connection,
+        user_id: u64,
+        filter: &ClipFilter,
+    ) -> BDEResult<Vec<Self>> {
+        let (mut where_args, mut params) = match filter.group_id {
+            Some(group_id) => (
+                vec!["clips.group_id == ?"],
+                vec![Value::Integer(group_id as i64)],
+            ),
+            None => (
+                vec!["clips.user_id == ? and clips.group_id IS NULL"],
+                vec![Value::Integer(user_id as i64)],
+            ),
+        };
+
+        if let Some(before) = filter.before {
+            where_args.push("clips.seq < ?");
+            params.push(Value::Integer(before as i64));
+        }
+
+        if let Some(clip_type) = filter.clip_type {
+            where_args.push("clips.type == ?");
+            params.push(Value::Text(clip_type.to_string()));
+        }
+
+        if let Some(device_id) = filter.device_id {
+            where_args.push("clips.device_id == ?");
+            params.push(Value::Integer(device_id as i64));
+        }
+
+        if let Some(from) = filter.from {
+            where_args.push("clips.received_at >= ?");
+            params.push(Value::Integer(from as i64));
+        }
+
+        if let Some(to) = filter.to {
+            where_args.push("clips.received_at <= ?");
+            params.push(Value::Integer(to as i64));
+        }
+
+        if let Some(pinned) = filter.pinned {
+            where_args.push("clips.pinned == ?");
+            params.push(Value::Integer(pinned as i64));
+        }
+
+        if let Some(favorite) = filter.favorite {
+            where_args.push("clips.favorite == ?");
+            params.push(Value::Integer(favorite as i64));
+        }
+
+        let channel_args;
+        if let Some(channels) = &filter.channels {
+            channel_args = format!(
+                "clips.channel IN ({})",
+                vec!["?"; channels.len()].join(", ")
+            );
+            where_args.push(channel_args.as_str());
+            params.extend(channels.iter().map(|channel| Value::Text(channel.clone())));
+        }
+
+        if let Some(recipient) = filter.recipient {
+            where_args.push("(clips.targets IS NULL OR clips.device_id == ? OR EXISTS (SELECT 1 FROM json_each(clips.targets) WHERE json_each.value == ?))");
+            params.push(Value::Integer(recipient as i64));
+            params.push(Value::Integer(recipient as i64));
+        }
+
+        if filter.untargeted {
+            where_args.push("clips.targets IS NULL");
+        }
+
+        Self::query_clips(
+            conn,
+            where_args.join(" and ").as_str(),
+            params,
+            filter.limit,
+        )
+    }
+
+    /// 在用户的文字剪切板中全文搜索, 按相关度排序
+    pub fn search_clips(
+        conn: &Connection,
+        user_id: u64,
+        terms: &[SearchTerm],
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<(Self, String)>> {
+        let mut all_data: Vec<(Self, String)> = Vec::new();
+        let query = fts_match_query(terms);
+
+        let mut stmt = conn.prepare(
+            "SELECT clips.*, devices.name AS device_name, devices.type AS device_type, snippet(clips_fts, 0, '<mark>', '</mark>', '...', 16) AS snippet FROM clips_fts JOIN clips ON clips.id = clips_fts.rowid LEFT JOIN devices ON clips.device_id = devices.id WHERE clips_fts MATCH ?1 and clips.user_id == ?2 and clips.group_id IS NULL ORDER BY rank LIMIT ?3 OFFSET ?4",
+        )?;
+
+        let mut rows = stmt.query((query.as_str(), user_id, limit as u64, offset as u64))?;
+
+        while let Some(row) = rows.next()? {
+            let clip = serde_rusqlite::from_row::<Self>(row)?;
+            let snippet: String = row.get("snippet")?;
+
+            all_data.push((clip, snippet));
+        }
+
+        Ok(all_data)
+    }
+
+    fn query_clips(
+        conn: &Connection,
+        where_args: &str,
+        params: Vec<Value>,
+        limit: usize,
+    ) -> BDEResult<Vec<Self>> {
+        let mut all_data: Vec<Self> = Vec::new();
 
-        let sql_command = format!("SELECT users.* FROM users JOIN user_device ON users.id = user_device.user_id where user_device.device_id = {}", device_id);
+        let sql_command = format!(
+            "{} WHERE {} ORDER BY clips.seq DESC LIMIT {}",
+            CLIP_SELECT_SQL, where_args, limit
+        );
 
         let mut stmt = conn.prepare(sql_command.as_str())?;
 
-        let data_iter = serde_rusqlite::from_rows::<DatabaseUser>(stmt.query([])?);
+        let data_iter =
+            serde_rusqlite::from_rows::<Self>(stmt.query(rusqlite::params_from_iter(params))?);
 
         for data in data_iter {
             all_data.push(data?);
         }
 
-        Ok(all_data.into_iter().next())
+        Ok(all_data)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseClipSize {
+    pub id: u64,
+    // 服务器收到的时间
+    pub date: u64,
+    pub size: u64,
+    pub sensitive: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DatabaseRetentionPolicy {
+    pub user_id: u64,
+    pub max_count: Option<u64>,
+    pub max_age_days: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub sensitive_ttl_secs: Option<u64>,
+}
+
+impl DatabaseRetentionPolicy {
+    pub fn get_policy(conn: &Connection, user_id: u64) -> BDEResult<Option<Self>> {
+        database_select_single_name(conn, "retention_policies", user_id, "user_id")
+    }
+
+    pub fn set_policy(&self, conn: &Connection) -> BDEResult<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO retention_policies (user_id, max_count, max_age_days, max_bytes, sensitive_ttl_secs) VALUES (?, ?, ?, ?, ?)",
+            (
+                self.user_id,
+                self.max_count,
+                self.max_age_days,
+                self.max_bytes,
+                self.sensitive_ttl_secs,
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseDeviceFilter {
+    pub device_id: u64,
+    // DeviceFilter 的 json
+    pub rules: String,
+}
+
+impl DatabaseDeviceFilter {
+    pub fn get_filter(conn: &Connection, device_id: u64) -> BDEResult<Option<Self>> {
+        database_select_single_name(conn, "device_filters", device_id, "device_id")
+    }
+
+    pub fn set_filter(&self, conn: &Connection) -> BDEResult<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO device_filters (device_id, rules) VALUES (?, ?)",
+            (self.device_id, self.rules.clone()),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseGroup {
+    pub id: u64,
+    pub name: String,
+    pub owner_id: u64,
+}
+
+impl DatabaseGroup {
+    /// 创建群组并把创建者加为 owner, 在同一个事务里
+    pub fn create_group(conn: &mut Connection, name: &str, owner_id: u64) -> BDEResult<u64> {
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "INSERT INTO clip_groups (name, owner_id) VALUES (?, ?)",
+            (name, owner_id),
+        )?;
+        let id = tx.last_insert_rowid() as u64;
+
+        tx.execute(
+            "INSERT INTO group_members (group_id, user_id, role) VALUES (?, ?, ?)",
+            (id, owner_id, GroupRole::Owner),
+        )?;
+
+        tx.commit()?;
+
+        Ok(id)
+    }
+
+    pub fn get_group(conn: &Connection, id: u64) -> BDEResult<Option<Self>> {
+        database_select_single(conn, "clip_groups", id)
+    }
+
+    pub fn find_group(conn: &Connection, name: &str) -> BDEResult<Option<Self>> {
+        let mut stmt = conn.prepare("SELECT * FROM clip_groups WHERE name == ?")?;
+
+        let mut data_iter = serde_rusqlite::from_rows::<Self>(stmt.query((name,))?);
+
+        Ok(data_iter.next().transpose()?)
+    }
+
+    pub fn get_all_groups(conn: &Connection) -> BDEResult<Vec<Self>> {
+        database_select::<Self>(conn, "clip_groups", None)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseGroupMember {
+    pub group_id: u64,
+    pub user_id: u64,
+    pub role: GroupRole,
+}
+
+/// 群组成员和用户名
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseGroupMemberName {
+    pub user_id: u64,
+    pub name: String,
+    pub role: GroupRole,
+}
+
+/// 用户加入的群组和在群组里的角色
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseUserGroup {
+    pub id: u64,
+    pub name: String,
+    pub role: GroupRole,
+}
+
+impl DatabaseGroupMember {
+    pub fn get_member(conn: &Connection, group_id: u64, user_id: u64) -> BDEResult<Option<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT * FROM group_members WHERE group_id == ? and user_id == ?")?;
+
+        let mut data_iter = serde_rusqlite::from_rows::<Self>(stmt.query((group_id, user_id))?);
+
+        Ok(data_iter.next().transpose()?)
+    }
+
+    /// 添加成员, 已经是成员时修改角色
+    pub fn set_member(&self, conn: &Connection) -> BDEResult<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO group_members (group_id, user_id, role) VALUES (?, ?, ?)",
+            (self.group_id, self.user_id, self.role),
+        )?;
+
+        Ok(())
+    }
+
+    /// 删除成员, 返回成员是否存在
+    pub fn delete_member(conn: &Connection, group_id: u64, user_id: u64) -> BDEResult<bool> {
+        let changed = conn.execute(
+            "DELETE FROM group_members WHERE group_id == ? and user_id == ?",
+            (group_id, user_id),
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    pub fn get_group_members(
+        conn: &Connection,
+        group_id: u64,
+    ) -> BDEResult<Vec<DatabaseGroupMemberName>> {
+        let mut all_data: Vec<DatabaseGroupMemberName> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT group_members.user_id, users.name, group_members.role FROM group_members JOIN users ON users.id = group_members.user_id WHERE group_members.group_id == ? ORDER BY group_members.user_id",
+        )?;
+
+        let data_iter =
+            serde_rusqlite::from_rows::<DatabaseGroupMemberName>(stmt.query((group_id,))?);
+
+        for data in data_iter {
+            all_data.push(data?);
+        }
+
+        Ok(all_data)
+    }
+
+    pub fn get_user_groups(conn: &Connection, user_id: u64) -> BDEResult<Vec<DatabaseUserGroup>> {
+        let mut all_data: Vec<DatabaseUserGroup> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT clip_groups.id, clip_groups.name, group_members.role FROM clip_groups JOIN group_members ON clip_groups.id = group_members.group_id WHERE group_members.user_id == ? ORDER BY clip_groups.id",
+        )?;
+
+        let data_iter = serde_rusqlite::from_rows::<DatabaseUserGroup>(stmt.query((user_id,))?);
+
+        for data in data_iter {
+            all_data.push(data?);
+        }
+
+        Ok(all_data)
+    }
+
+    /// 群组所有成员的所有设备
+    pub fn get_group_devices(conn: &Connection, group_id: u64) -> BDEResult<Vec<DatabaseDevice>> {
+        let mut all_data: Vec<DatabaseDevice> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT devices.* FROM devices JOIN user_device ON devices.id = user_device.device_id JOIN group_members ON group_members.user_id = user_device.user_id WHERE group_members.group_id == ?",
+        )?;
+
+        let data_iter = serde_rusqlite::from_rows::<DatabaseDevice>(stmt.query((group_id,))?);
+
+        for data in data_iter {
+            all_data.push(data?);
+        }
+
+        Ok(all_data)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DatabaseChannel {
+    pub id: u64,
+    pub user_id: u64,
+    pub name: String,
+}
+
+impl DatabaseChannel {
+    pub fn insert_channel(conn: &Connection, user_id: u64, name: String) -> BDEResult<u64> {
+        database_insert(conn, "channels", vec!["user_id", "name"], (user_id, name))
+    }
+
+    pub fn get_user_channels(conn: &Connection, user_id: u64) -> BDEResult<Vec<Self>> {
+        let mut all_data: Vec<Self> = Vec::new();
+
+        let mut stmt = conn.prepare("SELECT * FROM channels WHERE user_id == ? ORDER BY id")?;
+
+        let data_iter = serde_rusqlite::from_rows::<Self>(stmt.query((user_id,))?);
+
+        for data in data_iter {
+            all_data.push(data?);
+        }
+
+        Ok(all_data)
+    }
+
+    /// 删除频道, 频道里的剪切板和设备的订阅, 返回频道是否存在
+    pub fn delete_channel(conn: &mut Connection, user_id: u64, name: &str) -> BDEResult<bool> {
+        let tx = conn.transaction()?;
+
+        let changed = tx.execute(
+            "DELETE FROM channels WHERE user_id == ? and name == ?",
+            (user_id, name),
+        )?;
+
+        if changed > 0 {
+            tx.execute(
+                "DELETE FROM clips WHERE user_id == ? and group_id IS NULL and channel == ?",
+                (user_id, name),
+            )?;
+            tx.execute(
+                "DELETE FROM device_channels WHERE channel == ? and device_id IN (SELECT device_id FROM user_device WHERE user_id == ?)",
+                (name, user_id),
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(changed > 0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DatabaseDeviceChannel {
+    pub device_id: u64,
+    pub channel: String,
+}
+
+impl DatabaseDeviceChannel {
+    pub fn get_device_channels(conn: &Connection, device_id: u64) -> BDEResult<Vec<String>> {
+        Ok(database_select::<Self>(
+            conn,
+            "device_channels",
+            Some(format!("device_id == {}", device_id)),
+        )?
+        .into_iter()
+        .map(|channel| channel.channel)
+        .collect())
+    }
+
+    /// 替换设备订阅的所有频道
+    pub fn set_device_channels(
+        conn: &mut Connection,
+        device_id: u64,
+        channels: &[String],
+    ) -> BDEResult<()> {
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "DELETE FROM device_channels WHERE device_id == ?",
+            (device_id,),
+        )?;
+
+        for channel in channels {
+            tx.execute(
+                "INSERT INTO device_channels (device_id, channel) VALUES (?, ?)",
+                (device_id, channel),
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+impl From<DatabaseClip> for Clipboard {
+    fn from(clip: DatabaseClip) -> Self {
+        let source = match (clip.device_name, clip.device_type) {
+            (Some(name), Some(device_type)) => Some(DeviceIdentity { name, device_type }),
+            _ => None,
+        };
+
+        Clipboard {
+            id: clip.id,
+            data: clip.data,
+            clipboard_type: clip.clip_type,
+            date: clip.date as u128,
+            received_at: clip.received_at,
+            seq: clip.seq,
+            future_date: clip.future_date,
+            source,
+            pinned: clip.pinned,
+            favorite: clip.favorite,
+            sensitive: clip.sensitive,
+            targets: clip
+                .targets
+                .and_then(|targets| serde_json::from_str(&targets).ok()),
+            group_id: clip.group_id,
+            channel: (clip.channel != DEFAULT_CHANNEL).then_some(clip.channel),
+            copy_count: clip.copy_count,
+        }
     }
 }
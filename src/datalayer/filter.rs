@@ -0,0 +1,127 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::clipboard::{Clipboard, ClipboardDataType};
+use super::database::DatabaseDeviceFilter;
+use super::storage::Storage;
+use crate::utils::{ba_error, BDEResult};
+
+/// 只在一天中的这段时间接收剪切板, `start` 大于 `end` 时表示跨过午夜
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReceiveWindow {
+    // "HH:MM"
+    pub start: String,
+    pub end: String,
+    // 设备所在时区, 为空时使用服务器的时区
+    #[serde(default)]
+    pub utc_offset_minutes: Option<i32>,
+}
+
+impl ReceiveWindow {
+    fn parse_time(time: &str) -> BDEResult<NaiveTime> {
+        Ok(NaiveTime::parse_from_str(time, "%H:%M")?)
+    }
+
+    pub fn validate(&self) -> BDEResult<()> {
+        Self::parse_time(&self.start)?;
+        Self::parse_time(&self.end)?;
+
+        if let Some(offset) = self.utc_offset_minutes {
+            FixedOffset::east_opt(offset * 60).ok_or_else(|| ba_error("invalid utc offset"))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let (Ok(start), Ok(end)) = (Self::parse_time(&self.start), Self::parse_time(&self.end))
+        else {
+            return true;
+        };
+
+        let now = match self
+            .utc_offset_minutes
+            .and_then(|offset| FixedOffset::east_opt(offset * 60))
+        {
+            Some(offset) => now.with_timezone(&offset).time(),
+            None => now.with_timezone(&Local).time(),
+        };
+        let now = now.with_second(0).unwrap_or(now);
+
+        if start <= end {
+            start <= now && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+}
+
+/// 设备接收剪切板的规则, 默认接收所有内容
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct DeviceFilter {
+    // 只接收这些类型, 为空时接收所有类型
+    pub clip_types: Vec<ClipboardDataType>,
+    // 不接收超过这个大小的图片
+    pub max_image_bytes: Option<u64>,
+    // 不接收这些设备 (device id) 上传的剪切板
+    pub ignore_devices: Vec<u64>,
+    pub receive_window: Option<ReceiveWindow>,
+}
+
+impl DeviceFilter {
+    pub fn validate(&self) -> BDEResult<()> {
+        if let Some(window) = &self.receive_window {
+            window.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// 设备是否应该收到 `from_device` 上传的 `clipboard`
+    pub fn allows(&self, clipboard: &Clipboard, from_device: u64, now: DateTime<Utc>) -> bool {
+        if !self.clip_types.is_empty() && !self.clip_types.contains(&clipboard.clipboard_type) {
+            return false;
+        }
+
+        if let Some(max_image_bytes) = self.max_image_bytes {
+            if clipboard.clipboard_type == ClipboardDataType::Image
+                && clipboard.data.len() as u64 > max_image_bytes
+            {
+                return false;
+            }
+        }
+
+        if self.ignore_devices.contains(&from_device) {
+            return false;
+        }
+
+        if let Some(window) = &self.receive_window {
+            if !window.contains(now) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+pub fn get_device_filter(storage: &dyn Storage, device_id: u64) -> BDEResult<DeviceFilter> {
+    match storage.get_device_filter(device_id)? {
+        Some(filter) => Ok(serde_json::from_str(&filter.rules)?),
+        None => Ok(DeviceFilter::default()),
+    }
+}
+
+pub fn set_device_filter(
+    storage: &dyn Storage,
+    device_id: u64,
+    filter: &DeviceFilter,
+) -> BDEResult<()> {
+    filter.validate()?;
+
+    storage.set_device_filter(&DatabaseDeviceFilter {
+        device_id,
+        rules: serde_json::to_string(filter)?,
+    })
+}
@@ -0,0 +1,154 @@
+use rustsqlite_derive::{FromSqlMacro, ToSqlMacro};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use super::database::{DatabaseGroupMember, DatabaseGroupMemberName, DatabaseUserGroup};
+use super::storage::Storage;
+use super::Device;
+use crate::utils::{ba_error, BDEResult};
+
+/// 群组成员的角色
+#[derive(
+    Deserialize,
+    Serialize,
+    EnumString,
+    Display,
+    ToSqlMacro,
+    FromSqlMacro,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+pub enum GroupRole {
+    // 创建群组的用户, 不能被移除
+    Owner,
+    // 可以管理成员
+    Admin,
+    Member,
+    // 只能接收, 不能发布剪切板
+    Viewer,
+}
+
+impl GroupRole {
+    pub fn can_publish(self) -> bool {
+        self != GroupRole::Viewer
+    }
+
+    pub fn can_manage(self) -> bool {
+        matches!(self, GroupRole::Owner | GroupRole::Admin)
+    }
+}
+
+pub type Group = DatabaseUserGroup;
+pub type GroupMember = DatabaseGroupMemberName;
+
+/// 创建群组, 创建者成为群组的 owner
+pub fn create_group(storage: &dyn Storage, user_id: u64, name: &str) -> BDEResult<Group> {
+    let name = name.trim().to_string();
+
+    if name.is_empty() {
+        return Err(ba_error("group name is empty"));
+    }
+
+    if storage.find_group(&name)?.is_some() {
+        return Err(ba_error("group already exists"));
+    }
+
+    let id = storage.create_group(&name, user_id)?;
+
+    Ok(Group {
+        id,
+        name,
+        role: GroupRole::Owner,
+    })
+}
+
+pub fn user_groups(storage: &dyn Storage, user_id: u64) -> BDEResult<Vec<Group>> {
+    storage.get_user_groups(user_id)
+}
+
+/// 用户在群组里的角色, 不是成员时返回错误
+pub fn member_role(storage: &dyn Storage, group_id: u64, user_id: u64) -> BDEResult<GroupRole> {
+    match storage.get_member_role(group_id, user_id)? {
+        Some(role) => Ok(role),
+        None => Err(ba_error("not a member of the group")),
+    }
+}
+
+pub fn group_members(storage: &dyn Storage, group_id: u64) -> BDEResult<Vec<GroupMember>> {
+    storage.get_group_members(group_id)
+}
+
+pub fn group_devices(storage: &dyn Storage, group_id: u64) -> BDEResult<Vec<Device>> {
+    storage.get_group_devices(group_id)
+}
+
+/// `operator_id` 添加成员或者修改成员的角色, 只有 owner 可以设置 admin
+pub fn set_group_member(
+    storage: &dyn Storage,
+    group_id: u64,
+    operator_id: u64,
+    user_id: u64,
+    role: GroupRole,
+) -> BDEResult<()> {
+    let operator = member_role(storage, group_id, operator_id)?;
+
+    if !operator.can_manage() {
+        return Err(ba_error("no permission to manage the group"));
+    }
+
+    if role == GroupRole::Owner {
+        return Err(ba_error("group can only have one owner"));
+    }
+
+    if role == GroupRole::Admin && operator != GroupRole::Owner {
+        return Err(ba_error("only owner can add admin"));
+    }
+
+    if let Some(member) = storage.get_member_role(group_id, user_id)? {
+        if member == GroupRole::Owner {
+            return Err(ba_error("can not change the owner"));
+        }
+
+        if member == GroupRole::Admin && operator != GroupRole::Owner {
+            return Err(ba_error("only owner can change admin"));
+        }
+    }
+
+    storage.set_member(&DatabaseGroupMember {
+        group_id,
+        user_id,
+        role,
+    })
+}
+
+/// `operator_id` 移除成员, 成员也可以自己退出群组
+pub fn remove_group_member(
+    storage: &dyn Storage,
+    group_id: u64,
+    operator_id: u64,
+    user_id: u64,
+) -> BDEResult<()> {
+    let operator = member_role(storage, group_id, operator_id)?;
+    let member = member_role(storage, group_id, user_id)?;
+
+    if member == GroupRole::Owner {
+        return Err(ba_error("owner can not leave the group"));
+    }
+
+    if operator_id != user_id {
+        if !operator.can_manage() {
+            return Err(ba_error("no permission to manage the group"));
+        }
+
+        if member == GroupRole::Admin && operator != GroupRole::Owner {
+            return Err(ba_error("only owner can remove admin"));
+        }
+    }
+
+    storage.delete_member(group_id, user_id)?;
+
+    Ok(())
+}
@@ -0,0 +1,905 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+use connect_any_protocol::DEFAULT_CHANNEL;
+
+use super::clipboard::{Clipboard, ClipboardDataType, SearchTerm};
+use super::database::{
+    ClipFilter, DatabaseClip, DatabaseClipSize, DatabaseDeviceFilter, DatabaseDeviceOwner,
+    DatabaseGroup, DatabaseGroupMember, DatabaseGroupMemberName, DatabaseRetentionPolicy,
+    DatabaseUser, DatabaseUserGroup, DatabaseUserStorage, DatabaseUserSummary, SavedClip,
+    IMPORT_SEQ_BASE,
+};
+use super::group::GroupRole;
+use super::storage::Storage;
+use super::{Device, DeviceType};
+use crate::utils::{ba_error, conflict_error, BDEResult};
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    users: Vec<DatabaseUser>,
+    devices: Vec<Device>,
+    // (user id, device id)
+    user_devices: Vec<(u64, u64)>,
+    clips: Vec<DatabaseClip>,
+    // (user id, 频道名)
+    channels: Vec<(u64, String)>,
+    device_channels: HashMap<u64, Vec<String>>,
+    device_filters: HashMap<u64, DatabaseDeviceFilter>,
+    retention_policies: HashMap<u64, DatabaseRetentionPolicy>,
+    groups: Vec<DatabaseGroup>,
+    group_members: Vec<DatabaseGroupMember>,
+    last_user_id: u64,
+    last_device_id: u64,
+    last_clip_id: u64,
+    last_group_id: u64,
+    // 序号和 sqlite 一样从 `IMPORT_SEQ_BASE` 往上分配, 导入的历史记录往下分配
+    clip_seq: u64,
+    import_seqs: u64,
+}
+
+impl MemoryData {
+    // 和 sqlite 里 join 出来的一样, 带上上传设备的名字和类型
+    fn with_device(&self, clip: &DatabaseClip) -> DatabaseClip {
+        let mut clip = clip.clone();
+        let device = clip
+            .device_id
+            .and_then(|id| self.devices.iter().find(|device| device.id == id));
+
+        clip.device_name = device.map(|device| device.name.clone());
+        clip.device_type = device.map(|device| device.device_type.to_string());
+
+        clip
+    }
+
+    fn push_clip(
+        &mut self,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+    ) -> BDEResult<u64> {
+        self.last_clip_id += 1;
+        let id = self.last_clip_id;
+        self.clips.push(DatabaseClip {
+            id,
+            user_id,
+            device_id: Some(device_id),
+            clip_type: clip.clipboard_type,
+            data: if blob.is_some() {
+                String::new()
+            } else {
+                clip.data.clone()
+            },
+            date: clip.date as u64,
+            received_at: clip.received_at,
+            seq: clip.seq,
+            future_date: clip.future_date,
+            size,
+            blob,
+            pinned: false,
+            favorite: false,
+            sensitive: clip.sensitive,
+            targets: match &clip.targets {
+                Some(targets) => Some(serde_json::to_string(targets)?),
+                None => None,
+            },
+            group_id: clip.group_id,
+            channel: clip.channel.clone().unwrap_or(DEFAULT_CHANNEL.to_string()),
+            hash: Some(hash.to_string()),
+            copy_count: 1,
+            device_name: None,
+            device_type: None,
+        });
+
+        Ok(id)
+    }
+
+    fn duplicate_clip(
+        &self,
+        user_id: u64,
+        clip: &Clipboard,
+        hash: &str,
+        since: u64,
+    ) -> Option<u64> {
+        let channel = clip.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+
+        self.clips
+            .iter()
+            .filter(|item| match clip.group_id {
+                Some(group_id) => item.group_id == Some(group_id),
+                None => {
+                    item.user_id == user_id && item.group_id.is_none() && item.channel == channel
+                }
+            })
+            .filter(|item| {
+                item.hash.as_deref() == Some(hash)
+                    && item.received_at >= since
+                    && item.targets.is_none()
+            })
+            .max_by_key(|item| item.seq)
+            .map(|item| item.id)
+    }
+
+    fn member_role(&self, group_id: u64, user_id: u64) -> Option<GroupRole> {
+        self.group_members
+            .iter()
+            .find(|member| member.group_id == group_id && member.user_id == user_id)
+            .map(|member| member.role)
+    }
+
+    fn user_name(&self, user_id: u64) -> Option<String> {
+        self.users
+            .iter()
+            .find(|user| user.id == user_id)
+            .map(|user| user.name.clone())
+    }
+
+    // 保留历史记录, 只去掉剪切板上的设备
+    fn delete_device_rows(&mut self, device_id: u64) {
+        self.user_devices.retain(|(_, id)| *id != device_id);
+        self.device_filters.remove(&device_id);
+        self.device_channels.remove(&device_id);
+        for clip in self.clips.iter_mut() {
+            if clip.device_id == Some(device_id) {
+                clip.device_id = None;
+            }
+        }
+        self.devices.retain(|device| device.id != device_id);
+    }
+
+    // 和 sqlite 一样按序号从新到旧
+    fn clip_sizes<F: Fn(&DatabaseClip) -> bool>(&self, matches: F) -> Vec<DatabaseClipSize> {
+        let mut clips: Vec<&DatabaseClip> = self
+            .clips
+            .iter()
+            .filter(|clip| !clip.pinned && matches(clip))
+            .collect();
+        clips.sort_by_key(|clip| std::cmp::Reverse(clip.seq));
+
+        clips
+            .into_iter()
+            .map(|clip| DatabaseClipSize {
+                id: clip.id,
+                date: clip.received_at,
+                size: clip.size,
+                sensitive: clip.sensitive,
+            })
+            .collect()
+    }
+}
+
+/// 保存在内存里的存储, 进程退出后数据就没有了, 用于测试
+///
+/// 搜索只是不区分大小写的子串匹配, 片段就是剪切板的内容
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    data: Mutex<MemoryData>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn data(&self) -> BDEResult<MutexGuard<'_, MemoryData>> {
+        self.data
+            .lock()
+            .map_err(|_| ba_error("memory storage poisoned"))
+    }
+}
+
+fn clip_targets(clip: &DatabaseClip) -> Vec<u64> {
+    clip.targets
+        .as_deref()
+        .and_then(|targets| serde_json::from_str(targets).ok())
+        .unwrap_or_default()
+}
+
+// 名字模糊查找, 和 sqlite 的 LIKE 一样不区分 ASCII 大小写
+fn name_matches(name: &str, query: &str) -> bool {
+    name.to_ascii_lowercase()
+        .contains(query.to_ascii_lowercase().as_str())
+}
+
+fn matches_filter(clip: &DatabaseClip, user_id: u64, filter: &ClipFilter) -> bool {
+    let owner = match filter.group_id {
+        Some(group_id) => clip.group_id == Some(group_id),
+        None => clip.user_id == user_id && clip.group_id.is_none(),
+    };
+
+    owner
+        && filter.before.is_none_or(|before| clip.seq < before)
+        && filter
+            .clip_type
+            .is_none_or(|clip_type| clip.clip_type == clip_type)
+        && filter
+            .device_id
+            .is_none_or(|device_id| clip.device_id == Some(device_id))
+        && filter.from.is_none_or(|from| clip.received_at >= from)
+        && filter.to.is_none_or(|to| clip.received_at <= to)
+        && filter.pinned.is_none_or(|pinned| clip.pinned == pinned)
+        && filter
+            .favorite
+            .is_none_or(|favorite| clip.favorite == favorite)
+        && filter
+            .channels
+            .as_ref()
+            .is_none_or(|channels| channels.contains(&clip.channel))
+        && filter.recipient.is_none_or(|recipient| {
+            clip.targets.is_none()
+                || clip.device_id == Some(recipient)
+                || clip_targets(clip).contains(&recipient)
+        })
+        && (!filter.untargeted || clip.targets.is_none())
+}
+
+impl Storage for MemoryStorage {
+    fn register_device(
+        &self,
+        user_name: &str,
+        device_name: &str,
+        device_type: DeviceType,
+        notification: &str,
+    ) -> BDEResult<DatabaseUser> {
+        let mut data = self.data()?;
+
+        let device = data
+            .devices
+            .iter()
+            .find(|device| device.name == device_name && device.device_type == device_type)
+            .map(|device| device.id);
+        let owner_id = device.and_then(|device_id| {
+            data.user_devices
+                .iter()
+                .find(|(_, id)| *id == device_id)
+                .map(|(user_id, _)| *user_id)
+        });
+        let user = data
+            .users
+            .iter()
+            .find(|user| user.name == user_name)
+            .cloned();
+
+        if owner_id.is_some() && owner_id != user.as_ref().map(|user| user.id) {
+            return Err(conflict_error(
+                format!(
+                    "device ({}, {}) is registered to another user",
+                    device_name, device_type
+                )
+                .as_str(),
+            ));
+        }
+
+        let user = match user {
+            Some(user) => user,
+            None => {
+                data.last_user_id += 1;
+                let user = DatabaseUser {
+                    id: data.last_user_id,
+                    name: user_name.to_string(),
+                };
+                data.users.push(user.clone());
+                user
+            }
+        };
+
+        let device_id = match device {
+            Some(device_id) => device_id,
+            None => {
+                data.last_device_id += 1;
+                let device_id = data.last_device_id;
+                data.devices.push(Device {
+                    id: device_id,
+                    name: device_name.to_string(),
+                    notification: notification.to_string(),
+                    device_type,
+                });
+                device_id
+            }
+        };
+
+        if owner_id.is_none() {
+            data.user_devices.push((user.id, device_id));
+        }
+
+        Ok(user)
+    }
+
+    fn find_user(&self, name: &str) -> BDEResult<Option<DatabaseUser>> {
+        Ok(self
+            .data()?
+            .users
+            .iter()
+            .find(|user| user.name == name)
+            .cloned())
+    }
+
+    fn get_user(&self, id: u64) -> BDEResult<Option<DatabaseUser>> {
+        Ok(self
+            .data()?
+            .users
+            .iter()
+            .find(|user| user.id == id)
+            .cloned())
+    }
+
+    fn get_all_users(&self) -> BDEResult<Vec<DatabaseUser>> {
+        Ok(self.data()?.users.clone())
+    }
+
+    fn search_users(
+        &self,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseUserSummary>> {
+        let data = self.data()?;
+
+        Ok(data
+            .users
+            .iter()
+            .filter(|user| name_matches(&user.name, query))
+            .skip(offset)
+            .take(limit)
+            .map(|user| DatabaseUserSummary {
+                id: user.id,
+                name: user.name.clone(),
+                devices: data
+                    .user_devices
+                    .iter()
+                    .filter(|(user_id, _)| *user_id == user.id)
+                    .count() as u64,
+            })
+            .collect())
+    }
+
+    fn find_device(&self, name: &str, device_type: DeviceType) -> BDEResult<Option<Device>> {
+        Ok(self
+            .data()?
+            .devices
+            .iter()
+            .find(|device| device.name == name && device.device_type == device_type)
+            .cloned())
+    }
+
+    fn get_user_devices(&self, user_id: u64) -> BDEResult<Vec<Device>> {
+        let data = self.data()?;
+
+        Ok(data
+            .devices
+            .iter()
+            .filter(|device| data.user_devices.contains(&(user_id, device.id)))
+            .cloned()
+            .collect())
+    }
+
+    fn get_device_user(&self, device_id: u64) -> BDEResult<Option<DatabaseUser>> {
+        let data = self.data()?;
+
+        Ok(data
+            .user_devices
+            .iter()
+            .find(|(_, id)| *id == device_id)
+            .and_then(|(user_id, _)| data.users.iter().find(|user| user.id == *user_id))
+            .cloned())
+    }
+
+    fn delete_device(&self, user_id: u64, device_id: u64) -> BDEResult<bool> {
+        let mut data = self.data()?;
+
+        if !data.user_devices.contains(&(user_id, device_id)) {
+            return Ok(false);
+        }
+
+        data.delete_device_rows(device_id);
+
+        Ok(true)
+    }
+
+    fn delete_user_devices(&self, user_id: u64) -> BDEResult<Vec<u64>> {
+        let mut data = self.data()?;
+
+        let device_ids: Vec<u64> = data
+            .user_devices
+            .iter()
+            .filter(|(id, _)| *id == user_id)
+            .map(|(_, device_id)| *device_id)
+            .collect();
+
+        for device_id in device_ids.iter() {
+            data.delete_device_rows(*device_id);
+        }
+
+        Ok(device_ids)
+    }
+
+    fn search_devices(
+        &self,
+        query: &str,
+        user_id: Option<u64>,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseDeviceOwner>> {
+        let data = self.data()?;
+
+        Ok(data
+            .devices
+            .iter()
+            .filter(|device| name_matches(&device.name, query))
+            .map(|device| {
+                let owner = data
+                    .user_devices
+                    .iter()
+                    .find(|(_, id)| *id == device.id)
+                    .map(|(user_id, _)| *user_id);
+
+                DatabaseDeviceOwner {
+                    id: device.id,
+                    name: device.name.clone(),
+                    device_type: device.device_type,
+                    user_id: owner,
+                    user_name: owner.and_then(|owner| data.user_name(owner)),
+                }
+            })
+            .filter(|device| user_id.is_none() || device.user_id == user_id)
+            .skip(offset)
+            .take(limit)
+            .collect())
+    }
+
+    fn reserve_import_seqs(&self, count: u64) -> BDEResult<u64> {
+        let mut data = self.data()?;
+
+        if data.import_seqs + count >= IMPORT_SEQ_BASE {
+            return Err(ba_error("no import sequence left"));
+        }
+        data.import_seqs += count;
+
+        Ok(IMPORT_SEQ_BASE - data.import_seqs)
+    }
+
+    fn insert_clip(
+        &self,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+    ) -> BDEResult<u64> {
+        self.data()?
+            .push_clip(user_id, device_id, clip, size, blob, hash)
+    }
+
+    fn get_clip(&self, user_id: u64, id: u64) -> BDEResult<Option<DatabaseClip>> {
+        let data = self.data()?;
+
+        Ok(data
+            .clips
+            .iter()
+            .find(|clip| {
+                clip.id == id
+                    && (clip.user_id == user_id
+                        || clip
+                            .group_id
+                            .is_some_and(|group_id| data.member_role(group_id, user_id).is_some()))
+            })
+            .map(|clip| data.with_device(clip)))
+    }
+
+    fn find_clips(&self, user_id: u64, filter: &ClipFilter) -> BDEResult<Vec<DatabaseClip>> {
+        let data = self.data()?;
+
+        let mut clips: Vec<DatabaseClip> = data
+            .clips
+            .iter()
+            .filter(|clip| matches_filter(clip, user_id, filter))
+            .map(|clip| data.with_device(clip))
+            .collect();
+        clips.sort_by_key(|clip| std::cmp::Reverse(clip.seq));
+        clips.truncate(filter.limit);
+
+        Ok(clips)
+    }
+
+    fn find_duplicate_clip(
+        &self,
+        user_id: u64,
+        clip: &Clipboard,
+        hash: &str,
+        since: u64,
+    ) -> BDEResult<Option<u64>> {
+        Ok(self.data()?.duplicate_clip(user_id, clip, hash, since))
+    }
+
+    fn save_clip(
+        &self,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+        since: Option<u64>,
+    ) -> BDEResult<SavedClip> {
+        // 查找和保存时一直持有锁, 和 sqlite 的事务一样
+        let mut data = self.data()?;
+
+        let duplicate = since.and_then(|since| data.duplicate_clip(user_id, clip, hash, since));
+        data.clip_seq += 1;
+        let seq = IMPORT_SEQ_BASE + data.clip_seq;
+
+        match duplicate {
+            Some(id) => {
+                if let Some(item) = data.clips.iter_mut().find(|item| item.id == id) {
+                    item.date = item.date.max(clip.date as u64);
+                    item.received_at = clip.received_at;
+                    item.seq = seq;
+                    item.copy_count += 1;
+                }
+
+                Ok(SavedClip {
+                    id,
+                    seq,
+                    duplicate: true,
+                })
+            }
+            None => {
+                let mut clip = clip.clone();
+                clip.seq = seq;
+                let id = data.push_clip(user_id, device_id, &clip, size, blob, hash)?;
+
+                Ok(SavedClip {
+                    id,
+                    seq,
+                    duplicate: false,
+                })
+            }
+        }
+    }
+
+    fn update_clip_flags(
+        &self,
+        user_id: u64,
+        id: u64,
+        pinned: Option<bool>,
+        favorite: Option<bool>,
+    ) -> BDEResult<bool> {
+        let mut data = self.data()?;
+
+        let Some(clip) = data
+            .clips
+            .iter_mut()
+            .find(|clip| clip.user_id == user_id && clip.id == id)
+        else {
+            return Ok(false);
+        };
+
+        clip.pinned = pinned.unwrap_or(clip.pinned);
+        clip.favorite = favorite.unwrap_or(clip.favorite);
+
+        Ok(true)
+    }
+
+    fn delete_user_clip(&self, user_id: u64, id: u64) -> BDEResult<bool> {
+        let mut data = self.data()?;
+
+        let len = data.clips.len();
+        data.clips
+            .retain(|clip| !(clip.user_id == user_id && clip.id == id));
+
+        Ok(data.clips.len() < len)
+    }
+
+    fn delete_user_clips(&self, user_id: u64, keep_pinned: bool) -> BDEResult<usize> {
+        let mut data = self.data()?;
+
+        let len = data.clips.len();
+        data.clips.retain(|clip| {
+            !(clip.user_id == user_id && clip.group_id.is_none() && !(keep_pinned && clip.pinned))
+        });
+
+        Ok(len - data.clips.len())
+    }
+
+    fn delete_clips(&self, ids: &[u64]) -> BDEResult<()> {
+        self.data()?.clips.retain(|clip| !ids.contains(&clip.id));
+
+        Ok(())
+    }
+
+    fn search_clips(
+        &self,
+        user_id: u64,
+        terms: &[SearchTerm],
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<(DatabaseClip, String)>> {
+        let data = self.data()?;
+
+        let mut clips: Vec<&DatabaseClip> = data
+            .clips
+            .iter()
+            .filter(|clip| {
+                clip.user_id == user_id
+                    && clip.group_id.is_none()
+                    && clip.clip_type == ClipboardDataType::Text
+                    && !clip.sensitive
+            })
+            .filter(|clip| {
+                let text = clip.data.to_lowercase();
+                terms
+                    .iter()
+                    .all(|term| text.contains(term.text.to_lowercase().as_str()))
+            })
+            .collect();
+        clips.sort_by_key(|clip| std::cmp::Reverse(clip.seq));
+
+        Ok(clips
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|clip| (data.with_device(clip), clip.data.clone()))
+            .collect())
+    }
+
+    fn get_unpinned_clip_sizes(&self, user_id: u64) -> BDEResult<Vec<DatabaseClipSize>> {
+        Ok(self
+            .data()?
+            .clip_sizes(|clip| clip.user_id == user_id && clip.group_id.is_none()))
+    }
+
+    fn get_unpinned_group_clip_sizes(&self, group_id: u64) -> BDEResult<Vec<DatabaseClipSize>> {
+        Ok(self
+            .data()?
+            .clip_sizes(|clip| clip.group_id == Some(group_id)))
+    }
+
+    fn get_blob_names(&self) -> BDEResult<HashSet<String>> {
+        Ok(self
+            .data()?
+            .clips
+            .iter()
+            .filter_map(|clip| clip.blob.clone())
+            .collect())
+    }
+
+    fn get_user_storage(&self) -> BDEResult<Vec<DatabaseUserStorage>> {
+        let data = self.data()?;
+
+        let mut all_data: Vec<DatabaseUserStorage> = data
+            .users
+            .iter()
+            .map(|user| {
+                let clips: Vec<&DatabaseClip> = data
+                    .clips
+                    .iter()
+                    .filter(|clip| clip.user_id == user.id)
+                    .collect();
+
+                DatabaseUserStorage {
+                    user_id: user.id,
+                    name: user.name.clone(),
+                    clips: clips.len() as u64,
+                    bytes: clips.iter().map(|clip| clip.size).sum(),
+                    blob_bytes: clips
+                        .iter()
+                        .filter(|clip| clip.blob.is_some())
+                        .map(|clip| clip.size)
+                        .sum(),
+                }
+            })
+            .collect();
+        all_data.sort_by_key(|user| (std::cmp::Reverse(user.bytes), user.user_id));
+
+        Ok(all_data)
+    }
+
+    fn get_user_channels(&self, user_id: u64) -> BDEResult<Vec<String>> {
+        Ok(self
+            .data()?
+            .channels
+            .iter()
+            .filter(|(id, _)| *id == user_id)
+            .map(|(_, name)| name.clone())
+            .collect())
+    }
+
+    fn insert_channel(&self, user_id: u64, name: &str) -> BDEResult<()> {
+        self.data()?.channels.push((user_id, name.to_string()));
+
+        Ok(())
+    }
+
+    fn delete_channel(&self, user_id: u64, name: &str) -> BDEResult<bool> {
+        let mut data = self.data()?;
+
+        let len = data.channels.len();
+        data.channels
+            .retain(|(id, channel)| !(*id == user_id && channel == name));
+
+        if data.channels.len() == len {
+            return Ok(false);
+        }
+
+        data.clips.retain(|clip| {
+            !(clip.user_id == user_id && clip.group_id.is_none() && clip.channel == name)
+        });
+
+        let device_ids: Vec<u64> = data
+            .user_devices
+            .iter()
+            .filter(|(id, _)| *id == user_id)
+            .map(|(_, device_id)| *device_id)
+            .collect();
+        for device_id in device_ids {
+            if let Some(channels) = data.device_channels.get_mut(&device_id) {
+                channels.retain(|channel| channel != name);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn get_device_channels(&self, device_id: u64) -> BDEResult<Vec<String>> {
+        Ok(self
+            .data()?
+            .device_channels
+            .get(&device_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn set_device_channels(&self, device_id: u64, channels: &[String]) -> BDEResult<()> {
+        self.data()?
+            .device_channels
+            .insert(device_id, channels.to_vec());
+
+        Ok(())
+    }
+
+    fn find_group(&self, name: &str) -> BDEResult<Option<DatabaseGroup>> {
+        Ok(self
+            .data()?
+            .groups
+            .iter()
+            .find(|group| group.name == name)
+            .cloned())
+    }
+
+    fn get_all_groups(&self) -> BDEResult<Vec<DatabaseGroup>> {
+        Ok(self.data()?.groups.clone())
+    }
+
+    fn create_group(&self, name: &str, owner_id: u64) -> BDEResult<u64> {
+        let mut data = self.data()?;
+
+        if data.groups.iter().any(|group| group.name == name) {
+            return Err(conflict_error("group already exists"));
+        }
+
+        data.last_group_id += 1;
+        let id = data.last_group_id;
+        data.groups.push(DatabaseGroup {
+            id,
+            name: name.to_string(),
+            owner_id,
+        });
+        data.group_members.push(DatabaseGroupMember {
+            group_id: id,
+            user_id: owner_id,
+            role: GroupRole::Owner,
+        });
+
+        Ok(id)
+    }
+
+    fn get_member_role(&self, group_id: u64, user_id: u64) -> BDEResult<Option<GroupRole>> {
+        Ok(self.data()?.member_role(group_id, user_id))
+    }
+
+    fn set_member(&self, member: &DatabaseGroupMember) -> BDEResult<()> {
+        let mut data = self.data()?;
+
+        data.group_members
+            .retain(|item| !(item.group_id == member.group_id && item.user_id == member.user_id));
+        data.group_members.push(member.clone());
+
+        Ok(())
+    }
+
+    fn delete_member(&self, group_id: u64, user_id: u64) -> BDEResult<bool> {
+        let mut data = self.data()?;
+
+        let len = data.group_members.len();
+        data.group_members
+            .retain(|member| !(member.group_id == group_id && member.user_id == user_id));
+
+        Ok(data.group_members.len() < len)
+    }
+
+    fn get_group_members(&self, group_id: u64) -> BDEResult<Vec<DatabaseGroupMemberName>> {
+        let data = self.data()?;
+
+        let mut members: Vec<DatabaseGroupMemberName> = data
+            .group_members
+            .iter()
+            .filter(|member| member.group_id == group_id)
+            .filter_map(|member| {
+                Some(DatabaseGroupMemberName {
+                    user_id: member.user_id,
+                    name: data.user_name(member.user_id)?,
+                    role: member.role,
+                })
+            })
+            .collect();
+        members.sort_by_key(|member| member.user_id);
+
+        Ok(members)
+    }
+
+    fn get_user_groups(&self, user_id: u64) -> BDEResult<Vec<DatabaseUserGroup>> {
+        let data = self.data()?;
+
+        Ok(data
+            .groups
+            .iter()
+            .filter_map(|group| {
+                Some(DatabaseUserGroup {
+                    id: group.id,
+                    name: group.name.clone(),
+                    role: data.member_role(group.id, user_id)?,
+                })
+            })
+            .collect())
+    }
+
+    fn get_group_devices(&self, group_id: u64) -> BDEResult<Vec<Device>> {
+        let data = self.data()?;
+
+        Ok(data
+            .devices
+            .iter()
+            .filter(|device| {
+                data.user_devices.iter().any(|(user_id, device_id)| {
+                    *device_id == device.id && data.member_role(group_id, *user_id).is_some()
+                })
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn get_device_filter(&self, device_id: u64) -> BDEResult<Option<DatabaseDeviceFilter>> {
+        Ok(self.data()?.device_filters.get(&device_id).cloned())
+    }
+
+    fn set_device_filter(&self, filter: &DatabaseDeviceFilter) -> BDEResult<()> {
+        self.data()?
+            .device_filters
+            .insert(filter.device_id, filter.clone());
+
+        Ok(())
+    }
+
+    fn get_retention_policy(&self, user_id: u64) -> BDEResult<Option<DatabaseRetentionPolicy>> {
+        Ok(self.data()?.retention_policies.get(&user_id).cloned())
+    }
+
+    fn set_retention_policy(&self, policy: &DatabaseRetentionPolicy) -> BDEResult<()> {
+        self.data()?
+            .retention_policies
+            .insert(policy.user_id, policy.clone());
+
+        Ok(())
+    }
+
+    fn ping(&self) -> BDEResult<()> {
+        // 锁没有被污染就可以使用
+        let _data = self.data()?;
+
+        Ok(())
+    }
+}
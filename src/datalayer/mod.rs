@@ -1,17 +1,35 @@
-use rustsqlite_derive::ToSqlMacro;
+use connect_any_protocol::DeviceIdentity;
+use rustsqlite_derive::{FromSqlMacro, ToSqlMacro};
 use serde::{Deserialize, Serialize};
 use strum_macros::Display;
 use strum_macros::EnumString;
 
 use crate::utils::ba_error;
 use crate::utils::BDEResult;
+use storage::Storage;
 
+pub mod blob;
+pub mod channel;
 pub mod clipboard;
+pub mod filter;
+pub mod group;
 
-mod database;
+pub mod database;
+pub mod memory;
+pub mod storage;
 
 #[derive(
-    Deserialize, Serialize, EnumString, Display, ToSqlMacro, Clone, Copy, Debug, PartialEq, Eq,
+    Deserialize,
+    Serialize,
+    EnumString,
+    Display,
+    ToSqlMacro,
+    FromSqlMacro,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
 )]
 pub enum DeviceType {
     Ios,
@@ -19,6 +37,8 @@ pub enum DeviceType {
     Windows,
     Mac,
     Linux,
+    // 内置的网页版
+    Web,
 }
 
 pub type Device = database::DatabaseDevice;
@@ -30,11 +50,24 @@ pub struct InputDevice {
     device_type: String,
 }
 
+impl From<DeviceIdentity> for InputDevice {
+    fn from(device: DeviceIdentity) -> Self {
+        InputDevice {
+            name: device.name,
+            device_type: device.device_type,
+        }
+    }
+}
+
 impl InputDevice {
-    pub fn parse(self) -> BDEResult<Device> {
+    pub fn parse(self, storage: &dyn Storage) -> BDEResult<Device> {
         let device_type = self.device_type.parse()?;
 
-        Device::get_device(self.name, device_type)
+        storage
+            .find_device(&self.name, device_type)?
+            .ok_or_else(|| {
+                ba_error(format!("no ({}, {}) device found", self.name, device_type).as_str())
+            })
     }
 }
 
@@ -46,34 +79,29 @@ pub struct User {
 }
 
 impl User {
-    pub fn build(name: String) -> BDEResult<Self> {
-        // 尝试从数据库中查找用户
-        if let Some(user) = database::DatabaseUser::find_user(name.clone())? {
-            // 如果找到用户，则获取其设备信息
-            let devices = database::DatabaseUserDevice::get_user_devices(user.id)?;
-
-            // 返回用户及其设备信息
-            return Ok(Self {
-                id: user.id,
-                name: user.name,
-                devices,
-            });
-        }
+    /// 注册设备, 用户不存在时创建, 设备已经注册过时直接返回用户
+    pub fn register(
+        storage: &dyn Storage,
+        name: &str,
+        device_name: &str,
+        device_type: DeviceType,
+        notification: &str,
+    ) -> BDEResult<Self> {
+        let user = storage.register_device(name, device_name, device_type, notification)?;
 
-        // 如果用户不存在，则将其插入数据库
-        let id = database::DatabaseUser::insert_user(name.clone())?;
+        let devices = storage.get_user_devices(user.id)?;
 
-        Ok(User {
-            id,
-            name,
-            devices: vec![],
+        Ok(Self {
+            id: user.id,
+            name: user.name,
+            devices,
         })
     }
 
-    pub fn find_user(name: String) -> BDEResult<Option<Self>> {
-        if let Some(user) = database::DatabaseUser::find_user(name.clone())? {
+    pub fn find_user(storage: &dyn Storage, name: String) -> BDEResult<Option<Self>> {
+        if let Some(user) = storage.find_user(&name)? {
             // 如果找到用户，则获取其设备信息
-            let devices = database::DatabaseUserDevice::get_user_devices(user.id)?;
+            let devices = storage.get_user_devices(user.id)?;
 
             // 返回用户及其设备信息
             Ok(Some(Self {
@@ -86,10 +114,10 @@ impl User {
         }
     }
 
-    pub fn find_user_from_device(device: &Device) -> BDEResult<Self> {
-        if let Some(user) = database::DatabaseUserDevice::get_device_users(device.id)? {
+    pub fn find_user_from_device(storage: &dyn Storage, device: &Device) -> BDEResult<Self> {
+        if let Some(user) = storage.get_device_user(device.id)? {
             // 如果找到用户，则获取其设备信息
-            let devices = database::DatabaseUserDevice::get_user_devices(user.id)?;
+            let devices = storage.get_user_devices(user.id)?;
 
             // 返回用户及其设备信息
             Ok(Self {
@@ -101,21 +129,4 @@ impl User {
             Err(ba_error("device user not found"))
         }
     }
-
-    pub fn add_device(
-        &mut self,
-        name: String,
-        device_type: DeviceType,
-        notification: String,
-    ) -> BDEResult<()> {
-        if database::DatabaseDevice::find_device(name.clone(), device_type)?.is_none() {
-            let device = database::DatabaseDevice::insert_device(name, device_type, notification)?;
-
-            database::DatabaseUserDevice::insert_user_device(self.id, device.id)?;
-
-            self.devices.push(device);
-        }
-
-        Ok(())
-    }
 }
@@ -0,0 +1,470 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use super::clipboard::{Clipboard, SearchTerm};
+use super::database::{
+    ClipFilter, DatabaseChannel, DatabaseClip, DatabaseClipSize, DatabaseDevice,
+    DatabaseDeviceChannel, DatabaseDeviceFilter, DatabaseDeviceOwner, DatabaseGroup,
+    DatabaseGroupMember, DatabaseGroupMemberName, DatabaseRetentionPolicy, DatabaseUser,
+    DatabaseUserDevice, DatabaseUserGroup, DatabaseUserStorage, DatabaseUserSummary, SavedClip,
+};
+use super::group::GroupRole;
+use super::{Device, DeviceType};
+use crate::utils::database::Database;
+use crate::utils::BDEResult;
+
+/// 服务器所有数据的存储: 用户, 设备, 剪切板, 频道, 群组, 接收规则和保留规则
+///
+/// 默认保存在 sqlite 里, 测试时可以换成 `MemoryStorage`
+pub trait Storage: Send + Sync + Debug {
+    /// 注册用户和设备, 用户不存在时创建, 设备已经属于其他用户时返回冲突, 不会只写入一半
+    fn register_device(
+        &self,
+        user_name: &str,
+        device_name: &str,
+        device_type: DeviceType,
+        notification: &str,
+    ) -> BDEResult<DatabaseUser>;
+
+    fn find_user(&self, name: &str) -> BDEResult<Option<DatabaseUser>>;
+
+    fn get_user(&self, id: u64) -> BDEResult<Option<DatabaseUser>>;
+
+    fn get_all_users(&self) -> BDEResult<Vec<DatabaseUser>>;
+
+    /// 按名字模糊查找用户, 带上设备数量
+    fn search_users(
+        &self,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseUserSummary>>;
+
+    fn find_device(&self, name: &str, device_type: DeviceType) -> BDEResult<Option<Device>>;
+
+    fn get_user_devices(&self, user_id: u64) -> BDEResult<Vec<Device>>;
+
+    /// 设备所属的用户
+    fn get_device_user(&self, device_id: u64) -> BDEResult<Option<DatabaseUser>>;
+
+    /// 删除用户的一个设备, 保留设备上传的剪切板, 返回设备是否存在
+    fn delete_device(&self, user_id: u64, device_id: u64) -> BDEResult<bool>;
+
+    /// 删除用户所有的设备和设备的设置, 返回删除的设备 id
+    fn delete_user_devices(&self, user_id: u64) -> BDEResult<Vec<u64>>;
+
+    /// 按名字模糊查找设备, `user_id` 不为空时只查找这个用户的设备
+    fn search_devices(
+        &self,
+        query: &str,
+        user_id: Option<u64>,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseDeviceOwner>>;
+
+    /// 为导入的历史记录预留 `count` 个比所有剪切板都小的连续序号, 返回第一个
+    fn reserve_import_seqs(&self, count: u64) -> BDEResult<u64>;
+
+    /// 使用 `clip.seq` 插入剪切板, 只用于导入预留了序号的历史记录
+    fn insert_clip(
+        &self,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+    ) -> BDEResult<u64>;
+
+    /// 用户自己的剪切板, 或者用户所在群组的剪切板
+    fn get_clip(&self, user_id: u64, id: u64) -> BDEResult<Option<DatabaseClip>>;
+
+    /// 按序号从新到旧查找用户或者群组的剪切板
+    fn find_clips(&self, user_id: u64, filter: &ClipFilter) -> BDEResult<Vec<DatabaseClip>>;
+
+    /// 在同一个用户频道或者群组里找服务器在 `since` 之后收到的内容相同的最新的剪切板
+    fn find_duplicate_clip(
+        &self,
+        user_id: u64,
+        clip: &Clipboard,
+        hash: &str,
+        since: u64,
+    ) -> BDEResult<Option<u64>>;
+
+    /// 分配序号并保存剪切板, `since` 不为空时先在同一个事务里查找 `find_duplicate_clip`,
+    /// 找到时只更新原来的剪切板的时间, 序号和复制次数
+    #[allow(clippy::too_many_arguments)]
+    fn save_clip(
+        &self,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+        since: Option<u64>,
+    ) -> BDEResult<SavedClip>;
+
+    /// 修改剪切板的固定/收藏状态, 为空的项保持不变, 返回剪切板是否存在
+    fn update_clip_flags(
+        &self,
+        user_id: u64,
+        id: u64,
+        pinned: Option<bool>,
+        favorite: Option<bool>,
+    ) -> BDEResult<bool>;
+
+    /// 删除用户的一条剪切板, 返回剪切板是否存在
+    fn delete_user_clip(&self, user_id: u64, id: u64) -> BDEResult<bool>;
+
+    /// 删除用户所有的个人剪切板, `keep_pinned` 时保留固定的剪切板, 返回删除的数量
+    fn delete_user_clips(&self, user_id: u64, keep_pinned: bool) -> BDEResult<usize>;
+
+    fn delete_clips(&self, ids: &[u64]) -> BDEResult<()>;
+
+    /// 在用户的个人文字剪切板中搜索, 敏感内容不会被搜到, 返回剪切板和匹配的片段
+    fn search_clips(
+        &self,
+        user_id: u64,
+        terms: &[SearchTerm],
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<(DatabaseClip, String)>>;
+
+    /// 用户所有没有固定的个人剪切板, 按序号从新到旧
+    fn get_unpinned_clip_sizes(&self, user_id: u64) -> BDEResult<Vec<DatabaseClipSize>>;
+
+    /// 群组所有没有固定的剪切板, 按序号从新到旧
+    fn get_unpinned_group_clip_sizes(&self, group_id: u64) -> BDEResult<Vec<DatabaseClipSize>>;
+
+    /// 剪切板引用的所有 blob
+    fn get_blob_names(&self) -> BDEResult<HashSet<String>>;
+
+    /// 每个用户的剪切板数量和字节数, 按字节数从大到小
+    fn get_user_storage(&self) -> BDEResult<Vec<DatabaseUserStorage>>;
+
+    /// 用户自己创建的频道, 不包括默认频道
+    fn get_user_channels(&self, user_id: u64) -> BDEResult<Vec<String>>;
+
+    fn insert_channel(&self, user_id: u64, name: &str) -> BDEResult<()>;
+
+    /// 删除频道, 频道里的剪切板和设备的订阅, 返回频道是否存在
+    fn delete_channel(&self, user_id: u64, name: &str) -> BDEResult<bool>;
+
+    /// 设备订阅的频道, 没有设置时为空
+    fn get_device_channels(&self, device_id: u64) -> BDEResult<Vec<String>>;
+
+    /// 替换设备订阅的所有频道
+    fn set_device_channels(&self, device_id: u64, channels: &[String]) -> BDEResult<()>;
+
+    fn find_group(&self, name: &str) -> BDEResult<Option<DatabaseGroup>>;
+
+    fn get_all_groups(&self) -> BDEResult<Vec<DatabaseGroup>>;
+
+    /// 创建群组, 创建者成为群组的 owner, 返回群组 id
+    fn create_group(&self, name: &str, owner_id: u64) -> BDEResult<u64>;
+
+    /// 用户在群组里的角色, 不是成员时为空
+    fn get_member_role(&self, group_id: u64, user_id: u64) -> BDEResult<Option<GroupRole>>;
+
+    /// 添加成员, 已经是成员时修改角色
+    fn set_member(&self, member: &DatabaseGroupMember) -> BDEResult<()>;
+
+    /// 删除成员, 返回成员是否存在
+    fn delete_member(&self, group_id: u64, user_id: u64) -> BDEResult<bool>;
+
+    fn get_group_members(&self, group_id: u64) -> BDEResult<Vec<DatabaseGroupMemberName>>;
+
+    /// 用户加入的群组和在群组里的角色
+    fn get_user_groups(&self, user_id: u64) -> BDEResult<Vec<DatabaseUserGroup>>;
+
+    /// 群组所有成员的所有设备
+    fn get_group_devices(&self, group_id: u64) -> BDEResult<Vec<Device>>;
+
+    fn get_device_filter(&self, device_id: u64) -> BDEResult<Option<DatabaseDeviceFilter>>;
+
+    fn set_device_filter(&self, filter: &DatabaseDeviceFilter) -> BDEResult<()>;
+
+    fn get_retention_policy(&self, user_id: u64) -> BDEResult<Option<DatabaseRetentionPolicy>>;
+
+    fn set_retention_policy(&self, policy: &DatabaseRetentionPolicy) -> BDEResult<()>;
+
+    /// 检查存储是否可以读取, 用于健康检查
+    fn ping(&self) -> BDEResult<()>;
+}
+
+/// 保存在 sqlite 里, 所有操作共用启动时创建的连接池
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    db: Database,
+}
+
+impl SqliteStorage {
+    pub fn new(db: Database) -> Self {
+        SqliteStorage { db }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn register_device(
+        &self,
+        user_name: &str,
+        device_name: &str,
+        device_type: DeviceType,
+        notification: &str,
+    ) -> BDEResult<DatabaseUser> {
+        DatabaseUserDevice::register_device(
+            &mut *self.db.get()?,
+            user_name,
+            device_name,
+            device_type,
+            notification,
+        )
+    }
+
+    fn find_user(&self, name: &str) -> BDEResult<Option<DatabaseUser>> {
+        DatabaseUser::find_user(&*self.db.get()?, name.to_string())
+    }
+
+    fn get_user(&self, id: u64) -> BDEResult<Option<DatabaseUser>> {
+        DatabaseUser::get_user(&*self.db.get()?, id)
+    }
+
+    fn get_all_users(&self) -> BDEResult<Vec<DatabaseUser>> {
+        DatabaseUser::get_all_users(&*self.db.get()?)
+    }
+
+    fn search_users(
+        &self,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseUserSummary>> {
+        DatabaseUser::search_users(&*self.db.get()?, query, limit, offset)
+    }
+
+    fn find_device(&self, name: &str, device_type: DeviceType) -> BDEResult<Option<Device>> {
+        DatabaseDevice::find_device(&*self.db.get()?, name.to_string(), device_type)
+    }
+
+    fn get_user_devices(&self, user_id: u64) -> BDEResult<Vec<Device>> {
+        DatabaseUserDevice::get_user_devices(&*self.db.get()?, user_id)
+    }
+
+    fn get_device_user(&self, device_id: u64) -> BDEResult<Option<DatabaseUser>> {
+        DatabaseUserDevice::get_device_users(&*self.db.get()?, device_id)
+    }
+
+    fn delete_device(&self, user_id: u64, device_id: u64) -> BDEResult<bool> {
+        DatabaseUserDevice::delete_device(&mut *self.db.get()?, user_id, device_id)
+    }
+
+    fn delete_user_devices(&self, user_id: u64) -> BDEResult<Vec<u64>> {
+        DatabaseUserDevice::delete_user_devices(&mut *self.db.get()?, user_id)
+    }
+
+    fn search_devices(
+        &self,
+        query: &str,
+        user_id: Option<u64>,
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<DatabaseDeviceOwner>> {
+        DatabaseDevice::search_devices(&*self.db.get()?, query, user_id, limit, offset)
+    }
+
+    fn reserve_import_seqs(&self, count: u64) -> BDEResult<u64> {
+        DatabaseClip::reserve_import_seqs(&*self.db.get()?, count)
+    }
+
+    fn insert_clip(
+        &self,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+    ) -> BDEResult<u64> {
+        DatabaseClip::insert_clip(&*self.db.get()?, user_id, device_id, clip, size, blob, hash)
+    }
+
+    fn get_clip(&self, user_id: u64, id: u64) -> BDEResult<Option<DatabaseClip>> {
+        DatabaseClip::get_clip(&*self.db.get()?, user_id, id)
+    }
+
+    fn find_clips(&self, user_id: u64, filter: &ClipFilter) -> BDEResult<Vec<DatabaseClip>> {
+        DatabaseClip::find_clips(&*self.db.get()?, user_id, filter)
+    }
+
+    fn find_duplicate_clip(
+        &self,
+        user_id: u64,
+        clip: &Clipboard,
+        hash: &str,
+        since: u64,
+    ) -> BDEResult<Option<u64>> {
+        DatabaseClip::find_duplicate_clip(&*self.db.get()?, user_id, clip, hash, since)
+    }
+
+    fn save_clip(
+        &self,
+        user_id: u64,
+        device_id: u64,
+        clip: &Clipboard,
+        size: u64,
+        blob: Option<String>,
+        hash: &str,
+        since: Option<u64>,
+    ) -> BDEResult<SavedClip> {
+        DatabaseClip::save_clip(
+            &mut *self.db.get()?,
+            user_id,
+            device_id,
+            clip,
+            size,
+            blob,
+            hash,
+            since,
+        )
+    }
+
+    fn update_clip_flags(
+        &self,
+        user_id: u64,
+        id: u64,
+        pinned: Option<bool>,
+        favorite: Option<bool>,
+    ) -> BDEResult<bool> {
+        DatabaseClip::update_clip_flags(&*self.db.get()?, user_id, id, pinned, favorite)
+    }
+
+    fn delete_user_clip(&self, user_id: u64, id: u64) -> BDEResult<bool> {
+        DatabaseClip::delete_user_clip(&*self.db.get()?, user_id, id)
+    }
+
+    fn delete_user_clips(&self, user_id: u64, keep_pinned: bool) -> BDEResult<usize> {
+        DatabaseClip::delete_user_clips(&*self.db.get()?, user_id, keep_pinned)
+    }
+
+    fn delete_clips(&self, ids: &[u64]) -> BDEResult<()> {
+        DatabaseClip::delete_clips(&*self.db.get()?, ids)
+    }
+
+    fn search_clips(
+        &self,
+        user_id: u64,
+        terms: &[SearchTerm],
+        limit: usize,
+        offset: usize,
+    ) -> BDEResult<Vec<(DatabaseClip, String)>> {
+        DatabaseClip::search_clips(&*self.db.get()?, user_id, terms, limit, offset)
+    }
+
+    fn get_unpinned_clip_sizes(&self, user_id: u64) -> BDEResult<Vec<DatabaseClipSize>> {
+        DatabaseClip::get_unpinned_clip_sizes(&*self.db.get()?, user_id)
+    }
+
+    fn get_unpinned_group_clip_sizes(&self, group_id: u64) -> BDEResult<Vec<DatabaseClipSize>> {
+        DatabaseClip::get_unpinned_group_clip_sizes(&*self.db.get()?, group_id)
+    }
+
+    fn get_blob_names(&self) -> BDEResult<HashSet<String>> {
+        DatabaseClip::get_blob_names(&*self.db.get()?)
+    }
+
+    fn get_user_storage(&self) -> BDEResult<Vec<DatabaseUserStorage>> {
+        DatabaseClip::get_user_storage(&*self.db.get()?)
+    }
+
+    fn get_user_channels(&self, user_id: u64) -> BDEResult<Vec<String>> {
+        Ok(
+            DatabaseChannel::get_user_channels(&*self.db.get()?, user_id)?
+                .into_iter()
+                .map(|channel| channel.name)
+                .collect(),
+        )
+    }
+
+    fn insert_channel(&self, user_id: u64, name: &str) -> BDEResult<()> {
+        DatabaseChannel::insert_channel(&*self.db.get()?, user_id, name.to_string())?;
+
+        Ok(())
+    }
+
+    fn delete_channel(&self, user_id: u64, name: &str) -> BDEResult<bool> {
+        DatabaseChannel::delete_channel(&mut *self.db.get()?, user_id, name)
+    }
+
+    fn get_device_channels(&self, device_id: u64) -> BDEResult<Vec<String>> {
+        DatabaseDeviceChannel::get_device_channels(&*self.db.get()?, device_id)
+    }
+
+    fn set_device_channels(&self, device_id: u64, channels: &[String]) -> BDEResult<()> {
+        DatabaseDeviceChannel::set_device_channels(&mut *self.db.get()?, device_id, channels)
+    }
+
+    fn find_group(&self, name: &str) -> BDEResult<Option<DatabaseGroup>> {
+        DatabaseGroup::find_group(&*self.db.get()?, name)
+    }
+
+    fn get_all_groups(&self) -> BDEResult<Vec<DatabaseGroup>> {
+        DatabaseGroup::get_all_groups(&*self.db.get()?)
+    }
+
+    fn create_group(&self, name: &str, owner_id: u64) -> BDEResult<u64> {
+        DatabaseGroup::create_group(&mut *self.db.get()?, name, owner_id)
+    }
+
+    fn get_member_role(&self, group_id: u64, user_id: u64) -> BDEResult<Option<GroupRole>> {
+        Ok(
+            DatabaseGroupMember::get_member(&*self.db.get()?, group_id, user_id)?
+                .map(|member| member.role),
+        )
+    }
+
+    fn set_member(&self, member: &DatabaseGroupMember) -> BDEResult<()> {
+        member.set_member(&*self.db.get()?)
+    }
+
+    fn delete_member(&self, group_id: u64, user_id: u64) -> BDEResult<bool> {
+        DatabaseGroupMember::delete_member(&*self.db.get()?, group_id, user_id)
+    }
+
+    fn get_group_members(&self, group_id: u64) -> BDEResult<Vec<DatabaseGroupMemberName>> {
+        DatabaseGroupMember::get_group_members(&*self.db.get()?, group_id)
+    }
+
+    fn get_user_groups(&self, user_id: u64) -> BDEResult<Vec<DatabaseUserGroup>> {
+        DatabaseGroupMember::get_user_groups(&*self.db.get()?, user_id)
+    }
+
+    fn get_group_devices(&self, group_id: u64) -> BDEResult<Vec<Device>> {
+        DatabaseGroupMember::get_group_devices(&*self.db.get()?, group_id)
+    }
+
+    fn get_device_filter(&self, device_id: u64) -> BDEResult<Option<DatabaseDeviceFilter>> {
+        DatabaseDeviceFilter::get_filter(&*self.db.get()?, device_id)
+    }
+
+    fn set_device_filter(&self, filter: &DatabaseDeviceFilter) -> BDEResult<()> {
+        filter.set_filter(&*self.db.get()?)
+    }
+
+    fn get_retention_policy(&self, user_id: u64) -> BDEResult<Option<DatabaseRetentionPolicy>> {
+        DatabaseRetentionPolicy::get_policy(&*self.db.get()?, user_id)
+    }
+
+    fn set_retention_policy(&self, policy: &DatabaseRetentionPolicy) -> BDEResult<()> {
+        policy.set_policy(&*self.db.get()?)
+    }
+
+    fn ping(&self) -> BDEResult<()> {
+        let conn = self.db.get()?;
+
+        // 查一张表, 同时确认已经建表
+        conn.query_row("SELECT count(*) FROM users", [], |row| row.get::<_, u64>(0))?;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,40 @@
+pub mod admin;
 pub mod api;
-mod bark;
-mod datalayer;
-mod state;
-mod utils;
+pub mod backup;
+pub mod bark;
+pub mod config;
+pub mod datalayer;
+pub mod limits;
+pub mod metrics;
+pub mod retention;
+pub mod sensitive;
+pub mod shutdown;
+pub mod state;
+pub mod tls;
+pub mod transfer;
+pub mod utils;
+pub mod web;
 pub mod websocket;
 
+use std::sync::Arc;
+
+use backup::Backup;
+use config::Config;
+use datalayer::storage::{SqliteStorage, Storage};
 use state::AppState;
+use utils::database::{database_path, Database};
 
 pub async fn init() -> AppState {
-    AppState::new()
+    let config = Config::load().unwrap();
+
+    let database = Database::open(&database_path()).unwrap();
+
+    let storage: Arc<dyn Storage> = Arc::new(SqliteStorage::new(database.clone()));
+    let backup: Arc<dyn Backup> = Arc::new(database);
+
+    retention::spawn_retention_task(storage.clone(), config.retention.clone());
+
+    backup::spawn_backup_task(backup.clone(), config.backup.clone());
+
+    AppState::new(config, storage).with_backup(backup)
 }
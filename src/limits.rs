@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use connect_any_protocol::ClipboardDataType;
+
+use crate::config::{LimitsConfig, RateLimitConfig};
+use crate::utils::{too_large_error, BDEResult};
+
+// 桶的数量超过这个值时, 清理已经攒满的桶, 攒满的桶和新建的桶没有区别
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// 按 `K` 分开计数的令牌桶
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 取一个令牌, 没有令牌时返回需要等待的时间
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn check_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let RateLimitConfig { per_sec, burst } = self.config;
+        if per_sec <= 0.0 {
+            return Ok(());
+        }
+        let burst = f64::from(burst.max(1));
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec < burst
+            });
+        }
+
+        let bucket = buckets.entry(key).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// 服务器上所有的限流器
+#[derive(Debug)]
+pub struct Limiter {
+    // 设备名和类型, 设备可能还没有注册
+    pub device: RateLimiter<(String, String)>,
+    pub ip: RateLimiter<IpAddr>,
+}
+
+impl Limiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Limiter {
+            device: RateLimiter::new(config.device_rate.clone()),
+            ip: RateLimiter::new(config.ip_rate.clone()),
+        }
+    }
+}
+
+/// 检查剪切板内容是否超过这种类型的上限
+pub fn check_clip_size(
+    config: &LimitsConfig,
+    clipboard_type: ClipboardDataType,
+    size: usize,
+) -> BDEResult<()> {
+    let limit = match clipboard_type {
+        ClipboardDataType::Text => config.max_text_bytes,
+        ClipboardDataType::Image => config.max_image_bytes,
+        ClipboardDataType::None => return Ok(()),
+    };
+
+    if size > limit {
+        return Err(too_large_error(
+            format!(
+                "{} clipboard is {} bytes, the limit is {} bytes",
+                clipboard_type, size, limit
+            )
+            .as_str(),
+        ));
+    }
+
+    Ok(())
+}
@@ -1,44 +1,226 @@
+use std::future::Future;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
     routing::{get, post},
     Router,
 };
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use axum_server::Handle;
 
+use connect_any_server::api::admin;
+use connect_any_server::api::channel;
+use connect_any_server::api::group;
+use connect_any_server::api::health;
 use connect_any_server::api::message;
 use connect_any_server::api::user;
+use connect_any_server::api::{check_client_cert, limit_request};
+use connect_any_server::backup;
+use connect_any_server::config::{Config, TlsConfig};
 use connect_any_server::init;
+use connect_any_server::metrics;
+use connect_any_server::shutdown;
+use connect_any_server::tls;
+use connect_any_server::utils::database::{database_path, Database};
+use connect_any_server::utils::{ba_error, BDEResult};
+use connect_any_server::web;
 use connect_any_server::websocket::ws_handler;
 
+const USAGE: &str = "usage: connect-any-server [backup [DIR] | restore PATH]
+
+  (no command)  run the server
+  backup [DIR]  save a snapshot of the database and blobs to DIR, or to the configured backup dir
+  restore PATH  stop the server first, then restore from a snapshot dir or a database file";
+
 #[tokio::main]
 async fn main() {
     // initialize tracing
     tracing_subscriber::fmt::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let res = match args.as_slice() {
+        [] => {
+            serve().await;
+            Ok(())
+        }
+        ["backup"] => backup_command(None),
+        ["backup", dir] => backup_command(Some(dir)),
+        ["restore", path] => restore_command(path),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(err) = res {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// 服务器运行时也可以备份, 指定目录时不轮换旧的快照
+fn backup_command(dir: Option<&str>) -> BDEResult<()> {
+    let config = Config::load()?;
+
+    if !database_path().exists() {
+        return Err(ba_error("no database to back up"));
+    }
+
+    let database = Database::open(&database_path())?;
+
+    let snapshot = match dir {
+        Some(dir) => backup::create_snapshot(&database, Path::new(dir))?,
+        None => backup::run_backup(&database, &config.backup)?,
+    };
+
+    println!("{}", serde_json::to_string_pretty(&snapshot)?);
+
+    Ok(())
+}
+
+fn restore_command(path: &str) -> BDEResult<()> {
+    let config = Config::load()?;
+
+    let database = Database::open(&database_path())?;
+
+    let report = backup::restore(&database, Path::new(path), &config.backup.dir)?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+async fn serve() {
     let state = init().await;
+    let tls_config = state.config.tls.clone();
+    let shutdown_state = state.clone();
+    let shutdown = shutdown::shutdown_signal(state.clone(), state.config.shutdown.clone());
 
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .route("/ws", get(ws_handler))
+        .route("/web", get(web::index))
+        .route("/web/", get(web::index))
+        .route("/web/app.js", get(web::app_js))
+        .route("/web/style.css", get(web::style_css))
         .route("/user/adduser", post(user::add_user))
         .route("/user/devices", get(user::get_user_device))
+        .route("/user/deletedevice", post(user::delete_device))
+        .route(
+            "/user/retention",
+            get(user::get_retention).post(user::set_retention),
+        )
+        .route("/user/retention/dryrun", get(user::retention_dry_run))
+        .route(
+            "/user/devicefilter",
+            get(user::get_device_filter_rules).post(user::set_device_filter_rules),
+        )
+        .route("/channel/channels", get(channel::get_channels))
+        .route("/channel/addchannel", post(channel::add_channel))
+        .route("/channel/deletechannel", post(channel::remove_channel))
+        .route("/channel/subscribe", post(channel::subscribe_channels))
+        .route("/group/addgroup", post(group::add_group))
+        .route("/group/groups", get(group::get_groups))
+        .route("/group/members", get(group::get_group_members))
+        .route("/group/setmember", post(group::set_member))
+        .route("/group/removemember", post(group::remove_member))
         .route("/message/addmessage", post(message::add_message))
         .route("/message/updatebase", post(message::message_update_base))
+        .route("/message/updatemessage", post(message::update_message))
+        .route("/message/deletemessage", post(message::delete_message))
+        .route("/message/history", get(message::message_history))
+        .route("/message/search", get(message::message_search))
+        .route("/message/current", get(message::current_message))
+        .route("/message/export", get(message::export_messages))
+        .route("/message/import", post(message::import_messages))
+        .route("/message/:id", get(message::get_message))
+        .route("/admin/users", get(admin::list_users))
+        .route("/admin/devices", get(admin::list_devices))
+        .route("/admin/storage", get(admin::get_storage))
+        .route("/admin/sessions", get(admin::list_sessions))
+        .route("/admin/disconnect", post(admin::disconnect))
+        .route("/admin/resetuser", post(admin::reset_user))
+        .route("/admin/purge", post(admin::purge_history))
+        .route("/admin/backup", post(admin::backup))
+        .route("/metrics", get(metrics::export_metrics))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_client_cert,
+        ))
+        // body 上限由 `limit_request` 按配置检查
+        .layer(middleware::from_fn_with_state(state.clone(), limit_request))
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn(metrics::track_request))
         .with_state(state);
 
-    // run our app with hyper
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:22010")
+    let addr = SocketAddr::from(([0, 0, 0, 0], 22010));
+
+    if !tls_config.enabled {
+        // run our app with hyper
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        tracing::info!("listening on {}", listener.local_addr().unwrap());
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown)
+        .await
+        .unwrap();
+    } else {
+        serve_tls(app, addr, tls_config, shutdown).await;
+    }
+
+    // 升级后的 websocket 连接不在 http 服务器里, 单独等它们关闭
+    let timeout = Duration::from_secs(shutdown_state.config.shutdown.timeout_secs);
+    if !shutdown_state.wait_sessions_closed(timeout).await {
+        tracing::warn!("websocket connections are still open, exiting anyway");
+    }
+    tracing::info!("server stopped");
+}
+
+async fn serve_tls(
+    app: Router,
+    addr: SocketAddr,
+    tls_config: TlsConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let server_config = match tls::load_server_config(&tls_config) {
+        Ok(server_config) => server_config,
+        Err(err) => {
+            eprintln!("error: tls: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let rustls_config = RustlsConfig::from_config(Arc::new(server_config));
+    tls::spawn_reload_task(tls_config, rustls_config.clone());
+
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+
+    tracing::info!("listening on {} (tls)", addr);
+    axum_server::bind(addr)
+        .handle(handle)
+        .acceptor(tls::ClientCertAcceptor::new(RustlsAcceptor::new(
+            rustls_config,
+        )))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
-    tracing::info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
 }
 
 // basic handler that responds with a static string
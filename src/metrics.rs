@@ -0,0 +1,377 @@
+//! `GET /metrics` 返回的 Prometheus 文本格式的指标
+//!
+//! | 名字 | 类型 | 标签 | 说明 |
+//! | --- | --- | --- | --- |
+//! | `connect_any_websocket_connections` | gauge | `device_type` | 在线的 websocket 连接数 |
+//! | `connect_any_clips_received_total` | counter | `type`, `route` | 保存的上传的剪切板, `route` 是 `/message/addmessage`, `/ws` 或 `/message/import` |
+//! | `connect_any_clips_delivered_total` | counter | `type`, `route` | 推送给设备的剪切板, `route` 是 `/ws` 或 `/message/updatebase` |
+//! | `connect_any_broadcast_lagged_total` | counter | `scope` | 连接处理得太慢, 广播通道丢弃消息的次数, `scope` 是 `user` 或 `group` |
+//! | `connect_any_broadcast_lagged_messages_total` | counter | `scope` | 被丢弃的消息数 |
+//! | `connect_any_notifications_total` | counter | `outcome` | 发送 Bark 通知的结果, `success` 或 `error` |
+//! | `connect_any_http_request_duration_seconds` | histogram | `method`, `route` | 请求的处理时间, websocket 只计算到升级为止 |
+//! | `connect_any_db_operation_duration_seconds` | histogram | | 每次在阻塞线程里执行数据库操作的时间 |
+//! | `connect_any_history_clips` | gauge | | 数据库里所有的剪切板数量 |
+//! | `connect_any_history_bytes` | gauge | | 剪切板内容的总大小, 包括图片 |
+//! | `connect_any_history_blob_bytes` | gauge | | 保存为 blob 的图片的总大小 |
+//! | `connect_any_user_history_clips` | histogram | | 每个用户的剪切板数量的分布 |
+//!
+//! `connect_any_history_*` 和 `connect_any_user_history_clips` 在请求 `/metrics` 时从数据库读取
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::datalayer::clipboard::Clipboard;
+use crate::datalayer::database::DatabaseUserStorage;
+use crate::state::AppState;
+use crate::utils::database::run_blocking;
+
+// 标签按名字排列, 同一组标签对应同一个值
+type Labels = Vec<(&'static str, String)>;
+
+const HTTP_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const DB_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+const HISTORY_BUCKETS: &[f64] = &[10.0, 100.0, 1000.0, 10000.0, 100000.0];
+
+fn labels(pairs: &[(&'static str, &str)]) -> Labels {
+    pairs
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// `name{a="1",b="2"}`, `extra` 是 histogram 的 `le`
+fn write_sample(out: &mut String, name: &str, labels: &Labels, extra: Option<String>, value: f64) {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect();
+    pairs.extend(extra.map(|le| format!("le=\"{}\"", le)));
+
+    if pairs.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, pairs.join(","), value);
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// 按标签分开的计数或者当前值, `kind` 是 `counter` 或 `gauge`
+pub struct ValueVec {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    values: Mutex<BTreeMap<Labels, f64>>,
+}
+
+impl ValueVec {
+    fn new(name: &'static str, help: &'static str, kind: &'static str) -> Self {
+        ValueVec {
+            name,
+            help,
+            kind,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn add(&self, pairs: &[(&'static str, &str)], value: f64) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(labels(pairs))
+            .or_insert(0.0) += value;
+    }
+
+    pub fn inc(&self, pairs: &[(&'static str, &str)]) {
+        self.add(pairs, 1.0);
+    }
+
+    pub fn dec(&self, pairs: &[(&'static str, &str)]) {
+        self.add(pairs, -1.0);
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, self.kind);
+
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            write_sample(out, self.name, labels, None, *value);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct HistogramValue {
+    // 每个桶自己的数量, 输出时再累加
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl HistogramValue {
+    fn new(buckets: &[f64]) -> Self {
+        HistogramValue {
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, buckets: &[f64], value: f64) {
+        if let Some(index) = buckets.iter().position(|bucket| value <= *bucket) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, buckets: &[f64], labels: &Labels) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+
+        for (bucket, count) in buckets.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            write_sample(
+                out,
+                &bucket_name,
+                labels,
+                Some(bucket.to_string()),
+                cumulative as f64,
+            );
+        }
+        write_sample(
+            out,
+            &bucket_name,
+            labels,
+            Some("+Inf".to_string()),
+            self.count as f64,
+        );
+        write_sample(out, &format!("{}_sum", name), labels, None, self.sum);
+        write_sample(
+            out,
+            &format!("{}_count", name),
+            labels,
+            None,
+            self.count as f64,
+        );
+    }
+}
+
+/// 按标签分开的分布
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Labels, HistogramValue>>,
+}
+
+impl HistogramVec {
+    fn new(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Self {
+        HistogramVec {
+            name,
+            help,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, pairs: &[(&'static str, &str)], value: f64) {
+        self.values
+            .lock()
+            .unwrap()
+            .entry(labels(pairs))
+            .or_insert_with(|| HistogramValue::new(self.buckets))
+            .observe(self.buckets, value);
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "histogram");
+
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            value.render(out, self.name, self.buckets, labels);
+        }
+    }
+}
+
+/// 进程里所有的指标
+pub struct Metrics {
+    pub websocket_connections: ValueVec,
+    pub clips_received: ValueVec,
+    pub clips_delivered: ValueVec,
+    pub broadcast_lagged: ValueVec,
+    pub broadcast_lagged_messages: ValueVec,
+    pub notifications: ValueVec,
+    pub http_request_duration: HistogramVec,
+    pub db_operation_duration: HistogramVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            websocket_connections: ValueVec::new(
+                "connect_any_websocket_connections",
+                "Open websocket connections.",
+                "gauge",
+            ),
+            clips_received: ValueVec::new(
+                "connect_any_clips_received_total",
+                "Clips saved from devices.",
+                "counter",
+            ),
+            clips_delivered: ValueVec::new(
+                "connect_any_clips_delivered_total",
+                "Clips delivered to devices.",
+                "counter",
+            ),
+            broadcast_lagged: ValueVec::new(
+                "connect_any_broadcast_lagged_total",
+                "Times a connection fell behind its broadcast channel.",
+                "counter",
+            ),
+            broadcast_lagged_messages: ValueVec::new(
+                "connect_any_broadcast_lagged_messages_total",
+                "Broadcast messages dropped for slow connections.",
+                "counter",
+            ),
+            notifications: ValueVec::new(
+                "connect_any_notifications_total",
+                "Bark notifications sent.",
+                "counter",
+            ),
+            http_request_duration: HistogramVec::new(
+                "connect_any_http_request_duration_seconds",
+                "HTTP request latency.",
+                HTTP_BUCKETS,
+            ),
+            db_operation_duration: HistogramVec::new(
+                "connect_any_db_operation_duration_seconds",
+                "Blocking database operation duration.",
+                DB_BUCKETS,
+            ),
+        }
+    }
+
+    /// 进程里唯一的指标, 第一次使用时创建
+    pub fn global() -> &'static Metrics {
+        METRICS.get_or_init(Metrics::new)
+    }
+
+    /// 输出所有的指标, `history` 是每个用户的剪切板用量
+    pub fn render(&self, history: &[DatabaseUserStorage]) -> String {
+        let mut out = String::new();
+
+        self.websocket_connections.render(&mut out);
+        self.clips_received.render(&mut out);
+        self.clips_delivered.render(&mut out);
+        self.broadcast_lagged.render(&mut out);
+        self.broadcast_lagged_messages.render(&mut out);
+        self.notifications.render(&mut out);
+        self.http_request_duration.render(&mut out);
+        self.db_operation_duration.render(&mut out);
+
+        render_history(&mut out, history);
+
+        out
+    }
+}
+
+fn render_history(out: &mut String, history: &[DatabaseUserStorage]) {
+    let no_labels = Labels::new();
+    let totals = [
+        (
+            "connect_any_history_clips",
+            "Clips stored for all users.",
+            history.iter().map(|user| user.clips).sum::<u64>(),
+        ),
+        (
+            "connect_any_history_bytes",
+            "Bytes of clip content stored for all users.",
+            history.iter().map(|user| user.bytes).sum::<u64>(),
+        ),
+        (
+            "connect_any_history_blob_bytes",
+            "Bytes of images stored as blobs.",
+            history.iter().map(|user| user.blob_bytes).sum::<u64>(),
+        ),
+    ];
+
+    for (name, help, value) in totals {
+        write_header(out, name, help, "gauge");
+        write_sample(out, name, &no_labels, None, value as f64);
+    }
+
+    let name = "connect_any_user_history_clips";
+    let mut value = HistogramValue::new(HISTORY_BUCKETS);
+    for user in history {
+        value.observe(HISTORY_BUCKETS, user.clips as f64);
+    }
+    write_header(out, name, "Clips stored per user.", "histogram");
+    value.render(out, name, HISTORY_BUCKETS, &no_labels);
+}
+
+/// `GET /metrics`, 读取数据库失败时只是没有历史记录的指标
+pub async fn export_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let storage = state.storage.clone();
+    let history = match run_blocking(move || storage.get_user_storage()).await {
+        Ok(history) => history,
+        Err(err) => {
+            tracing::error!("metrics: read history error: {}", err);
+            Vec::new()
+        }
+    };
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        Metrics::global().render(&history),
+    )
+}
+
+/// 按剪切板的类型和经过的路由计数
+pub fn record_clip(counter: &ValueVec, clip: &Clipboard, route: &str) {
+    counter.inc(&[("type", &clip.clipboard_type.to_string()), ("route", route)]);
+}
+
+/// 按路由记录请求的处理时间, 没有匹配到路由的请求记为 `unmatched`
+pub async fn track_request(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_string());
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    Metrics::global().http_request_duration.observe(
+        &[("method", &method), ("route", &route)],
+        start.elapsed().as_secs_f64(),
+    );
+
+    response
+}
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::RetentionConfig;
+use crate::datalayer::blob::remove_orphan_blobs;
+use crate::datalayer::database::{DatabaseClipSize, DatabaseRetentionPolicy};
+use crate::datalayer::storage::Storage;
+use crate::utils::BDEResult;
+
+/// 剪切板保留规则, 为空的项表示不限制, 固定 (pinned) 的剪切板不受限制
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_count: Option<u64>,
+    pub max_age_days: Option<u64>,
+    pub max_bytes: Option<u64>,
+    // 敏感内容的保留时间
+    pub sensitive_ttl_secs: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// 没有设置的项使用 `default` 里的值
+    pub fn merge(&self, default: &RetentionPolicy) -> RetentionPolicy {
+        RetentionPolicy {
+            max_count: self.max_count.or(default.max_count),
+            max_age_days: self.max_age_days.or(default.max_age_days),
+            max_bytes: self.max_bytes.or(default.max_bytes),
+            sensitive_ttl_secs: self.sensitive_ttl_secs.or(default.sensitive_ttl_secs),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeReason {
+    Count,
+    Age,
+    Bytes,
+    Sensitive,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PurgeCandidate {
+    pub id: u64,
+    pub date: u64,
+    pub size: u64,
+    pub reason: PurgeReason,
+}
+
+/// 用户自己设置的规则, 没有设置时为空
+pub fn get_user_policy(storage: &dyn Storage, user_id: u64) -> BDEResult<RetentionPolicy> {
+    Ok(match storage.get_retention_policy(user_id)? {
+        Some(policy) => RetentionPolicy {
+            max_count: policy.max_count,
+            max_age_days: policy.max_age_days,
+            max_bytes: policy.max_bytes,
+            sensitive_ttl_secs: policy.sensitive_ttl_secs,
+        },
+        None => RetentionPolicy::default(),
+    })
+}
+
+pub fn set_user_policy(
+    storage: &dyn Storage,
+    user_id: u64,
+    policy: &RetentionPolicy,
+) -> BDEResult<()> {
+    storage.set_retention_policy(&DatabaseRetentionPolicy {
+        user_id,
+        max_count: policy.max_count,
+        max_age_days: policy.max_age_days,
+        max_bytes: policy.max_bytes,
+        sensitive_ttl_secs: policy.sensitive_ttl_secs,
+    })
+}
+
+/// 找出按照 `policy` 需要清除的剪切板, 从新到旧依次保留, 超出限制的都会被清除
+pub fn plan_purge(
+    storage: &dyn Storage,
+    user_id: u64,
+    policy: &RetentionPolicy,
+) -> BDEResult<Vec<PurgeCandidate>> {
+    Ok(plan_purge_clips(
+        storage.get_unpinned_clip_sizes(user_id)?,
+        policy,
+    ))
+}
+
+fn plan_purge_clips(clips: Vec<DatabaseClipSize>, policy: &RetentionPolicy) -> Vec<PurgeCandidate> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let oldest_date = policy
+        .max_age_days
+        .map(|days| now.saturating_sub(days * 24 * 60 * 60 * 1000));
+    let oldest_sensitive_date = policy
+        .sensitive_ttl_secs
+        .map(|secs| now.saturating_sub(secs * 1000));
+
+    let mut kept_count = 0;
+    let mut kept_bytes = 0;
+    let mut candidates = Vec::new();
+
+    for DatabaseClipSize {
+        id,
+        date,
+        size,
+        sensitive,
+    } in clips
+    {
+        let reason = if sensitive && oldest_sensitive_date.is_some_and(|oldest| date < oldest) {
+            Some(PurgeReason::Sensitive)
+        } else if policy.max_count.is_some_and(|max| kept_count >= max) {
+            Some(PurgeReason::Count)
+        } else if oldest_date.is_some_and(|oldest| date < oldest) {
+            Some(PurgeReason::Age)
+        } else if policy.max_bytes.is_some_and(|max| kept_bytes + size > max) {
+            Some(PurgeReason::Bytes)
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => candidates.push(PurgeCandidate {
+                id,
+                date,
+                size,
+                reason,
+            }),
+            None => {
+                kept_count += 1;
+                kept_bytes += size;
+            }
+        }
+    }
+
+    candidates
+}
+
+/// 按照用户的规则清除剪切板, 返回清除的数量
+pub fn enforce_user_retention(
+    storage: &dyn Storage,
+    user_id: u64,
+    default: &RetentionPolicy,
+) -> BDEResult<usize> {
+    let policy = get_user_policy(storage, user_id)?.merge(default);
+    let candidates = plan_purge(storage, user_id, &policy)?;
+
+    let ids: Vec<u64> = candidates.iter().map(|candidate| candidate.id).collect();
+    storage.delete_clips(&ids)?;
+
+    Ok(ids.len())
+}
+
+/// 群组没有自己的规则, 使用服务器默认的规则
+pub fn enforce_group_retention(
+    storage: &dyn Storage,
+    group_id: u64,
+    default: &RetentionPolicy,
+) -> BDEResult<usize> {
+    let candidates = plan_purge_clips(storage.get_unpinned_group_clip_sizes(group_id)?, default);
+
+    let ids: Vec<u64> = candidates.iter().map(|candidate| candidate.id).collect();
+    storage.delete_clips(&ids)?;
+
+    Ok(ids.len())
+}
+
+/// 清理所有用户和群组的剪切板以及不再被引用的 blob
+pub fn run_retention(storage: &dyn Storage, default: &RetentionPolicy) -> BDEResult<()> {
+    for user in storage.get_all_users()? {
+        let purged = enforce_user_retention(storage, user.id, default)?;
+
+        if purged > 0 {
+            tracing::info!("retention purged {} clips of user {}", purged, user.name);
+        }
+    }
+
+    for group in storage.get_all_groups()? {
+        let purged = enforce_group_retention(storage, group.id, default)?;
+
+        if purged > 0 {
+            tracing::info!("retention purged {} clips of group {}", purged, group.name);
+        }
+    }
+
+    let removed = remove_orphan_blobs(&storage.get_blob_names()?)?;
+
+    if removed > 0 {
+        tracing::info!("retention removed {} orphaned blobs", removed);
+    }
+
+    Ok(())
+}
+
+pub fn spawn_retention_task(storage: Arc<dyn Storage>, config: RetentionConfig) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+
+        loop {
+            interval.tick().await;
+
+            let default = config.default.clone();
+            let storage = storage.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                run_retention(storage.as_ref(), &default).map_err(|err| err.to_string())
+            })
+            .await;
+
+            match res {
+                Ok(Err(err)) => tracing::error!("retention error: {}", err),
+                Err(err) => tracing::error!("retention task error: {}", err),
+                Ok(Ok(())) => {}
+            }
+        }
+    });
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::datalayer::clipboard::{Clipboard, ClipboardDataType};
+use crate::utils::BDEResult;
+
+/// 判断剪切板是否为敏感内容的规则
+pub trait SensitivityRule: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn matches(&self, clipboard: &Clipboard) -> bool;
+}
+
+/// 文字内容匹配正则表达式
+pub struct RegexRule {
+    name: String,
+    regex: Regex,
+}
+
+impl RegexRule {
+    pub fn new(name: &str, pattern: &str) -> BDEResult<Self> {
+        Ok(RegexRule {
+            name: name.to_string(),
+            regex: Regex::new(pattern)?,
+        })
+    }
+}
+
+impl SensitivityRule for RegexRule {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn matches(&self, clipboard: &Clipboard) -> bool {
+        matches!(clipboard.clipboard_type, ClipboardDataType::Text)
+            && self.regex.is_match(&clipboard.data)
+    }
+}
+
+// 卡组织的号码前缀范围 (包含两端) 和允许的长度, 时间戳, 订单号等数字不在这些范围里
+const CARD_PREFIXES: &[(u32, u32, &[usize])] = &[
+    // Visa
+    (4, 4, &[13, 16, 19]),
+    // Mastercard
+    (51, 55, &[16]),
+    (2221, 2720, &[16]),
+    // American Express
+    (34, 34, &[15]),
+    (37, 37, &[15]),
+    // Discover
+    (6011, 6011, &[16, 17, 18, 19]),
+    (644, 649, &[16, 17, 18, 19]),
+    (65, 65, &[16, 17, 18, 19]),
+    // JCB
+    (3528, 3589, &[16, 17, 18, 19]),
+    // Diners Club
+    (300, 305, &[14]),
+    (36, 36, &[14]),
+    (38, 39, &[14]),
+    // UnionPay
+    (62, 62, &[16, 17, 18, 19]),
+];
+
+/// 信用卡号, 除了格式还要符合卡组织的前缀和长度, 并通过 Luhn 校验, 减少误判
+pub struct CreditCardRule {
+    regex: Regex,
+}
+
+impl CreditCardRule {
+    pub fn new() -> Self {
+        CreditCardRule {
+            regex: Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap(),
+        }
+    }
+
+    fn issuer(digits: &[u32]) -> bool {
+        CARD_PREFIXES.iter().any(|(low, high, lengths)| {
+            let width = low.to_string().len();
+            let prefix = digits
+                .iter()
+                .take(width)
+                .fold(0, |prefix, digit| prefix * 10 + digit);
+
+            (*low..=*high).contains(&prefix) && lengths.contains(&digits.len())
+        })
+    }
+
+    fn luhn(digits: &[u32]) -> bool {
+        let sum: u32 = digits
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(index, digit)| {
+                if !index.is_multiple_of(2) {
+                    let double = digit * 2;
+                    if double > 9 {
+                        double - 9
+                    } else {
+                        double
+                    }
+                } else {
+                    *digit
+                }
+            })
+            .sum();
+
+        sum.is_multiple_of(10)
+    }
+}
+
+impl Default for CreditCardRule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SensitivityRule for CreditCardRule {
+    fn name(&self) -> &str {
+        "credit_card"
+    }
+
+    fn matches(&self, clipboard: &Clipboard) -> bool {
+        matches!(clipboard.clipboard_type, ClipboardDataType::Text)
+            && self
+                .regex
+                .find_iter(&clipboard.data)
+                .map(|number| {
+                    number
+                        .as_str()
+                        .chars()
+                        .filter_map(|c| c.to_digit(10))
+                        .collect::<Vec<u32>>()
+                })
+                .any(|digits| Self::issuer(&digits) && Self::luhn(&digits))
+    }
+}
+
+/// 客户端根据密码管理器的提示 (例如 macOS 的 ConcealedType) 标记的敏感内容
+pub struct ClientHintRule;
+
+impl SensitivityRule for ClientHintRule {
+    fn name(&self) -> &str {
+        "client_hint"
+    }
+
+    fn matches(&self, clipboard: &Clipboard) -> bool {
+        clipboard.sensitive
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PatternConfig {
+    pub name: String,
+    pub pattern: String,
+}
+
+pub struct Classifier {
+    rules: Vec<Box<dyn SensitivityRule>>,
+}
+
+impl Classifier {
+    pub fn new() -> Self {
+        Classifier { rules: Vec::new() }
+    }
+
+    /// 内置的规则: 客户端提示, 信用卡, 常见的 API key, JWT 和私钥
+    pub fn with_default_rules() -> Self {
+        let patterns = [
+            ("aws_access_key", r"\bAKIA[0-9A-Z]{16}\b"),
+            ("github_token", r"\bgh[pousr]_[A-Za-z0-9]{36,}\b"),
+            ("slack_token", r"\bxox[abposr]-[A-Za-z0-9-]{10,}"),
+            ("google_api_key", r"\bAIza[0-9A-Za-z_-]{35}\b"),
+            (
+                "secret_key",
+                r"\b(?:sk|rk)[-_](?:live_|test_|proj-)?[A-Za-z0-9_-]{20,}",
+            ),
+            (
+                "api_key_assignment",
+                r#"(?i)\b(?:api[_-]?key|secret|token|passw(?:or)?d)\b\s*[:=]\s*["']?[^\s"']{8,}"#,
+            ),
+            (
+                "jwt",
+                r"\beyJ[A-Za-z0-9_-]{8,}\.eyJ[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}",
+            ),
+            ("private_key", r"-----BEGIN (?:[A-Z]+ )*PRIVATE KEY-----"),
+        ];
+
+        let mut classifier = Classifier::new()
+            .with_rule(Box::new(ClientHintRule))
+            .with_rule(Box::new(CreditCardRule::new()));
+
+        for (name, pattern) in patterns {
+            classifier = classifier.with_rule(Box::new(RegexRule::new(name, pattern).unwrap()));
+        }
+
+        classifier
+    }
+
+    /// 内置规则加上配置文件里的自定义正则
+    pub fn from_patterns(patterns: &[PatternConfig]) -> BDEResult<Self> {
+        let mut classifier = Self::with_default_rules();
+
+        for pattern in patterns {
+            classifier = classifier.with_rule(Box::new(RegexRule::new(
+                pattern.name.as_str(),
+                pattern.pattern.as_str(),
+            )?));
+        }
+
+        Ok(classifier)
+    }
+
+    pub fn with_rule(mut self, rule: Box<dyn SensitivityRule>) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// 返回第一个匹配的规则名字, 不敏感时为空
+    pub fn classify(&self, clipboard: &Clipboard) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(clipboard))
+            .map(|rule| rule.name())
+    }
+}
+
+impl fmt::Debug for Classifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.rules.iter().map(|rule| rule.name()))
+            .finish()
+    }
+}
+
+impl Default for Classifier {
+    fn default() -> Self {
+        Self::with_default_rules()
+    }
+}
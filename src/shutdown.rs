@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use crate::config::ShutdownConfig;
+use crate::state::AppState;
+
+/// 等待 SIGTERM 或 SIGINT (Ctrl-C)
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("listen SIGINT error: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("listen SIGTERM error: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// 收到信号后开始关闭服务器, 返回后服务器应该停止接受新的连接
+///
+/// 所有的 websocket 连接发送完已经排队的消息后用 1001 关闭,
+/// 超过 `timeout_secs` 还没有退出时直接结束进程
+pub async fn shutdown_signal(state: AppState, config: ShutdownConfig) {
+    wait_for_signal().await;
+
+    tracing::info!("shutting down, waiting up to {}s", config.timeout_secs);
+
+    // 用系统线程计时, 运行时在等待阻塞任务时也能退出
+    let timeout = Duration::from_secs(config.timeout_secs);
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        tracing::error!("shutdown timed out, exiting");
+        std::process::exit(1);
+    });
+
+    let closed = state.begin_shutdown().await;
+    tracing::info!("closing {} websocket connections", closed);
+}
@@ -1,17 +1,61 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::sync::broadcast;
+use chrono::Utc;
+use connect_any_protocol::{close_code, PresenceDevice, WsMessage};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::AbortHandle;
+use uuid::Uuid;
 
-use crate::datalayer::clipboard::Clipboard;
-use crate::datalayer::Device;
-use crate::utils::{arc_mutex, ArcBroadcastSender, ArcMutex};
+use crate::backup::Backup;
+use crate::config::Config;
+use crate::datalayer::channel::{check_channel, device_channels, DEFAULT_CHANNEL};
+use crate::datalayer::clipboard::{
+    current_clipboard, dedup_clipboard, delete_clipboard, update_clipboard, Clipboard,
+    CurrentClipboard,
+};
+use crate::datalayer::filter::{get_device_filter, DeviceFilter};
+use crate::datalayer::group::{group_devices, member_role};
+use crate::datalayer::storage::Storage;
+use crate::datalayer::{Device, InputDevice, User};
+use crate::limits::{check_clip_size, Limiter};
+use crate::retention::{enforce_group_retention, enforce_user_retention};
+use crate::sensitive::Classifier;
+use crate::utils::database::run_blocking;
+use crate::utils::{arc_mutex, ba_error, log_error, ArcBroadcastSender, ArcMutex, BDEResult};
+
+// 关闭服务器时检查 websocket 连接是否都已经关闭的间隔
+const SESSION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 通过 `ws_tx` 广播给同一个用户所有 websocket 连接的消息
+#[derive(Debug, Clone)]
+pub struct WsBroadcast {
+    // 发出这条消息的设备, 不会再推送回这个设备
+    pub from_device: Option<u64>,
+    // 只推送给这些设备, 为空时推送给所有设备
+    pub recipients: Option<HashSet<u64>>,
+    pub message: WsMessage,
+}
+
+/// 最近一次推送的剪切板和已经有这条剪切板的设备
+#[derive(Debug, Clone)]
+pub struct LastClip {
+    pub id: u64,
+    pub holders: HashSet<u64>,
+}
 
 #[derive(Debug, Clone)]
 pub struct ClipboardData {
-    pub data: Vec<Clipboard>,
     pub devices: Vec<Device>,
-    pub ws_tx: ArcBroadcastSender<Clipboard>,
+    pub ws_tx: ArcBroadcastSender<WsBroadcast>,
+    // device id -> 在线的 websocket 连接数
+    pub online: HashMap<u64, usize>,
+    pub last_clip: Option<LastClip>,
+    // 频道 -> 已经推送的序号最大的当前剪切板, 序号更小的剪切板不会再覆盖它
+    pub current: HashMap<String, CurrentClipboard>,
 }
 
 impl ClipboardData {
@@ -19,9 +63,124 @@ impl ClipboardData {
         let (ws_tx, _) = broadcast::channel(10);
 
         ClipboardData {
-            data: Vec::new(),
             devices: Vec::new(),
             ws_tx: Arc::new(ws_tx),
+            online: HashMap::new(),
+            last_clip: None,
+            current: HashMap::new(),
+        }
+    }
+
+    pub fn add_clipboard(
+        &mut self,
+        clipboard: Clipboard,
+        now_device: &Device,
+        devices: Vec<Device>,
+        filters: &HashMap<u64, DeviceFilter>,
+    ) {
+        let now = Utc::now();
+
+        // 重复复制最近一次推送的剪切板时, 不再推送给已经有这条剪切板的设备
+        let repeated = self
+            .last_clip
+            .as_ref()
+            .is_some_and(|last_clip| last_clip.id == clipboard.id);
+        let mut holders = match self.last_clip.take() {
+            Some(last_clip) if repeated => last_clip.holders,
+            _ => HashSet::new(),
+        };
+
+        // 定向发送时只发给目标设备, 再按照每个设备的接收规则过滤
+        let devices: Vec<Device> = devices
+            .into_iter()
+            .filter(|device| device != now_device)
+            .filter(|device| !holders.contains(&device.id))
+            .filter(|device| {
+                clipboard
+                    .targets
+                    .as_ref()
+                    .is_none_or(|targets| targets.contains(&device.id))
+            })
+            .filter(|device| {
+                filters
+                    .get(&device.id)
+                    .is_none_or(|filter| filter.allows(&clipboard, now_device.id, now))
+            })
+            .collect();
+
+        let recipients: HashSet<u64> = devices.iter().map(|device| device.id).collect();
+
+        holders.insert(now_device.id);
+        holders.extend(recipients.iter());
+        self.last_clip = Some(LastClip {
+            id: clipboard.id,
+            holders,
+        });
+
+        // websocket
+        if !recipients.is_empty() {
+            let message = WsMessage::ClipPush {
+                id: Uuid::now_v7().to_string(),
+                clip: clipboard,
+            };
+            self.broadcast_to(Some(now_device.id), Some(recipients), message);
+        }
+
+        // 重复的剪切板不影响还没有取走这条剪切板的设备
+        if repeated {
+            for device in devices {
+                if !self.devices.contains(&device) {
+                    self.devices.push(device);
+                }
+            }
+        } else {
+            self.devices = devices;
+        }
+    }
+
+    pub fn broadcast(&self, from_device: Option<u64>, message: WsMessage) {
+        self.broadcast_to(from_device, None, message);
+    }
+
+    pub fn broadcast_to(
+        &self,
+        from_device: Option<u64>,
+        recipients: Option<HashSet<u64>>,
+        message: WsMessage,
+    ) {
+        if let Err(err) = self.ws_tx.send(WsBroadcast {
+            from_device,
+            recipients,
+            message,
+        }) {
+            tracing::error!("send websocket error: {}", err);
+        }
+    }
+
+    pub fn set_online(&mut self, device_id: u64, online: bool) {
+        let count = self.online.entry(device_id).or_insert(0);
+
+        if online {
+            *count += 1;
+        } else {
+            *count = count.saturating_sub(1);
+        }
+
+        if *count == 0 {
+            self.online.remove(&device_id);
+        }
+    }
+
+    pub fn presence(&self, devices: &[Device]) -> WsMessage {
+        WsMessage::Presence {
+            devices: devices
+                .iter()
+                .map(|device| PresenceDevice {
+                    name: device.name.clone(),
+                    device_type: device.device_type.to_string(),
+                    online: self.online.contains_key(&device.id),
+                })
+                .collect(),
         }
     }
 }
@@ -32,28 +191,442 @@ impl Default for ClipboardData {
     }
 }
 
+/// 在线的 websocket 连接, 管理员可以强制断开
+#[derive(Debug)]
+pub struct WsSession {
+    pub user_id: u64,
+    pub device_id: u64,
+    pub addr: SocketAddr,
+    // 连接建立的时间 (毫秒)
+    pub connected_at: u64,
+    // 发送 close code 让连接关闭
+    pub close_tx: mpsc::Sender<u16>,
+    // 订阅的群组和转发群组消息的任务, 退出群组时停止转发
+    pub groups: HashMap<u64, AbortHandle>,
+}
+
 #[derive(Debug, Clone)]
 pub struct AppState {
     // pub clipboard_data: ArcMutex<HashMap<String, Vec<Clipboard>>>,
     pub clipboard_datas: ArcMutex<HashMap<u64, ClipboardData>>,
+    // 群组 id -> 群组的广播通道
+    pub group_datas: ArcMutex<HashMap<u64, ClipboardData>>,
     // pub short_memory_message: ArcMutex<Vec<RequestMessage>>,
     // pub message_tx: ArcMpscSender<InputMessage>,
-    pub client_n: ArcMutex<u8>,
+    // session id -> 在线的 websocket 连接
+    pub ws_sessions: ArcMutex<HashMap<String, WsSession>>,
+    // 所有数据的存储, 使用 sqlite 时持有启动时创建的连接池,
+    // 所有数据库操作都通过 `run_blocking` 放到阻塞线程里执行
+    pub storage: Arc<dyn Storage>,
+    // 保存快照用的数据库, 使用 `MemoryStorage` 时为空
+    pub backup: Option<Arc<dyn Backup>>,
+    pub config: Arc<Config>,
+    pub classifier: Arc<Classifier>,
+    pub limiter: Arc<Limiter>,
+    // 收到 SIGTERM/SIGINT 后为 true, 不再接受新的 websocket 连接
+    pub shutting_down: Arc<AtomicBool>,
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    pub fn new(config: Config, storage: Arc<dyn Storage>) -> Self {
         AppState {
             clipboard_datas: arc_mutex(HashMap::new()),
+            group_datas: arc_mutex(HashMap::new()),
             // short_memory_message: arc_mutex(Vec::new()),
             // message_tx: Arc::new(message_tx),
-            client_n: arc_mutex(0),
+            ws_sessions: arc_mutex(HashMap::new()),
+            storage,
+            backup: None,
+            // 配置文件加载时已经检查过正则, 这里只会是代码里构造的配置
+            classifier: Arc::new(
+                Classifier::from_patterns(&config.sensitive.patterns).unwrap_or_else(|err| {
+                    tracing::error!("sensitive patterns: {}", err);
+                    Classifier::with_default_rules()
+                }),
+            ),
+            limiter: Arc::new(Limiter::new(&config.limits)),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn with_backup(mut self, backup: Arc<dyn Backup>) -> Self {
+        self.backup = Some(backup);
+        self
+    }
+
+    /// 在阻塞线程里查找登录的设备
+    pub async fn parse_device(&self, device: InputDevice) -> BDEResult<Device> {
+        let storage = self.storage.clone();
+
+        run_blocking(move || device.parse(storage.as_ref())).await
+    }
+
+    /// 在阻塞线程里查找设备所属的用户
+    pub async fn find_user(&self, device: &Device) -> BDEResult<User> {
+        let storage = self.storage.clone();
+        let device = device.clone();
+
+        run_blocking(move || User::find_user_from_device(storage.as_ref(), &device)).await
+    }
+
+    /// http 和 websocket 上传剪切板共用的入口, 返回保存后的剪切板
+    pub async fn add_clipboard(
+        &self,
+        user: User,
+        now_device: &Device,
+        mut clipboard: Clipboard,
+    ) -> BDEResult<Clipboard> {
+        check_clip_size(
+            &self.config.limits,
+            clipboard.clipboard_type,
+            clipboard.data.len(),
+        )?;
+
+        // 排序只使用服务器的时间和序号, 客户端时间作为参考保留
+        clipboard.received_at = Utc::now().timestamp_millis() as u64;
+        clipboard.future_date = clipboard.date as u64
+            > clipboard.received_at + self.config.clock.max_future_secs * 1000;
+
+        if clipboard.future_date {
+            if self.config.clock.reject_future {
+                return Err(ba_error("clipboard date is in the future"));
+            }
+
+            tracing::warn!(
+                "device ({}) clipboard date {} is ahead of server time",
+                now_device.name,
+                clipboard.date
+            );
+        }
+
+        let user_id = user.id;
+        let classifier = self.classifier.clone();
+        let storage = self.storage.clone();
+        let from_device = now_device.clone();
+        let (clipboard, devices, filters) = run_blocking(move || {
+            let storage = storage.as_ref();
+
+            if let Some(rule) = classifier.classify(&clipboard) {
+                tracing::info!(
+                    "device ({}) clipboard marked sensitive by {}",
+                    from_device.name,
+                    rule
+                );
+                clipboard.sensitive = true;
+            }
+
+            // 发布到群组时推送给所有成员的设备, 个人剪切板推送给订阅了频道的设备
+            let devices = match clipboard.group_id {
+                Some(group_id) => {
+                    if !member_role(storage, group_id, user.id)?.can_publish() {
+                        return Err(ba_error("no permission to publish to the group"));
+                    }
+
+                    // 群组没有频道
+                    clipboard.channel = None;
+
+                    group_devices(storage, group_id)?
+                }
+                None => {
+                    clipboard.channel =
+                        check_channel(storage, user.id, clipboard.channel.as_deref())?;
+
+                    subscribed_devices(storage, user.devices, clipboard.channel.as_deref())?
+                }
+            };
+
+            // 定向发送的目标必须是其他可以接收的设备
+            if let Some(targets) = clipboard.targets.as_mut() {
+                targets.sort_unstable();
+                targets.dedup();
+                targets.retain(|target| *target != from_device.id);
+
+                if targets.is_empty() {
+                    return Err(ba_error("targets is empty"));
+                }
+
+                if !targets
+                    .iter()
+                    .all(|target| devices.iter().any(|device| device.id == *target))
+                {
+                    return Err(ba_error("not find target device"));
+                }
+            }
+
+            let filters = get_device_filters(storage, &devices)?;
+
+            Ok((clipboard, devices, filters))
+        })
+        .await?;
+
+        let group_id = clipboard.group_id;
+        let dedup_window = self.config.dedup.window_secs;
+        let retention_policy = self.config.retention.default.clone();
+        let storage = self.storage.clone();
+        let from_device = now_device.clone();
+        let (previous, clipboard) = run_blocking(move || {
+            let previous = match clipboard.targets {
+                Some(_) => None,
+                None => current_clipboard(
+                    storage.as_ref(),
+                    user_id,
+                    group_id,
+                    clipboard.channel.as_deref(),
+                )?,
+            };
+
+            // 最近复制过相同的内容时只更新原来的剪切板, 新保存的剪切板才检查保留规则
+            let (clipboard, duplicate) = dedup_clipboard(
+                storage.as_ref(),
+                user_id,
+                &from_device,
+                clipboard,
+                dedup_window,
+            )?;
+
+            if !duplicate {
+                let retention = match group_id {
+                    Some(group_id) => {
+                        enforce_group_retention(storage.as_ref(), group_id, &retention_policy)
+                    }
+                    None => enforce_user_retention(storage.as_ref(), user_id, &retention_policy),
+                };
+                log_error("retention", retention.map(|_| ()));
+            }
+
+            Ok((previous, clipboard))
+        })
+        .await?;
+
+        // 只在更新内存里的推送状态时持有锁, 数据库操作都在锁外
+        let mut clipboard_datas = match group_id {
+            Some(_) => self.group_datas.lock().await,
+            None => self.clipboard_datas.lock().await,
+        };
+
+        let clipboard_data = clipboard_datas
+            .entry(group_id.unwrap_or(user_id))
+            .or_insert(ClipboardData::new());
+
+        // 并发上传时序号更大的剪切板已经推送了, 这条不再推送, 只通知上传的设备
+        let untargeted = clipboard.targets.is_none();
+        let channel = clipboard
+            .channel
+            .clone()
+            .unwrap_or(DEFAULT_CHANNEL.to_string());
+        let current = clipboard_data
+            .current
+            .get(&channel)
+            .copied()
+            .filter(|_| untargeted);
+        if let Some(current) = current.filter(|current| current.seq > clipboard.seq) {
+            clipboard_data.broadcast_to(
+                None,
+                Some(HashSet::from([now_device.id])),
+                WsMessage::ClipSuperseded {
+                    clip_id: clipboard.id,
+                    current_id: current.id,
+                    current_seq: current.seq,
+                },
+            );
+
+            return Ok(clipboard);
+        }
+
+        // 内存里的当前剪切板可能比锁外读到的更新
+        let previous = previous
+            .into_iter()
+            .chain(current)
+            .max_by_key(|current| current.seq);
+
+        if untargeted {
+            clipboard_data.current.insert(
+                channel,
+                CurrentClipboard {
+                    id: clipboard.id,
+                    seq: clipboard.seq,
+                    device_id: Some(now_device.id),
+                },
+            );
+        }
+
+        clipboard_data.add_clipboard(clipboard.clone(), now_device, devices, &filters);
+
+        // 定向发送的剪切板不会成为当前剪切板, 通知上一条剪切板的设备已经被取代
+        let superseded = previous.filter(|previous| {
+            previous.id != clipboard.id
+                && previous
+                    .device_id
+                    .is_some_and(|device_id| device_id != now_device.id)
+        });
+        if let Some(CurrentClipboard {
+            id: previous_id,
+            device_id: Some(device_id),
+            ..
+        }) = superseded
+        {
+            clipboard_data.broadcast_to(
+                None,
+                Some(HashSet::from([device_id])),
+                WsMessage::ClipSuperseded {
+                    clip_id: previous_id,
+                    current_id: clipboard.id,
+                    current_seq: clipboard.seq,
+                },
+            );
+        }
+
+        Ok(clipboard)
+    }
+
+    /// 修改固定/收藏状态并通知用户所有的 websocket 连接
+    pub async fn update_clipboard(
+        &self,
+        user_id: u64,
+        id: u64,
+        pinned: Option<bool>,
+        favorite: Option<bool>,
+    ) -> BDEResult<Clipboard> {
+        let storage = self.storage.clone();
+        let clipboard =
+            run_blocking(move || update_clipboard(storage.as_ref(), user_id, id, pinned, favorite))
+                .await?;
+
+        self.broadcast(
+            user_id,
+            clipboard.group_id,
+            WsMessage::ClipUpdated {
+                clip: clipboard.redacted(),
+            },
+        )
+        .await;
+
+        Ok(clipboard)
+    }
+
+    /// 删除剪切板并通知用户所有的 websocket 连接
+    pub async fn delete_clipboard(&self, user_id: u64, id: u64) -> BDEResult<()> {
+        let storage = self.storage.clone();
+        let group_id =
+            run_blocking(move || delete_clipboard(storage.as_ref(), user_id, id)).await?;
+
+        self.broadcast(user_id, group_id, WsMessage::ClipDeleted { id })
+            .await;
+
+        Ok(())
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// 开始关闭服务器, 所有的 websocket 连接发送完已经排队的消息后用 1001 关闭
+    pub async fn begin_shutdown(&self) -> usize {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        self.disconnect_sessions(|_, _| true, close_code::GOING_AWAY)
+            .await
+    }
+
+    /// 等待所有的 websocket 连接关闭, 超过 `timeout` 时返回 false
+    pub async fn wait_sessions_closed(&self, timeout: Duration) -> bool {
+        let wait = async {
+            while !self.ws_sessions.lock().await.is_empty() {
+                tokio::time::sleep(SESSION_POLL_INTERVAL).await;
+            }
+        };
+
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+
+    /// 用 `code` 关闭满足条件的 websocket 连接, 返回关闭的数量
+    pub async fn disconnect_sessions<F: Fn(&str, &WsSession) -> bool>(
+        &self,
+        matches: F,
+        code: u16,
+    ) -> usize {
+        let ws_sessions = self.ws_sessions.lock().await;
+        let mut count = 0;
+
+        for (_, session) in ws_sessions
+            .iter()
+            .filter(|(id, session)| matches(id, session))
+        {
+            // 连接正在关闭时会发送失败, 不算在内
+            if session.close_tx.try_send(code).is_ok() {
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// 用户退出群组后, 已经建立的连接不再收到群组的消息
+    pub async fn leave_group(&self, group_id: u64, user_id: u64) {
+        let mut ws_sessions = self.ws_sessions.lock().await;
+
+        for session in ws_sessions
+            .values_mut()
+            .filter(|session| session.user_id == user_id)
+        {
+            if let Some(task) = session.groups.remove(&group_id) {
+                task.abort();
+            }
+        }
+    }
+
+    /// 清空用户还没有取走的剪切板, 用于删除历史记录或者设备之后
+    pub async fn reset_user_data(&self, user_id: u64) {
+        if let Some(clipboard_data) = self.clipboard_datas.lock().await.get_mut(&user_id) {
+            clipboard_data.devices.clear();
+            clipboard_data.last_clip = None;
+            clipboard_data.current.clear();
+        }
+    }
+
+    /// 群组的剪切板通知群组的连接, 否则通知用户自己的连接
+    async fn broadcast(&self, user_id: u64, group_id: Option<u64>, message: WsMessage) {
+        let clipboard_datas = match group_id {
+            Some(_) => self.group_datas.lock().await,
+            None => self.clipboard_datas.lock().await,
+        };
+
+        if let Some(clipboard_data) = clipboard_datas.get(&group_id.unwrap_or(user_id)) {
+            clipboard_data.broadcast(None, message);
         }
     }
 }
 
-impl Default for AppState {
-    fn default() -> Self {
-        Self::new()
+fn subscribed_devices(
+    storage: &dyn Storage,
+    devices: Vec<Device>,
+    channel: Option<&str>,
+) -> BDEResult<Vec<Device>> {
+    let channel = channel.unwrap_or(DEFAULT_CHANNEL);
+    let mut subscribed = Vec::new();
+
+    for device in devices {
+        if device_channels(storage, device.id)?
+            .iter()
+            .any(|name| name == channel)
+        {
+            subscribed.push(device);
+        }
     }
+
+    Ok(subscribed)
+}
+
+fn get_device_filters(
+    storage: &dyn Storage,
+    devices: &[Device],
+) -> BDEResult<HashMap<u64, DeviceFilter>> {
+    let mut filters = HashMap::new();
+
+    for device in devices {
+        filters.insert(device.id, get_device_filter(storage, device.id)?);
+    }
+
+    Ok(filters)
 }
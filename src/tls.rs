@@ -0,0 +1,242 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+
+use crate::config::{ClientCertConfig, TlsConfig};
+use crate::datalayer::DeviceType;
+use crate::utils::{ba_error, sha256_hex, BDEResult};
+
+/// 连接握手时客户端提供的证书, 每个 https 请求和 websocket 连接都可以拿到
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertificate {
+    // 证书 DER 内容的 sha256, 没有提供证书时为空
+    pub fingerprint: Option<String>,
+}
+
+impl ClientCertificate {
+    /// 证书对应的设备, 没有提供证书时为空
+    pub fn device<'a>(&self, config: &'a TlsConfig) -> BDEResult<Option<&'a ClientCertConfig>> {
+        let Some(fingerprint) = &self.fingerprint else {
+            return Ok(None);
+        };
+
+        config
+            .client_certs
+            .iter()
+            .find(|item| normalize_fingerprint(&item.fingerprint) == *fingerprint)
+            .map(Some)
+            .ok_or_else(|| ba_error("client certificate is not bound to a device"))
+    }
+
+    /// 有证书时只能以证书对应的设备访问, 绑定了证书的设备必须提供证书
+    pub fn check(&self, config: &TlsConfig, name: &str, device_type: &str) -> BDEResult<()> {
+        let device_type = device_type.parse::<DeviceType>().ok();
+        let matches = |device: &ClientCertConfig| {
+            device.name == name && Some(device.device_type) == device_type
+        };
+
+        match self.device(config)? {
+            Some(device) if !matches(device) => {
+                Err(ba_error("client certificate does not match device"))
+            }
+            None if config.client_certs.iter().any(matches) => {
+                Err(ba_error("device requires a client certificate"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// 允许配置里的指纹带冒号和大写, 例如 `openssl x509 -fingerprint -sha256` 的输出
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn open_pem(path: &Path) -> BDEResult<BufReader<File>> {
+    let file = File::open(path)
+        .map_err(|err| ba_error(format!("open {}: {}", path.display(), err).as_str()))?;
+
+    Ok(BufReader::new(file))
+}
+
+fn load_certs(path: &Path) -> BDEResult<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?)
+        .map(|cert| cert.map(|der| Certificate(der.to_vec())))
+        .collect::<io::Result<Vec<Certificate>>>()?;
+
+    if certs.is_empty() {
+        return Err(ba_error(
+            format!("no certificate found in {}", path.display()).as_str(),
+        ));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> BDEResult<PrivateKey> {
+    let key = rustls_pemfile::private_key(&mut open_pem(path)?)?
+        .ok_or_else(|| ba_error(format!("no private key found in {}", path.display()).as_str()))?;
+
+    Ok(PrivateKey(key.secret_der().to_vec()))
+}
+
+/// 按照配置读取证书和私钥, 设置了 `client_ca` 时验证客户端证书
+pub fn load_server_config(config: &TlsConfig) -> BDEResult<ServerConfig> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(&cert)?;
+            }
+
+            let verifier = if config.require_client_cert {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+// 证书相关文件的修改时间, 用来判断是否需要重新加载
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    let mut paths: Vec<&PathBuf> = vec![&config.cert, &config.key];
+    paths.extend(config.client_ca.iter());
+
+    paths
+        .into_iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+// 收到 SIGHUP 时通知重新加载
+#[cfg(unix)]
+fn spawn_hangup_listener(reload_tx: mpsc::Sender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            tracing::error!("tls: listen SIGHUP error: {}", err);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            tracing::info!("tls: SIGHUP received");
+            let _ = reload_tx.try_send(());
+        }
+    });
+}
+
+/// 证书文件被修改或者收到 SIGHUP 时重新加载, 已经建立的连接不受影响
+///
+/// 新的证书有问题时继续使用原来的证书
+pub fn spawn_reload_task(config: TlsConfig, rustls_config: RustlsConfig) {
+    let (reload_tx, mut reload_rx) = mpsc::channel::<()>(1);
+
+    #[cfg(unix)]
+    spawn_hangup_listener(reload_tx.clone());
+
+    tokio::spawn(async move {
+        // 一直持有发送端, 不支持信号的平台上 `recv` 只会一直等待
+        let _reload_tx = reload_tx;
+        let period = Duration::from_secs(config.reload_interval_secs.max(1));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut modified = modified_times(&config);
+
+        loop {
+            tokio::select! {
+                Some(()) = reload_rx.recv() => {}
+                _ = interval.tick(), if config.reload_interval_secs > 0 => {
+                    let now_modified = modified_times(&config);
+                    if now_modified == modified {
+                        continue;
+                    }
+                }
+            }
+            modified = modified_times(&config);
+
+            match load_server_config(&config) {
+                Ok(server_config) => {
+                    rustls_config.reload_from_config(Arc::new(server_config));
+                    tracing::info!("tls: certificate reloaded");
+                }
+                Err(err) => tracing::error!("tls: reload certificate error: {}", err),
+            }
+        }
+    });
+}
+
+/// 在 rustls 握手后把客户端证书放到请求的 extension 里
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(inner: RustlsAcceptor) -> Self {
+        ClientCertAcceptor { inner }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientCertificate>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+
+            // 第一个是客户端自己的证书, 后面是中间证书
+            let fingerprint = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| sha256_hex(&cert.0));
+
+            Ok((
+                stream,
+                Extension(ClientCertificate { fingerprint }).layer(service),
+            ))
+        })
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::Utc;
+use connect_any_protocol::{DeviceIdentity, DEFAULT_CHANNEL};
+use serde::{Deserialize, Serialize};
+
+use crate::config::LimitsConfig;
+use crate::datalayer::clipboard::{
+    clipboard_hash, save_imported_clipboard, user_clipboards, Clipboard, ClipboardDataType,
+};
+use crate::datalayer::storage::Storage;
+use crate::datalayer::{Device, User};
+use crate::limits::check_clip_size;
+use crate::metrics::{record_clip, Metrics};
+use crate::retention::{enforce_user_retention, RetentionPolicy};
+use crate::sensitive::Classifier;
+use crate::utils::{ba_error, BDEResult};
+
+// 导出文件的版本, 格式不兼容时增加
+const EXPORT_VERSION: u32 = 1;
+
+/// 导入导出的文件格式, `Copyq` 和 `Clipy` 只能导入
+///
+/// - `Jsonl`: 每行一条记录, 第一行是 `meta`, 然后是 `device` 和从旧到新的 `clip`
+/// - `Csv`: 只有文字剪切板, 导入时只需要 `data` 列
+/// - `Copyq`: CopyQ 脚本导出的 json 数组, 从新到旧, 每一项是 mime 类型到内容的映射,
+///   例如 `copyq eval 'var r=[];for(var i=0;i<size();++i)r.push({"text/plain":str(read(i))});print(JSON.stringify(r))'`,
+///   可以加上 `time` (毫秒) 保留复制的时间
+/// - `Clipy`: Clipy 导出的 snippets xml
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferFormat {
+    #[default]
+    Jsonl,
+    Csv,
+    Copyq,
+    Clipy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportMeta {
+    pub version: u32,
+    pub user: String,
+    pub exported_at: u64,
+    pub clips: usize,
+}
+
+/// json lines 里的一行
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExportRecord {
+    Meta(ExportMeta),
+    Device(DeviceIdentity),
+    Clip(Clipboard),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CsvClip {
+    #[serde(default)]
+    date: Option<u64>,
+    #[serde(default)]
+    received_at: Option<u64>,
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    favorite: bool,
+    #[serde(default)]
+    sensitive: bool,
+    #[serde(default)]
+    device_name: Option<String>,
+    #[serde(default)]
+    device_type: Option<String>,
+    data: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,
+    // 和已有的历史记录或者同一个文件里前面的内容重复
+    pub duplicates: usize,
+    // 空的或者不支持的内容
+    pub skipped: usize,
+}
+
+fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+/// 导出用户的设备和所有的个人剪切板
+pub fn export_jsonl(storage: &dyn Storage, user: &User) -> BDEResult<String> {
+    let clips = user_clipboards(storage, user.id)?;
+
+    let mut records = vec![ExportRecord::Meta(ExportMeta {
+        version: EXPORT_VERSION,
+        user: user.name.clone(),
+        exported_at: now_millis(),
+        clips: clips.len(),
+    })];
+    records.extend(
+        user.devices
+            .iter()
+            .map(|device| ExportRecord::Device(device.identity())),
+    );
+    records.extend(clips.into_iter().map(ExportRecord::Clip));
+
+    let mut output = String::new();
+    for record in records {
+        output.push_str(&serde_json::to_string(&record)?);
+        output.push('\n');
+    }
+
+    Ok(output)
+}
+
+/// 只导出文字剪切板
+pub fn export_csv(storage: &dyn Storage, user: &User) -> BDEResult<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+
+    for clip in user_clipboards(storage, user.id)? {
+        if clip.clipboard_type != ClipboardDataType::Text {
+            continue;
+        }
+
+        writer.serialize(CsvClip {
+            date: Some(clip.date as u64),
+            received_at: Some(clip.received_at),
+            channel: clip.channel,
+            pinned: clip.pinned,
+            favorite: clip.favorite,
+            sensitive: clip.sensitive,
+            device_name: clip.source.as_ref().map(|source| source.name.clone()),
+            device_type: clip.source.map(|source| source.device_type),
+            data: clip.data,
+        })?;
+    }
+
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+pub fn parse_import(format: TransferFormat, data: &str) -> BDEResult<Vec<Clipboard>> {
+    match format {
+        TransferFormat::Jsonl => parse_jsonl(data),
+        TransferFormat::Csv => parse_csv(data),
+        TransferFormat::Copyq => parse_copyq(data),
+        TransferFormat::Clipy => parse_clipy(data),
+    }
+}
+
+fn parse_jsonl(data: &str) -> BDEResult<Vec<Clipboard>> {
+    let mut clips = Vec::new();
+
+    for (index, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: ExportRecord = serde_json::from_str(line)
+            .map_err(|err| ba_error(format!("line {}: {}", index + 1, err).as_str()))?;
+
+        if let ExportRecord::Clip(clip) = record {
+            clips.push(clip);
+        }
+    }
+
+    Ok(clips)
+}
+
+fn parse_csv(data: &str) -> BDEResult<Vec<Clipboard>> {
+    let mut reader = csv::Reader::from_reader(data.as_bytes());
+    let mut clips = Vec::new();
+
+    for row in reader.deserialize::<CsvClip>() {
+        let row = row?;
+        let mut clip = Clipboard::new(row.data, ClipboardDataType::Text);
+
+        if let Some(date) = row.date {
+            clip.date = date as u128;
+        }
+        clip.received_at = row.received_at.unwrap_or(0);
+        clip.channel = row.channel.filter(|channel| !channel.is_empty());
+        clip.pinned = row.pinned;
+        clip.favorite = row.favorite;
+        clip.sensitive = row.sensitive;
+        if let (Some(name), Some(device_type)) = (row.device_name, row.device_type) {
+            clip.source = Some(DeviceIdentity { name, device_type });
+        }
+
+        clips.push(clip);
+    }
+
+    Ok(clips)
+}
+
+// 没有时间的记录按照在文件里的顺序, 从导入的时间往前每条间隔 1 毫秒
+fn ordered_date(now: u64, newer: usize) -> u128 {
+    now.saturating_sub(newer as u64) as u128
+}
+
+fn parse_copyq(data: &str) -> BDEResult<Vec<Clipboard>> {
+    let items: Vec<HashMap<String, serde_json::Value>> = serde_json::from_str(data)?;
+    let now = now_millis();
+    let mut clips = Vec::new();
+
+    // CopyQ 的第一项是最新的
+    for (index, item) in items.iter().enumerate() {
+        let content = |mime: &str| item.get(mime).and_then(|value| value.as_str());
+
+        let mut clip = if let Some(text) = content("text/plain") {
+            Clipboard::new(text.to_string(), ClipboardDataType::Text)
+        } else if let Some(image) = content("image/png") {
+            Clipboard::new(image.to_string(), ClipboardDataType::Image)
+        } else {
+            Clipboard::new(String::new(), ClipboardDataType::None)
+        };
+
+        clip.date = match item.get("time").and_then(|time| time.as_u64()) {
+            Some(time) => time as u128,
+            None => ordered_date(now, index),
+        };
+
+        clips.push(clip);
+    }
+
+    Ok(clips)
+}
+
+fn parse_clipy(data: &str) -> BDEResult<Vec<Clipboard>> {
+    let document = roxmltree::Document::parse(data)?;
+    let now = now_millis();
+
+    let contents: Vec<String> = document
+        .descendants()
+        .filter(|node| node.has_tag_name("snippet"))
+        .map(|snippet| {
+            snippet
+                .children()
+                .find(|node| node.has_tag_name("content"))
+                .and_then(|content| content.text())
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect();
+
+    // snippets 没有时间, 按照文件里的顺序从旧到新
+    let count = contents.len();
+    Ok(contents
+        .into_iter()
+        .enumerate()
+        .map(|(index, content)| {
+            let mut clip = Clipboard::new(content, ClipboardDataType::Text);
+            clip.date = ordered_date(now, count - index - 1);
+            clip
+        })
+        .collect())
+}
+
+/// 把导入的剪切板保存到用户的个人历史记录里
+///
+/// - 导入的剪切板排在已有的历史记录前面, 同一次导入的按原来的时间从旧到新排序,
+///   不会修改已有剪切板的序号, 各频道原来的当前剪切板保持不变
+/// - 和已有的历史记录重复的内容跳过, 不会更新原来的剪切板
+/// - 超过大小上限的内容跳过, 导入后按照用户的保留规则清理
+/// - 来源设备是用户的设备时保留, 否则记为导入的设备
+/// - 不是 `channels` 里的频道保存到默认频道
+/// - 不会推送给设备
+#[allow(clippy::too_many_arguments)]
+pub fn import_clipboards(
+    storage: &dyn Storage,
+    user: &User,
+    device: &Device,
+    channels: &[String],
+    classifier: &Classifier,
+    limits: &LimitsConfig,
+    retention: &RetentionPolicy,
+    mut clips: Vec<Clipboard>,
+) -> BDEResult<ImportReport> {
+    let now = now_millis();
+    let mut report = ImportReport::default();
+    let mut seen: HashSet<(Option<String>, String)> = HashSet::new();
+    let mut accepted = Vec::new();
+
+    clips.sort_by_key(|clip| clip.date);
+
+    for mut clip in clips {
+        if clip.data.is_empty()
+            || clip.clipboard_type == ClipboardDataType::None
+            || check_clip_size(limits, clip.clipboard_type, clip.data.len()).is_err()
+        {
+            report.skipped += 1;
+            continue;
+        }
+
+        clip.id = 0;
+        clip.group_id = None;
+        clip.targets = None;
+        clip.future_date = false;
+        clip.date = clip.date.min(now as u128);
+        if clip.received_at == 0 || clip.received_at > now {
+            clip.received_at = clip.date as u64;
+        }
+        clip.channel = clip
+            .channel
+            .filter(|channel| channel != DEFAULT_CHANNEL && channels.contains(channel));
+        if classifier.classify(&clip).is_some() {
+            clip.sensitive = true;
+        }
+
+        let hash = clipboard_hash(&clip);
+        if !seen.insert((clip.channel.clone(), hash.clone()))
+            || storage
+                .find_duplicate_clip(user.id, &clip, &hash, 0)?
+                .is_some()
+        {
+            report.duplicates += 1;
+            continue;
+        }
+
+        accepted.push(clip);
+    }
+
+    if accepted.is_empty() {
+        return Ok(report);
+    }
+
+    let start = storage.reserve_import_seqs(accepted.len() as u64)?;
+
+    for (seq, clip) in (start..).zip(accepted) {
+        let source = clip
+            .source
+            .as_ref()
+            .and_then(|source| user.devices.iter().find(|item| item.identity() == *source))
+            .unwrap_or(device)
+            .clone();
+        let (pinned, favorite) = (clip.pinned, clip.favorite);
+
+        let saved = save_imported_clipboard(storage, user.id, &source, clip, seq)?;
+        record_clip(&Metrics::global().clips_received, &saved, "/message/import");
+        if pinned || favorite {
+            storage.update_clip_flags(user.id, saved.id, Some(pinned), Some(favorite))?;
+        }
+
+        report.imported += 1;
+    }
+
+    enforce_user_retention(storage, user.id, retention)?;
+
+    Ok(report)
+}
@@ -1,39 +1,181 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use rusqlite::Connection;
 use rusqlite::Params;
 use rusqlite::ToSql;
 
-use super::BDEResult;
+use super::{error_code, AiError, BDEResult, BDError};
+use crate::metrics::Metrics;
 
-pub fn get_database_connection() -> BDEResult<Connection> {
-    let database_path = init_database()?;
-    Ok(Connection::open(database_path)?)
+// 连接池最多的连接数, WAL 模式下读可以并发, 写仍然是串行的
+const POOL_MAX_SIZE: u32 = 8;
+// 数据库被其他连接锁住时最多等待的时间
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type DatabaseConnection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+/// 连接池使用的 sqlite 连接, 每个新连接都打开 WAL 和外键约束
+#[derive(Debug)]
+pub struct SqliteConnectionManager {
+    path: PathBuf,
 }
 
-fn init_database() -> BDEResult<PathBuf> {
-    let data_path = PathBuf::from("./data");
+impl r2d2::ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let conn = Connection::open(&self.path)?;
+
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        conn.execute_batch("")
+    }
 
-    if !data_path.exists() {
-        fs::create_dir(data_path.clone())?;
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
     }
+}
 
-    let database_path = data_path.join("data.db");
+/// 启动时创建一次的连接池, 通过 `SqliteStorage` 放在 `AppState` 里
+#[derive(Debug, Clone)]
+pub struct Database {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
 
-    if !database_path.exists() {
-        let conn = Connection::open(&database_path)?;
+impl Database {
+    /// 打开 `path` 的数据库, 第一次运行时建表, 然后执行还没有执行过的迁移
+    pub fn open(path: &Path) -> BDEResult<Self> {
+        init_database(path)?;
 
-        let create_table_sql = fs::read_to_string("./sql/create_table.sql")?;
-        let tables_sql = create_table_sql.split(';');
-        for table_sql in tables_sql {
-            if !table_sql.trim().is_empty() {
-                conn.execute(table_sql, ())?;
-            }
+        let pool =
+            r2d2::Pool::builder()
+                .max_size(POOL_MAX_SIZE)
+                .build(SqliteConnectionManager {
+                    path: path.to_path_buf(),
+                })?;
+
+        migrate_database(&mut *pool.get()?)?;
+
+        Ok(Database { pool })
+    }
+
+    pub fn get(&self) -> BDEResult<DatabaseConnection> {
+        Ok(self.pool.get()?)
+    }
+}
+
+/// 在阻塞线程池里执行数据库操作, 慢的磁盘 IO 不会卡住 websocket 等异步任务
+///
+/// 错误不能跨线程传递, 会转换成带错误码的 `AiError`
+pub async fn run_blocking<T, F>(f: F) -> BDEResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> BDEResult<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let res = f().map_err(|err| AiError::with_code(&err.to_string(), error_code(&err)));
+
+        Metrics::global()
+            .db_operation_duration
+            .observe(&[], start.elapsed().as_secs_f64());
+
+        res
+    })
+    .await?
+    .map_err(|err| Box::new(err) as BDError)
+}
+
+/// 违反了唯一约束, 例如并发注册了同名的用户
+pub fn is_unique_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(err, _)
+            if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+    )
+}
+
+/// 数据库文件 `./data/data.db`
+pub fn database_path() -> PathBuf {
+    PathBuf::from("./data").join("data.db")
+}
+
+fn init_database(path: &Path) -> BDEResult<()> {
+    if let Some(data_path) = path.parent() {
+        fs::create_dir_all(data_path)?;
+    }
+
+    if !path.exists() {
+        create_tables(&Connection::open(path)?)?;
+    }
+
+    Ok(())
+}
+
+/// 建立第一个版本的表, 之后的修改都在迁移里
+pub fn create_tables(conn: &Connection) -> BDEResult<()> {
+    let create_table_sql = fs::read_to_string("./sql/create_table.sql")?;
+    let tables_sql = create_table_sql.split(';');
+    for table_sql in tables_sql {
+        if !table_sql.trim().is_empty() {
+            conn.execute(table_sql, ())?;
         }
     }
 
-    Ok(database_path)
+    Ok(())
+}
+
+// 按顺序执行的数据库迁移, 数据库的 user_version 记录已经执行过几个
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../sql/migrations/001_clips.sql"),
+    include_str!("../../sql/migrations/002_clips_fts.sql"),
+    include_str!("../../sql/migrations/003_retention.sql"),
+    include_str!("../../sql/migrations/004_clip_flags.sql"),
+    include_str!("../../sql/migrations/005_sensitive.sql"),
+    include_str!("../../sql/migrations/006_device_filters.sql"),
+    include_str!("../../sql/migrations/007_clip_targets.sql"),
+    include_str!("../../sql/migrations/008_groups.sql"),
+    include_str!("../../sql/migrations/009_channels.sql"),
+    include_str!("../../sql/migrations/010_dedup.sql"),
+    include_str!("../../sql/migrations/011_server_order.sql"),
+    include_str!("../../sql/migrations/012_unique_names.sql"),
+    include_str!("../../sql/migrations/013_import_sequence.sql"),
+];
+
+/// 执行还没有执行过的迁移, 每个迁移在一个事务里
+pub fn migrate_database(conn: &mut Connection) -> BDEResult<()> {
+    migrate_database_to(conn, MIGRATIONS.len())
+}
+
+/// 只迁移到第 `target` 个版本, 已经更新的数据库不会回退
+pub fn migrate_database_to(conn: &mut Connection, target: usize) -> BDEResult<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .take(target)
+        .skip(version as usize)
+    {
+        tracing::info!("migrate database to version {}", index + 1);
+
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", (index + 1) as i64)?;
+        tx.commit()?;
+    }
+
+    Ok(())
 }
 
 pub fn generate_insert_sql(table_name: &str, args_n: u16) -> String {
@@ -46,13 +188,11 @@ pub fn generate_insert_sql(table_name: &str, args_n: u16) -> String {
 }
 
 pub fn database_insert<T: Params>(
+    conn: &Connection,
     table_name: &str,
     keywords: Vec<&str>,
     params: T,
 ) -> BDEResult<u64> {
-    let database_path = init_database()?;
-    let conn = Connection::open(database_path)?;
-
     let args_str_vec: Vec<&str> = vec!["?"; keywords.len()];
     let sql_command = format!(
         "INSERT INTO {} ({}) VALUES ({})",
@@ -63,29 +203,16 @@ pub fn database_insert<T: Params>(
 
     conn.execute(sql_command.as_str(), params)?;
 
-    let mut stmt = conn.prepare(
-        format!(
-            "select seq from sqlite_sequence where name='{}';",
-            table_name
-        )
-        .as_str(),
-    )?;
-
-    let mut data_iter = serde_rusqlite::from_rows::<u64>(stmt.query([])?);
-
-    let id = data_iter.next().unwrap()?;
-
-    Ok(id)
+    // 同一个连接上最后插入的 id, 其他连接同时插入也不会影响
+    Ok(conn.last_insert_rowid() as u64)
 }
 
 pub fn database_insert_no_id<T: Params>(
+    conn: &Connection,
     table_name: &str,
     keywords: Vec<&str>,
     params: T,
 ) -> BDEResult<()> {
-    let database_path = init_database()?;
-    let conn = Connection::open(database_path)?;
-
     let args_str_vec: Vec<&str> = vec!["?"; keywords.len()];
     let sql_command = format!(
         "INSERT INTO {} ({}) VALUES ({})",
@@ -100,12 +227,11 @@ pub fn database_insert_no_id<T: Params>(
 }
 
 pub fn database_select<T: serde::de::DeserializeOwned>(
+    conn: &Connection,
     table_name: &str,
     where_args: Option<String>,
 ) -> BDEResult<Vec<T>> {
     let mut all_data: Vec<T> = Vec::new();
-    let database_path = init_database()?;
-    let conn = Connection::open(database_path)?;
 
     let sql_command = format!(
         "SELECT * FROM {} {}",
@@ -132,33 +258,33 @@ pub fn database_select<T: serde::de::DeserializeOwned>(
 }
 
 pub fn database_select_single_name<T: serde::de::DeserializeOwned>(
+    conn: &Connection,
     table_name: &str,
     item_id: u64,
     primary_key_name: &str,
 ) -> BDEResult<Option<T>> {
     let where_args = format!("{} == {}", primary_key_name, item_id);
-    let data_iter = database_select::<T>(table_name, Some(where_args))?;
+    let data_iter = database_select::<T>(conn, table_name, Some(where_args))?;
 
     Ok(data_iter.into_iter().next())
 }
 
 pub fn database_select_single<T: serde::de::DeserializeOwned>(
+    conn: &Connection,
     table_name: &str,
     item_id: u64,
 ) -> BDEResult<Option<T>> {
-    let res = database_select_single_name(table_name, item_id, "id")?;
+    let res = database_select_single_name(conn, table_name, item_id, "id")?;
     Ok(res)
 }
 
 pub fn database_update<T: Params>(
+    conn: &Connection,
     table_name: &str,
     set_keywords: Vec<&str>,
     set_params: T,
     where_args: String,
 ) -> BDEResult<()> {
-    let database_path = init_database()?;
-    let conn = Connection::open(database_path)?;
-
     let keywords = set_keywords
         .into_iter()
         .map(|keyword| format!("{} = ?", keyword))
@@ -176,14 +302,12 @@ pub fn database_update<T: Params>(
 }
 
 pub fn database_update_single_set_where<P: ToSql>(
+    conn: &Connection,
     table_name: &str,
     keyword: &str,
     item_id: u64,
     data: P,
 ) -> BDEResult<()> {
-    let database_path = init_database()?;
-    let conn = Connection::open(database_path)?;
-
     let sql_command = format!("UPDATE {} SET {} = ?1 WHERE id = ?2", table_name, keyword);
 
     conn.execute(sql_command.as_str(), (data, item_id))?;
@@ -191,10 +315,7 @@ pub fn database_update_single_set_where<P: ToSql>(
     Ok(())
 }
 
-pub fn database_delete(table_name: &str, where_args: String) -> BDEResult<()> {
-    let database_path = init_database()?;
-    let conn = Connection::open(database_path)?;
-
+pub fn database_delete(conn: &Connection, table_name: &str, where_args: String) -> BDEResult<()> {
     let sql_command = format!("DELETE FROM {} WHERE {}", table_name, where_args);
 
     conn.execute(sql_command.as_str(), ())?;
@@ -1,4 +1,6 @@
 use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
 use tokio::sync::{broadcast, mpsc, Mutex};
 
 pub mod database;
@@ -10,17 +12,38 @@ pub type ArcBroadcastSender<T> = Arc<broadcast::Sender<T>>;
 pub type BDError = Box<dyn std::error::Error>;
 pub type BDEResult<T> = Result<T, BDError>;
 
+// 接口返回给客户端的错误码, 一般的错误都是 401
+pub const ERROR_CODE: i16 = 401;
+// 名字已经被占用
+pub const CONFLICT_CODE: i16 = 409;
+// 请求或者剪切板内容太大
+pub const PAYLOAD_TOO_LARGE_CODE: i16 = 413;
+// 请求太频繁
+pub const TOO_MANY_REQUESTS_CODE: i16 = 429;
+// 数据库不能访问或者服务器正在关闭
+pub const UNAVAILABLE_CODE: i16 = 503;
+
 #[derive(Debug, Clone)]
 pub struct AiError {
     err: String,
+    code: i16,
 }
 
 impl AiError {
     pub fn new(err: &str) -> AiError {
+        AiError::with_code(err, ERROR_CODE)
+    }
+
+    pub fn with_code(err: &str, code: i16) -> AiError {
         AiError {
             err: err.to_string(),
+            code,
         }
     }
+
+    pub fn code(&self) -> i16 {
+        self.code
+    }
 }
 
 impl std::fmt::Display for AiError {
@@ -44,6 +67,33 @@ pub fn ba_error(error: &str) -> Box<dyn std::error::Error> {
     Box::new(AiError::new(error))
 }
 
+pub fn conflict_error(error: &str) -> Box<dyn std::error::Error> {
+    Box::new(AiError::with_code(error, CONFLICT_CODE))
+}
+
+pub fn too_large_error(error: &str) -> Box<dyn std::error::Error> {
+    Box::new(AiError::with_code(error, PAYLOAD_TOO_LARGE_CODE))
+}
+
+pub fn rate_limit_error(error: &str) -> Box<dyn std::error::Error> {
+    Box::new(AiError::with_code(error, TOO_MANY_REQUESTS_CODE))
+}
+
+pub fn unavailable_error(error: &str) -> Box<dyn std::error::Error> {
+    Box::new(AiError::with_code(error, UNAVAILABLE_CODE))
+}
+
+/// 返回给客户端的错误码
+pub fn error_code(error: &BDError) -> i16 {
+    error
+        .downcast_ref::<AiError>()
+        .map_or(ERROR_CODE, |error| error.code())
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
 pub fn arc_mutex<T>(data: T) -> ArcMutex<T> {
     Arc::new(Mutex::new(data))
 }
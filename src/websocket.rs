@@ -1,143 +1,536 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
 use std::net::SocketAddr;
 use std::ops::ControlFlow;
-
-use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
-    extract::connect_info::ConnectInfo, extract::ws, extract::State, response::IntoResponse,
+    extract::connect_info::ConnectInfo, extract::ws, extract::State, http::StatusCode,
+    response::IntoResponse, response::Response, Extension,
+};
+use chrono::Utc;
+use connect_any_protocol::{
+    close_code, negotiate_version, ErrorCode, WsMessage, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use futures::{sink::SinkExt, stream::SplitSink, stream::StreamExt};
+use tokio::sync::{
+    broadcast::{
+        self,
+        error::{RecvError, TryRecvError},
+    },
+    mpsc, watch,
 };
-use futures::{sink::SinkExt, stream::StreamExt};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
 
-use crate::datalayer::clipboard::Clipboard;
-use crate::datalayer::{InputDevice, User};
+use crate::datalayer::channel::history_channels;
+use crate::datalayer::clipboard::{find_clipboards, ClipFilter};
+use crate::datalayer::group::member_role;
+use crate::datalayer::{Device, InputDevice, User};
+use crate::utils::database::run_blocking;
 
-use crate::state::{AppState, ClipboardData};
+use crate::metrics::{record_clip, Metrics};
+use crate::state::{AppState, ClipboardData, WsBroadcast, WsSession};
+use crate::tls::ClientCertificate;
 
-use crate::utils::{ArcBroadcastSender, ArcMutex, BDEResult};
+use crate::utils::{error_code, ArcBroadcastSender, BDError, PAYLOAD_TOO_LARGE_CODE};
 
-#[derive(Deserialize)]
-pub struct WsInitMessage {
-    device: InputDevice,
-    #[serde(rename = "type")]
-    message_type: String,
-}
+// history_request 默认和最多返回的条数
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+const MAX_HISTORY_LIMIT: usize = 100;
+// 服务器主动关闭连接时, 最多等这么久让 close 帧发出去
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub async fn ws_handler(
     ws: ws::WebSocketUpgrade,
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
+    cert: Option<Extension<ClientCertificate>>,
+) -> Response {
     tracing::info!("ws: {addr} connected.");
+    if state.is_shutting_down() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "server is shutting down").into_response();
+    }
+    // 没有启用 tls 时没有客户端证书
+    let cert = cert.map(|Extension(cert)| cert).unwrap_or_default();
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| {
-        handle_socket(
-            socket,
-            addr,
-            state.clipboard_datas.clone(),
-            state.client_n.clone(),
-        )
-    })
+    ws.max_message_size(state.config.limits.max_ws_message_bytes)
+        .on_upgrade(move |socket| handle_socket(socket, addr, state, cert))
+        .into_response()
+}
+
+struct Session {
+    user: User,
+    device: Device,
+    protocol_version: u32,
+    // 除了个人剪切板以外订阅的群组
+    groups: Vec<u64>,
 }
 
-async fn get_ws_tx(
-    user_id: u64,
-    clipboard_data_arc: ArcMutex<HashMap<u64, ClipboardData>>,
-) -> ArcBroadcastSender<Clipboard> {
-    let mut clipboard_datas = clipboard_data_arc.lock().await;
+struct HandshakeError {
+    close_code: u16,
+    error_code: ErrorCode,
+    message: String,
+}
+
+impl HandshakeError {
+    fn new(close_code: u16, error_code: ErrorCode, message: &str) -> Self {
+        HandshakeError {
+            close_code,
+            error_code,
+            message: message.to_string(),
+        }
+    }
+}
+
+async fn get_ws_tx(user_id: u64, state: &AppState) -> ArcBroadcastSender<WsBroadcast> {
+    let mut clipboard_datas = state.clipboard_datas.lock().await;
     let clipboard_data = clipboard_datas
         .entry(user_id)
         .or_insert(ClipboardData::new());
     clipboard_data.ws_tx.clone()
 }
 
-fn process_init_message(msg: ws::Message) -> BDEResult<u64> {
-    if let ws::Message::Text(text) = msg {
-        tracing::info!("init message: {}", text);
-        let data: WsInitMessage = serde_json::from_str(&text)?;
+async fn get_group_ws_tx(group_id: u64, state: &AppState) -> ArcBroadcastSender<WsBroadcast> {
+    let mut group_datas = state.group_datas.lock().await;
+    let group_data = group_datas.entry(group_id).or_insert(ClipboardData::new());
+    group_data.ws_tx.clone()
+}
+
+fn record_lagged(scope: &str, n: u64) {
+    let metrics = Metrics::global();
 
-        if data.message_type == "init" {
-            if let Ok(device) = data.device.parse() {
-                if let Ok(user) = User::find_user_from_device(&device) {
-                    return Ok(user.id);
+    metrics.broadcast_lagged.inc(&[("scope", scope)]);
+    metrics
+        .broadcast_lagged_messages
+        .add(&[("scope", scope)], n as f64);
+}
+
+/// 把群组广播的消息转发到这个连接, 连接关闭前把已经收到的消息转发完再退出
+fn forward_group_messages(
+    mut group_rx: broadcast::Receiver<WsBroadcast>,
+    group_tx: mpsc::Sender<WsBroadcast>,
+    mut closing: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                msg = group_rx.recv() => msg,
+                _ = closing.changed() => {
+                    loop {
+                        match group_rx.try_recv() {
+                            Ok(msg) => {
+                                if group_tx.send(msg).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(TryRecvError::Lagged(n)) => record_lagged("group", n),
+                            Err(_) => break,
+                        }
+                    }
+                    break;
+                }
+            };
+
+            match msg {
+                Ok(msg) => {
+                    if group_tx.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("group websocket lagged {} messages", n);
+                    record_lagged("group", n);
                 }
+                Err(RecvError::Closed) => break,
             }
         }
+    })
+}
+
+/// 判断广播的消息是否需要推送给这个设备
+fn accept_broadcast(msg: WsBroadcast, device_id: u64, block_sensitive: bool) -> Option<WsMessage> {
+    if msg.from_device == Some(device_id) {
+        return None;
+    }
+
+    if msg
+        .recipients
+        .as_ref()
+        .is_some_and(|recipients| !recipients.contains(&device_id))
+    {
+        return None;
+    }
+
+    // 敏感内容不推送给配置里屏蔽的设备类型
+    if block_sensitive && matches!(&msg.message, WsMessage::ClipPush { clip, .. } if clip.sensitive)
+    {
+        return None;
+    }
+
+    Some(msg.message)
+}
+
+async fn set_online(state: &AppState, session: &Session, online: bool) {
+    let mut clipboard_datas = state.clipboard_datas.lock().await;
+    let clipboard_data = clipboard_datas
+        .entry(session.user.id)
+        .or_insert(ClipboardData::new());
+
+    clipboard_data.set_online(session.device.id, online);
+
+    let presence = clipboard_data.presence(&session.user.devices);
+    clipboard_data.broadcast(None, presence);
+}
+
+fn process_hello_message(
+    state: &AppState,
+    cert: &ClientCertificate,
+    msg: ws::Message,
+) -> Result<Session, HandshakeError> {
+    let ws::Message::Text(text) = msg else {
+        return Err(HandshakeError::new(
+            close_code::PROTOCOL_ERROR,
+            ErrorCode::BadRequest,
+            "first message must be hello",
+        ));
+    };
+
+    tracing::info!("hello message: {}", text);
+
+    let Ok(WsMessage::Hello {
+        protocol_version,
+        min_protocol_version,
+        device,
+        groups,
+    }) = serde_json::from_str::<WsMessage>(&text)
+    else {
+        return Err(HandshakeError::new(
+            close_code::PROTOCOL_ERROR,
+            ErrorCode::BadRequest,
+            "first message must be hello",
+        ));
+    };
+
+    let Some(protocol_version) = negotiate_version(
+        min_protocol_version.unwrap_or(protocol_version),
+        protocol_version,
+    ) else {
+        return Err(HandshakeError::new(
+            close_code::UNSUPPORTED_VERSION,
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "unsupported protocol version, server supports {}-{}",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )
+            .as_str(),
+        ));
+    };
+
+    let unauthorized = |err: BDError| {
+        HandshakeError::new(
+            close_code::UNAUTHORIZED,
+            ErrorCode::Unauthorized,
+            err.to_string().as_str(),
+        )
+    };
+
+    // 使用了客户端证书时只能以证书对应的设备连接
+    cert.check(&state.config.tls, &device.name, &device.device_type)
+        .map_err(unauthorized)?;
+
+    let storage = state.storage.as_ref();
+    let device = InputDevice::from(device)
+        .parse(storage)
+        .map_err(unauthorized)?;
+    let user = User::find_user_from_device(storage, &device).map_err(unauthorized)?;
+
+    // 只能订阅自己所在的群组
+    for group_id in groups.iter() {
+        member_role(storage, *group_id, user.id).map_err(unauthorized)?;
+    }
+
+    Ok(Session {
+        user,
+        device,
+        protocol_version,
+        groups,
+    })
+}
+
+fn close_reason(code: u16) -> &'static str {
+    match code {
+        close_code::MESSAGE_TOO_BIG => "message too big",
+        close_code::RATE_LIMITED => "too many messages",
+        close_code::GOING_AWAY => "server going away",
+        _ => "disconnected by server",
+    }
+}
+
+// 消息超过了 `max_message_size`
+fn is_message_too_big(err: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if let Some(tungstenite::Error::Capacity(_)) = err.downcast_ref::<tungstenite::Error>() {
+            return true;
+        }
+        source = err.source();
     }
 
-    Err("init message error".into())
+    false
+}
+
+async fn send_ws_message(
+    socket: &mut ws::WebSocket,
+    message: &WsMessage,
+) -> Result<(), axum::Error> {
+    let data = serde_json::to_string(message).unwrap();
+    socket.send(ws::Message::Text(data)).await
+}
+
+// 连接建立后发送消息, 推送的剪切板计入指标
+async fn send_client_message(
+    sender: &mut SplitSink<ws::WebSocket, ws::Message>,
+    message: &WsMessage,
+) -> Result<(), axum::Error> {
+    let data = serde_json::to_string(message).unwrap();
+    sender.send(ws::Message::Text(data)).await?;
+
+    if let WsMessage::ClipPush { clip, .. } = message {
+        record_clip(&Metrics::global().clips_delivered, clip, "/ws");
+    }
+
+    Ok(())
+}
+
+async fn reject_socket(mut socket: ws::WebSocket, who: SocketAddr, err: HandshakeError) {
+    tracing::error!("client {who} disconnectd: {}", err.message);
+
+    let _ = send_ws_message(&mut socket, &WsMessage::error(err.error_code, &err.message)).await;
+    let _ = socket
+        .send(ws::Message::Close(Some(ws::CloseFrame {
+            code: err.close_code,
+            reason: Cow::from(err.message),
+        })))
+        .await;
 }
 
 async fn handle_socket(
     mut socket: ws::WebSocket,
     who: SocketAddr,
-    clipboard_data: ArcMutex<HashMap<u64, ClipboardData>>,
-    client_n: ArcMutex<u8>,
+    state: AppState,
+    cert: ClientCertificate,
 ) {
     // 建立链接, 将 ip 和 device id 对上号, 找到这个对应的 user, 如果发现有问题, 就断开链接, 返回错误信息
     // 让后等着接收消息, 如果接收到消息, 就将消息发送到对应的 user 的 ws 通道里面去
 
-    // 接受初始化消息
-
-    let user_id = if let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            match process_init_message(msg) {
-                Ok(user_id) => user_id,
+    // 接受 hello 消息, 协商协议版本
+    let hello_state = state.clone();
+    let session = match socket.recv().await {
+        // 认证要查数据库, 放到阻塞线程里执行
+        Some(Ok(msg)) => {
+            match tokio::task::spawn_blocking(move || {
+                process_hello_message(&hello_state, &cert, msg)
+            })
+            .await
+            .unwrap_or_else(|err| {
+                Err(HandshakeError::new(
+                    close_code::PROTOCOL_ERROR,
+                    ErrorCode::Internal,
+                    err.to_string().as_str(),
+                ))
+            }) {
+                Ok(session) => session,
                 Err(err) => {
-                    tracing::error!("client {who} disconnectd: {err}");
+                    reject_socket(socket, who, err).await;
                     return;
                 }
             }
-        } else {
+        }
+        _ => {
             tracing::error!("client {who} disconnectd");
             return;
         }
-    } else {
-        tracing::error!("client {who} disconnectd");
-        return;
     };
 
-    let ws_tx = get_ws_tx(user_id, clipboard_data.clone()).await;
+    let welcome = WsMessage::Welcome {
+        protocol_version: session.protocol_version,
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    if let Err(err) = send_ws_message(&mut socket, &welcome).await {
+        tracing::error!("client {who} disconnectd: {err}");
+        return;
+    }
+
+    tracing::info!(
+        "client {who} is device ({}) with protocol version {}",
+        session.device.name,
+        session.protocol_version
+    );
+
+    let session = Arc::new(session);
+
+    // 订阅的群组的消息
+    let (group_tx, mut group_rx) = mpsc::channel::<WsBroadcast>(16);
+    let (closing_tx, closing_rx) = watch::channel(false);
+    let mut group_tasks = Vec::new();
+    for group_id in session.groups.iter() {
+        let group_ws_tx = get_group_ws_tx(*group_id, &state).await;
+        group_tasks.push((
+            *group_id,
+            forward_group_messages(
+                group_ws_tx.subscribe(),
+                group_tx.clone(),
+                closing_rx.clone(),
+            ),
+        ));
+    }
+    drop(group_tx);
+
+    // 登记连接, 管理员可以通过 close_tx 强制断开
+    let session_id = Uuid::now_v7().to_string();
+    let (close_tx, mut close_rx) = mpsc::channel::<u16>(1);
+    let recv_close_tx = close_tx.clone();
+    state.ws_sessions.lock().await.insert(
+        session_id.clone(),
+        WsSession {
+            user_id: session.user.id,
+            device_id: session.device.id,
+            addr: who,
+            connected_at: Utc::now().timestamp_millis() as u64,
+            close_tx,
+            groups: group_tasks
+                .iter()
+                .map(|(group_id, task)| (*group_id, task.abort_handle()))
+                .collect(),
+        },
+    );
+
+    // 在开始关闭之后才登记的连接不会被 `begin_shutdown` 关闭
+    if state.is_shutting_down() {
+        let _ = recv_close_tx.try_send(close_code::GOING_AWAY);
+    }
+
+    let ws_tx = get_ws_tx(session.user.id, &state).await;
 
     let (mut sender, mut receiver) = socket.split();
 
     let mut ws_rx = ws_tx.subscribe();
 
+    // 直接回复给这个连接的消息 (ack, history 等)
+    let (reply_tx, mut reply_rx) = mpsc::channel::<WsMessage>(16);
+
+    let device_id = session.device.id;
+    let block_sensitive = state
+        .config
+        .sensitive
+        .block_device_types
+        .contains(&session.device.device_type);
     let mut send_ws_msg = tokio::spawn(async move {
-        while let Ok(msg) = ws_rx.recv().await {
-            let data = serde_json::to_string(&msg).unwrap();
+        loop {
+            let message = tokio::select! {
+                msg = ws_rx.recv() => match msg {
+                    Ok(msg) => match accept_broadcast(msg, device_id, block_sensitive) {
+                        Some(msg) => msg,
+                        None => continue,
+                    },
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("websocket lagged {} messages", n);
+                        record_lagged("user", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                msg = reply_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                // 没有订阅群组时 channel 已经关闭, 这个分支会被禁用
+                Some(msg) = group_rx.recv() => match accept_broadcast(msg, device_id, block_sensitive) {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                Some(code) = close_rx.recv() => {
+                    // 先把已经排队的消息发出去
+                    let _ = closing_tx.send(true);
+                    let mut pending = Vec::new();
+                    while let Ok(msg) = reply_rx.try_recv() {
+                        pending.push(msg);
+                    }
+                    loop {
+                        match ws_rx.try_recv() {
+                            Ok(msg) => {
+                                pending.extend(accept_broadcast(msg, device_id, block_sensitive));
+                            }
+                            Err(TryRecvError::Lagged(n)) => record_lagged("user", n),
+                            Err(_) => break,
+                        }
+                    }
+                    // 群组转发的任务转发完已经收到的消息后退出
+                    while let Some(msg) = group_rx.recv().await {
+                        pending.extend(accept_broadcast(msg, device_id, block_sensitive));
+                    }
+                    for message in pending {
+                        if send_client_message(&mut sender, &message).await.is_err() {
+                            break;
+                        }
+                    }
 
-            match sender.send(ws::Message::Text(data)).await {
-                Ok(()) => {}
-                Err(err) => {
-                    tracing::error!(
-                        "websocket send message error: {} with {}",
-                        err.to_string(),
-                        msg.data
-                    );
-                }
+                    let _ = sender
+                        .send(ws::Message::Close(Some(ws::CloseFrame {
+                            code,
+                            reason: Cow::from(close_reason(code)),
+                        })))
+                        .await;
+                    break;
+                },
+            };
+
+            if let Err(err) = send_client_message(&mut sender, &message).await {
+                tracing::error!("websocket send message error: {}", err.to_string());
+                break;
             }
         }
     });
 
-    // This second task will receive messages from client and print them on server console
+    set_online(&state, &session, true).await;
+
+    // This second task will receive messages from client and process them
+    let recv_state = state.clone();
+    let recv_session = session.clone();
     let mut recv_task = tokio::spawn(async move {
         let mut cnt = 0;
-        while let Some(Ok(msg)) = receiver.next().await {
-            cnt += 1;
-            // print message and break if instructed to do so
-            if process_message(msg, who).await.is_break() {
-                break;
+        let close = loop {
+            match receiver.next().await {
+                Some(Ok(msg)) => {
+                    cnt += 1;
+                    if let ControlFlow::Break(close) =
+                        process_message(msg, who, &recv_session, &recv_state, &reply_tx).await
+                    {
+                        break close;
+                    }
+                }
+                Some(Err(err)) => {
+                    tracing::error!("client {who} receive error: {err}");
+                    break is_message_too_big(&err).then_some(close_code::MESSAGE_TOO_BIG);
+                }
+                None => break None,
             }
+        };
+
+        // 由发送的任务发出 close 帧
+        if let Some(code) = close {
+            let _ = recv_close_tx.send(code).await;
         }
-        cnt
+
+        (cnt, close.is_some())
     });
 
-    {
-        let mut client_n = client_n.lock().await;
-        *client_n += 1;
-    }
+    let device_type = session.device.device_type.to_string();
+    Metrics::global()
+        .websocket_connections
+        .inc(&[("device_type", &device_type)]);
 
     tokio::select! {
         _ = (&mut send_ws_msg) => {
@@ -145,26 +538,204 @@ async fn handle_socket(
         },
         rv_r = (&mut recv_task) => {
             match rv_r {
-                Ok(r) => tracing::info!("Received {r} messages"),
+                Ok((r, closing)) => {
+                    tracing::info!("Received {r} messages");
+                    if closing {
+                        let _ = tokio::time::timeout(CLOSE_TIMEOUT, &mut send_ws_msg).await;
+                    }
+                },
                 Err(r) => tracing::info!("Error receiving messages {r:?}")
             }
             send_ws_msg.abort();
         },
     }
 
-    {
-        let mut client_n = client_n.lock().await;
-        *client_n -= 1;
+    for (_, group_task) in group_tasks {
+        group_task.abort();
     }
 
+    state.ws_sessions.lock().await.remove(&session_id);
+
+    Metrics::global()
+        .websocket_connections
+        .dec(&[("device_type", &device_type)]);
+
+    set_online(&state, &session, false).await;
+
     // returning from the handler closes the websocket connection
     tracing::info!("Websocket context {who} destroyed");
 }
 
-async fn process_message(msg: ws::Message, who: SocketAddr) -> ControlFlow<(), ()> {
+async fn process_ws_message(
+    message: WsMessage,
+    session: &Session,
+    state: &AppState,
+) -> Option<WsMessage> {
+    match message {
+        WsMessage::ClipPush { id, clip } => {
+            let user = match state.find_user(&session.device).await {
+                Ok(user) => user,
+                Err(err) => {
+                    return Some(WsMessage::error(
+                        ErrorCode::Unauthorized,
+                        err.to_string().as_str(),
+                    ))
+                }
+            };
+
+            match state.add_clipboard(user, &session.device, clip).await {
+                Ok(clip) => {
+                    tracing::info!("device ({}) push message: {}", session.device.name, clip);
+                    record_clip(&Metrics::global().clips_received, &clip, "/ws");
+
+                    Some(WsMessage::ClipAck {
+                        id,
+                        clip_id: Some(clip.id),
+                        seq: Some(clip.seq),
+                    })
+                }
+                Err(err) => {
+                    let code = match error_code(&err) {
+                        PAYLOAD_TOO_LARGE_CODE => ErrorCode::PayloadTooLarge,
+                        _ => ErrorCode::Internal,
+                    };
+                    Some(WsMessage::error(code, err.to_string().as_str()))
+                }
+            }
+        }
+        WsMessage::ClipAck { id, .. } => {
+            tracing::debug!("device ({}) ack clip {}", session.device.name, id);
+            None
+        }
+        WsMessage::HistoryRequest {
+            limit,
+            before,
+            group_id,
+            channel,
+        } => {
+            let user_id = session.user.id;
+            let device_id = session.device.id;
+            let storage = state.storage.clone();
+            let channels = match group_id {
+                Some(group_id) => {
+                    let storage = storage.clone();
+                    match run_blocking(move || member_role(storage.as_ref(), group_id, user_id))
+                        .await
+                    {
+                        Ok(_) => None,
+                        Err(err) => {
+                            return Some(WsMessage::error(
+                                ErrorCode::Unauthorized,
+                                err.to_string().as_str(),
+                            ))
+                        }
+                    }
+                }
+                None => {
+                    let storage = storage.clone();
+                    match run_blocking(move || {
+                        history_channels(storage.as_ref(), device_id, channel)
+                    })
+                    .await
+                    {
+                        Ok(channels) => Some(channels),
+                        Err(err) => {
+                            return Some(WsMessage::error(
+                                ErrorCode::Internal,
+                                err.to_string().as_str(),
+                            ))
+                        }
+                    }
+                }
+            };
+
+            let filter = ClipFilter {
+                before,
+                group_id,
+                channels,
+                limit: limit
+                    .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                    .clamp(1, MAX_HISTORY_LIMIT),
+                ..Default::default()
+            };
+
+            match run_blocking(move || find_clipboards(storage.as_ref(), user_id, &filter)).await {
+                Ok(page) => Some(WsMessage::HistoryResponse {
+                    clips: page.clips,
+                    next_cursor: page.next_cursor,
+                }),
+                Err(err) => Some(WsMessage::error(
+                    ErrorCode::Internal,
+                    err.to_string().as_str(),
+                )),
+            }
+        }
+        WsMessage::ClipUpdate {
+            id,
+            pinned,
+            favorite,
+        } => match state
+            .update_clipboard(session.user.id, id, pinned, favorite)
+            .await
+        {
+            Ok(_) => None,
+            Err(err) => Some(WsMessage::error(
+                ErrorCode::BadRequest,
+                err.to_string().as_str(),
+            )),
+        },
+        WsMessage::ClipDelete { id } => {
+            tracing::info!("device ({}) delete message {}", session.device.name, id);
+
+            match state.delete_clipboard(session.user.id, id).await {
+                Ok(()) => None,
+                Err(err) => Some(WsMessage::error(
+                    ErrorCode::BadRequest,
+                    err.to_string().as_str(),
+                )),
+            }
+        }
+        _ => Some(WsMessage::error(
+            ErrorCode::BadRequest,
+            "unexpected message type",
+        )),
+    }
+}
+
+async fn process_message(
+    msg: ws::Message,
+    who: SocketAddr,
+    session: &Session,
+    state: &AppState,
+    reply_tx: &mpsc::Sender<WsMessage>,
+) -> ControlFlow<Option<u16>, ()> {
     match msg {
         ws::Message::Text(t) => {
-            tracing::info!(">>> {who} sent str: {t:?}");
+            let key = (
+                session.device.name.clone(),
+                session.device.device_type.to_string(),
+            );
+            if state.limiter.device.check(key).is_err() {
+                tracing::warn!(">>> {who} sent too many messages");
+                return ControlFlow::Break(Some(close_code::RATE_LIMITED));
+            }
+
+            let reply = match serde_json::from_str::<WsMessage>(&t) {
+                Ok(message) => process_ws_message(message, session, state).await,
+                Err(err) => {
+                    tracing::error!(">>> {who} sent invalid message: {err}");
+                    Some(WsMessage::error(
+                        ErrorCode::BadRequest,
+                        err.to_string().as_str(),
+                    ))
+                }
+            };
+
+            if let Some(reply) = reply {
+                if reply_tx.send(reply).await.is_err() {
+                    return ControlFlow::Break(None);
+                }
+            }
         }
         ws::Message::Binary(d) => {
             tracing::info!(">>> {} sent {} bytes: {:?}", who, d.len(), d);
@@ -180,7 +751,7 @@ async fn process_message(msg: ws::Message, who: SocketAddr) -> ControlFlow<(), (
             } else {
                 tracing::info!(">>> {who} somehow sent close message without CloseFrame");
             }
-            return ControlFlow::Break(());
+            return ControlFlow::Break(None);
         }
         _ => {}
     }
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, HeaderMap, HeaderValue, Request};
+use axum::routing::post;
+use axum::Router;
+use connect_any_server::admin::{authenticate, AdminAccount, AdminRole};
+use connect_any_server::api::admin;
+use connect_any_server::config::{AdminConfig, Config};
+use connect_any_server::datalayer::memory::MemoryStorage;
+use connect_any_server::datalayer::storage::Storage;
+use connect_any_server::datalayer::{DeviceType, User};
+use connect_any_server::state::AppState;
+use tower::ServiceExt;
+
+fn config() -> AdminConfig {
+    AdminConfig {
+        accounts: vec![
+            AdminAccount {
+                name: "root".to_string(),
+                token: "root-token".to_string(),
+                role: AdminRole::Admin,
+            },
+            AdminAccount {
+                name: "ops".to_string(),
+                token: "ops-token".to_string(),
+                role: AdminRole::Viewer,
+            },
+            // 没有设置 token 的账号不能登录
+            AdminAccount {
+                name: "empty".to_string(),
+                token: String::new(),
+                role: AdminRole::Admin,
+            },
+        ],
+    }
+}
+
+fn headers(authorization: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(authorization).unwrap(),
+    );
+    headers
+}
+
+#[test]
+fn checks_admin_token_and_role() {
+    let config = config();
+    let login = |headers: &HeaderMap, manage: bool| {
+        authenticate(&config, headers, manage)
+            .map(|account| account.name)
+            .map_err(|err| err.to_string())
+    };
+
+    assert_eq!(login(&headers("Bearer root-token"), true).unwrap(), "root");
+    assert_eq!(login(&headers("Bearer ops-token"), false).unwrap(), "ops");
+    assert_eq!(
+        login(&headers("Bearer ops-token"), true).unwrap_err(),
+        "no permission"
+    );
+
+    assert_eq!(
+        login(&HeaderMap::new(), false).unwrap_err(),
+        "admin token required"
+    );
+    assert_eq!(
+        login(&headers("root-token"), false).unwrap_err(),
+        "admin token required"
+    );
+    assert_eq!(
+        login(&headers("Bearer root"), false).unwrap_err(),
+        "invalid admin token"
+    );
+    assert_eq!(
+        login(&headers("Bearer "), false).unwrap_err(),
+        "invalid admin token"
+    );
+
+    // 没有配置账号时所有请求都拒绝
+    assert!(authenticate(
+        &AdminConfig::default(),
+        &headers("Bearer root-token"),
+        false
+    )
+    .is_err());
+}
+
+async fn reset_user(state: &AppState, user_id: u64, token: &str) -> String {
+    let app = Router::new()
+        .route("/admin/resetuser", post(admin::reset_user))
+        .with_state(state.clone());
+
+    let request = Request::post("/admin/resetuser")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::from(format!(r#"{{"user_id": {}}}"#, user_id)))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn viewer_cannot_reset_user() {
+    let storage = Arc::new(MemoryStorage::new());
+    let user = User::register(storage.as_ref(), "liz", "laptop", DeviceType::Linux, "").unwrap();
+
+    let mut config = Config::default();
+    config.admin = self::config();
+    let state = AppState::new(config, storage.clone());
+
+    for token in ["ops-token", "wrong-token"] {
+        assert!(reset_user(&state, user.id, token)
+            .await
+            .contains(r#""data":null"#));
+        assert_eq!(storage.get_user_devices(user.id).unwrap().len(), 1);
+    }
+
+    assert!(reset_user(&state, user.id, "root-token")
+        .await
+        .contains(r#""code":200"#));
+    assert!(storage.get_user_devices(user.id).unwrap().is_empty());
+}
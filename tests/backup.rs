@@ -0,0 +1,201 @@
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use connect_any_server::backup::{create_snapshot, list_snapshots, restore, run_backup};
+use connect_any_server::config::BackupConfig;
+use connect_any_server::datalayer::blob::read_blob;
+use connect_any_server::datalayer::clipboard::{
+    delete_clipboard, find_clipboards, save_clipboard, ClipFilter, Clipboard, ClipboardDataType,
+};
+use connect_any_server::datalayer::storage::SqliteStorage;
+use connect_any_server::datalayer::{Device, DeviceType, User};
+use connect_any_server::utils::database::Database;
+use connect_any_server::utils::sha256_hex;
+use uuid::Uuid;
+
+// 每个测试使用自己的目录, 数据库在 `data.db`, 快照在 `backups` 下
+fn test_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("connect-any-{}", Uuid::now_v7()))
+}
+
+fn open(dir: &Path) -> (Database, SqliteStorage) {
+    let database = Database::open(&dir.join("data.db")).unwrap();
+    let storage = SqliteStorage::new(database.clone());
+
+    (database, storage)
+}
+
+fn history(storage: &SqliteStorage, user_id: u64) -> Vec<String> {
+    find_clipboards(
+        storage,
+        user_id,
+        &ClipFilter {
+            limit: 20,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .clips
+    .into_iter()
+    .map(|clip| clip.data)
+    .collect()
+}
+
+fn save(
+    storage: &SqliteStorage,
+    user_id: u64,
+    device: &Device,
+    data: &str,
+    kind: ClipboardDataType,
+) {
+    save_clipboard(
+        storage,
+        user_id,
+        device,
+        Clipboard::new(data.to_string(), kind),
+    )
+    .unwrap();
+}
+
+#[test]
+fn restores_snapshot() {
+    let dir = test_dir();
+    let backups = dir.join("backups");
+    let (database, storage) = open(&dir);
+
+    let user = User::register(&storage, "liz", "laptop", DeviceType::Linux, "").unwrap();
+    let laptop = user.devices[0].clone();
+
+    // blob 目录是共用的, 用不会重复的内容
+    let image = format!("image-{}", Uuid::now_v7());
+    let blob = sha256_hex(image.as_bytes());
+    save(&storage, user.id, &laptop, "hello", ClipboardDataType::Text);
+    save(&storage, user.id, &laptop, &image, ClipboardDataType::Image);
+
+    let snapshot = create_snapshot(&database, &backups).unwrap();
+    assert_eq!(snapshot.blobs, 1);
+    assert!(snapshot.missing_blobs.is_empty());
+    assert!(Path::new(&snapshot.path).join("data.db").is_file());
+    assert!(Path::new(&snapshot.path)
+        .join("blobs")
+        .join(&blob)
+        .is_file());
+
+    // 快照之后的修改在恢复时被覆盖
+    let hello = find_clipboards(
+        &storage,
+        user.id,
+        &ClipFilter {
+            limit: 20,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .clips
+    .into_iter()
+    .find(|clip| clip.data == "hello")
+    .unwrap();
+    delete_clipboard(&storage, user.id, hello.id).unwrap();
+    save(&storage, user.id, &laptop, "after", ClipboardDataType::Text);
+    assert_eq!(
+        history(&storage, user.id),
+        vec!["after".to_string(), image.clone()]
+    );
+    fs::remove_file(Path::new("./data/blobs").join(&blob)).unwrap();
+
+    let report = restore(&database, Path::new(&snapshot.path), &backups).unwrap();
+    assert_eq!(report.blobs, 1);
+    assert_eq!(
+        history(&storage, user.id),
+        vec![image.clone(), "hello".to_string()]
+    );
+    assert_eq!(read_blob(&blob).unwrap(), image);
+
+    // 恢复之前的数据另外保存了一份
+    assert_eq!(list_snapshots(&backups).unwrap().len(), 2);
+    let (_, previous) = open(&PathBuf::from(&report.previous));
+    assert_eq!(
+        history(&previous, user.id),
+        vec!["after".to_string(), image]
+    );
+
+    fs::remove_file(Path::new("./data/blobs").join(&blob)).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_corrupted_backup() {
+    let dir = test_dir();
+    let backups = dir.join("backups");
+    let (database, storage) = open(&dir);
+
+    let user = User::register(&storage, "liz", "laptop", DeviceType::Linux, "").unwrap();
+    let laptop = user.devices[0].clone();
+    for index in 0..200 {
+        let data = format!("clip {} {}", index, "x".repeat(100));
+        save(&storage, user.id, &laptop, &data, ClipboardDataType::Text);
+    }
+
+    let snapshot = create_snapshot(&database, &backups).unwrap();
+    let corrupted = dir.join("corrupted.db");
+    fs::copy(Path::new(&snapshot.path).join("data.db"), &corrupted).unwrap();
+
+    // 文件头完整, 但是后面的页被覆盖了
+    let mut file = fs::File::options().write(true).open(&corrupted).unwrap();
+    file.seek(SeekFrom::Start(4096)).unwrap();
+    file.write_all(&[0xff; 16384]).unwrap();
+    drop(file);
+
+    assert!(restore(&database, &corrupted, &backups)
+        .unwrap_err()
+        .to_string()
+        .contains("backup database is corrupted"));
+    assert!(restore(&database, &dir.join("missing.db"), &backups)
+        .unwrap_err()
+        .to_string()
+        .contains("not found"));
+
+    // 检查失败时不会修改当前的数据, 也不会保存恢复之前的快照
+    assert_eq!(history(&storage, user.id).len(), 20);
+    assert_eq!(list_snapshots(&backups).unwrap().len(), 1);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rotates_old_snapshots() {
+    let dir = test_dir();
+    let (database, _) = open(&dir);
+    let config = BackupConfig {
+        dir: dir.join("backups"),
+        keep: 2,
+        ..Default::default()
+    };
+
+    // 没写完的快照不算, 也不会被删除
+    let tmp = config.dir.join("snapshot-00000000-000000-000.tmp");
+    fs::create_dir_all(&tmp).unwrap();
+
+    let mut paths = Vec::new();
+    for _ in 0..4 {
+        let snapshot = run_backup(&database, &config).unwrap();
+        assert!(snapshot.rotated <= 1);
+        paths.push(PathBuf::from(snapshot.path));
+
+        // 快照按毫秒命名
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    assert_eq!(list_snapshots(&config.dir).unwrap(), paths[2..].to_vec());
+    assert!(tmp.is_dir());
+
+    // 为 0 时不删除旧的快照
+    let config = BackupConfig { keep: 0, ..config };
+    assert_eq!(run_backup(&database, &config).unwrap().rotated, 0);
+    assert_eq!(list_snapshots(&config.dir).unwrap().len(), 3);
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use connect_any_server::datalayer::clipboard::{
+    dedup_clipboard, find_clipboards, save_clipboard, ClipFilter, Clipboard, ClipboardDataType,
+};
+use connect_any_server::datalayer::database::{DatabaseClip, IMPORT_SEQ_BASE};
+use connect_any_server::datalayer::storage::{SqliteStorage, Storage};
+use connect_any_server::datalayer::{DeviceType, User};
+use connect_any_server::utils::database::{
+    create_tables, migrate_database, migrate_database_to, Database,
+};
+use rusqlite::Connection;
+use uuid::Uuid;
+
+// 每个测试使用自己的数据库文件
+fn database_path() -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("connect-any-{}", Uuid::now_v7()))
+        .join("data.db")
+}
+
+#[test]
+fn sqlite_storage_uses_its_own_database() {
+    let path = database_path();
+    let storage = SqliteStorage::new(Database::open(&path).unwrap());
+
+    let user = User::register(&storage, "liz", "laptop", DeviceType::Linux, "").unwrap();
+    let laptop = user.devices[0].clone();
+
+    let clipboard = Clipboard::new("hello".to_string(), ClipboardDataType::Text);
+    save_clipboard(&storage, user.id, &laptop, clipboard).unwrap();
+
+    // 重新打开同一个文件可以读到之前的数据
+    let storage = SqliteStorage::new(Database::open(&path).unwrap());
+    storage.ping().unwrap();
+
+    let clips = find_clipboards(
+        &storage,
+        user.id,
+        &ClipFilter {
+            limit: 20,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .clips;
+    assert_eq!(clips.len(), 1);
+    assert_eq!(clips[0].data, "hello");
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+fn rows<T: rusqlite::types::FromSql>(conn: &Connection, sql: &str) -> Vec<(u64, T)> {
+    let mut stmt = conn.prepare(sql).unwrap();
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    rows
+}
+
+#[test]
+fn unique_names_migration_merges_duplicates() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    create_tables(&conn).unwrap();
+    // 添加唯一索引之前的版本
+    migrate_database_to(&mut conn, 11).unwrap();
+
+    conn.execute_batch(
+        "INSERT INTO users (id, name) VALUES (1, 'liz'), (2, 'liz'), (3, 'bob');
+        INSERT INTO devices (id, name, type) VALUES (1, 'laptop', 'Linux'), (2, 'laptop', 'Linux'), (3, 'phone', 'Android');
+        INSERT INTO user_device (user_id, device_id) VALUES (1, 1), (2, 2), (2, 3), (3, 3);
+        INSERT INTO clips (data, type, date, user_id, device_id, seq) VALUES ('hello', 'Text', 0, 2, 2, 1);
+        UPDATE clip_sequence SET value = 1;
+        INSERT INTO channels (user_id, name) VALUES (1, 'work'), (2, 'work'), (2, 'home');
+        INSERT INTO device_channels (device_id, channel) VALUES (1, 'work'), (2, 'work'), (2, 'home');",
+    )
+    .unwrap();
+
+    migrate_database(&mut conn).unwrap();
+
+    assert_eq!(
+        rows::<String>(&conn, "SELECT id, name FROM users ORDER BY id"),
+        vec![(1, "liz".to_string()), (3, "bob".to_string())]
+    );
+    assert_eq!(
+        rows::<String>(&conn, "SELECT id, name FROM devices ORDER BY id"),
+        vec![(1, "laptop".to_string()), (3, "phone".to_string())]
+    );
+    // phone 先注册到了合并后的 liz
+    assert_eq!(
+        rows::<u64>(
+            &conn,
+            "SELECT device_id, user_id FROM user_device ORDER BY device_id"
+        ),
+        vec![(1, 1), (3, 1)]
+    );
+    assert_eq!(
+        rows::<u64>(&conn, "SELECT user_id, device_id FROM clips"),
+        vec![(1, 1)]
+    );
+    // 已有的序号整体移到导入的序号上面, 新的剪切板接着分配
+    assert_eq!(
+        rows::<u64>(&conn, "SELECT id, seq FROM clips"),
+        vec![(1, IMPORT_SEQ_BASE + 1)]
+    );
+    assert_eq!(DatabaseClip::next_seq(&conn).unwrap(), IMPORT_SEQ_BASE + 2);
+    assert_eq!(
+        DatabaseClip::reserve_import_seqs(&conn, 2).unwrap(),
+        IMPORT_SEQ_BASE - 2
+    );
+    assert_eq!(
+        rows::<String>(&conn, "SELECT user_id, name FROM channels ORDER BY name"),
+        vec![(1, "home".to_string()), (1, "work".to_string())]
+    );
+    assert_eq!(
+        rows::<String>(
+            &conn,
+            "SELECT device_id, channel FROM device_channels ORDER BY channel"
+        ),
+        vec![(1, "home".to_string()), (1, "work".to_string())]
+    );
+
+    // 合并之后不能再注册重复的名字
+    assert!(conn
+        .execute("INSERT INTO users (name) VALUES ('liz')", [])
+        .is_err());
+}
+
+#[test]
+fn concurrent_duplicates_are_saved_once() {
+    let path = database_path();
+    let storage = Arc::new(SqliteStorage::new(Database::open(&path).unwrap()));
+
+    let user = User::register(storage.as_ref(), "liz", "laptop", DeviceType::Linux, "").unwrap();
+    let laptop = user.devices[0].clone();
+
+    // 多个设备同时上传相同的内容, 只保存一份
+    let threads: Vec<_> = (0..8)
+        .map(|index| {
+            let (storage, laptop) = (storage.clone(), laptop.clone());
+            thread::spawn(move || {
+                let mut clipboard = Clipboard::new("same".to_string(), ClipboardDataType::Text);
+                clipboard.received_at = 1_000 + index;
+                dedup_clipboard(storage.as_ref(), user.id, &laptop, clipboard, 60)
+                    .unwrap()
+                    .1
+            })
+        })
+        .collect();
+    let duplicates = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .filter(|duplicate| *duplicate)
+        .count();
+    assert_eq!(duplicates, 7);
+
+    let clips = find_clipboards(
+        storage.as_ref(),
+        user.id,
+        &ClipFilter {
+            limit: 20,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .clips;
+    assert_eq!(clips.len(), 1);
+    assert_eq!(clips[0].copy_count, 8);
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,85 @@
+use chrono::{DateTime, TimeZone, Utc};
+use connect_any_server::datalayer::clipboard::{Clipboard, ClipboardDataType};
+use connect_any_server::datalayer::filter::{DeviceFilter, ReceiveWindow};
+
+fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 3, 1, hour, minute, 0).unwrap()
+}
+
+fn window(start: &str, end: &str) -> ReceiveWindow {
+    ReceiveWindow {
+        start: start.to_string(),
+        end: end.to_string(),
+        utc_offset_minutes: Some(0),
+    }
+}
+
+#[test]
+fn matches_types_sizes_and_devices() {
+    let text = Clipboard::new("hello".to_string(), ClipboardDataType::Text);
+    let image = Clipboard::new("x".repeat(2048), ClipboardDataType::Image);
+    let now = at(12, 0);
+
+    assert!(DeviceFilter::default().allows(&image, 1, now));
+
+    let filter = DeviceFilter {
+        clip_types: vec![ClipboardDataType::Text],
+        ..Default::default()
+    };
+    assert!(filter.allows(&text, 1, now));
+    assert!(!filter.allows(&image, 1, now));
+
+    let filter = DeviceFilter {
+        max_image_bytes: Some(1024),
+        ..Default::default()
+    };
+    assert!(!filter.allows(&image, 1, now));
+    // 大小限制只对图片生效
+    assert!(filter.allows(
+        &Clipboard::new("x".repeat(2048), ClipboardDataType::Text),
+        1,
+        now
+    ));
+
+    let filter = DeviceFilter {
+        ignore_devices: vec![2],
+        ..Default::default()
+    };
+    assert!(filter.allows(&text, 1, now));
+    assert!(!filter.allows(&text, 2, now));
+}
+
+#[test]
+fn matches_receive_window() {
+    let filter = DeviceFilter {
+        receive_window: Some(window("09:00", "18:00")),
+        ..Default::default()
+    };
+    let text = Clipboard::new("hello".to_string(), ClipboardDataType::Text);
+
+    assert!(filter.allows(&text, 1, at(9, 0)));
+    assert!(!filter.allows(&text, 1, at(18, 0)));
+    assert!(!filter.allows(&text, 1, at(8, 59)));
+
+    // 跨过午夜
+    let night = window("22:00", "06:00");
+    assert!(night.contains(at(23, 30)));
+    assert!(night.contains(at(5, 59)));
+    assert!(!night.contains(at(12, 0)));
+
+    // 按设备的时区计算
+    let tokyo = ReceiveWindow {
+        utc_offset_minutes: Some(9 * 60),
+        ..window("09:00", "18:00")
+    };
+    assert!(tokyo.contains(at(0, 0)));
+    assert!(!tokyo.contains(at(12, 0)));
+
+    assert!(window("25:00", "06:00").validate().is_err());
+    assert!(ReceiveWindow {
+        utc_offset_minutes: Some(24 * 60),
+        ..window("09:00", "18:00")
+    }
+    .validate()
+    .is_err());
+}
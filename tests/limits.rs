@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use connect_any_server::config::{LimitsConfig, RateLimitConfig};
+use connect_any_server::datalayer::clipboard::ClipboardDataType;
+use connect_any_server::limits::{check_clip_size, RateLimiter};
+use connect_any_server::utils::{error_code, PAYLOAD_TOO_LARGE_CODE};
+
+#[test]
+fn refills_token_bucket_over_time() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        per_sec: 2.0,
+        burst: 3,
+    });
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        assert!(limiter.check_at("laptop", start).is_ok());
+    }
+    let retry_after = limiter.check_at("laptop", start).unwrap_err();
+    assert_eq!(retry_after, Duration::from_millis(500));
+
+    // 其他 key 有自己的桶
+    assert!(limiter.check_at("phone", start).is_ok());
+
+    assert!(limiter
+        .check_at("laptop", start + Duration::from_millis(500))
+        .is_ok());
+    assert!(limiter
+        .check_at("laptop", start + Duration::from_millis(500))
+        .is_err());
+
+    // 最多攒 burst 个
+    let later = start + Duration::from_secs(60);
+    for _ in 0..3 {
+        assert!(limiter.check_at("laptop", later).is_ok());
+    }
+    assert!(limiter.check_at("laptop", later).is_err());
+}
+
+#[test]
+fn zero_rate_disables_limit() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        per_sec: 0.0,
+        burst: 0,
+    });
+
+    for _ in 0..1000 {
+        assert!(limiter.check("laptop").is_ok());
+    }
+}
+
+#[test]
+fn limits_body_and_clip_size() {
+    let config = LimitsConfig {
+        max_text_bytes: 10,
+        max_image_bytes: 20,
+        ..LimitsConfig::default()
+    };
+
+    assert_eq!(config.body_limit("/message/import"), 64 * 1024 * 1024);
+    assert_eq!(
+        config.body_limit("/message/addmessage"),
+        config.max_body_bytes
+    );
+
+    assert!(check_clip_size(&config, ClipboardDataType::Text, 10).is_ok());
+    let err = check_clip_size(&config, ClipboardDataType::Text, 11).unwrap_err();
+    assert_eq!(error_code(&err), PAYLOAD_TOO_LARGE_CODE);
+    assert!(check_clip_size(&config, ClipboardDataType::Image, 20).is_ok());
+    assert!(check_clip_size(&config, ClipboardDataType::Image, 21).is_err());
+}
@@ -0,0 +1,54 @@
+use connect_any_server::datalayer::clipboard::{Clipboard, ClipboardDataType};
+use connect_any_server::datalayer::database::DatabaseUserStorage;
+use connect_any_server::metrics::{record_clip, Metrics};
+
+fn user(clips: u64, bytes: u64) -> DatabaseUserStorage {
+    DatabaseUserStorage {
+        user_id: 1,
+        name: "liz".to_string(),
+        clips,
+        bytes,
+        blob_bytes: 0,
+    }
+}
+
+#[test]
+fn renders_prometheus_text() {
+    let metrics = Metrics::global();
+
+    let clip = Clipboard::new("hello".to_string(), ClipboardDataType::Image);
+    record_clip(&metrics.clips_received, &clip, "/ws");
+    record_clip(&metrics.clips_received, &clip, "/ws");
+    metrics
+        .websocket_connections
+        .inc(&[("device_type", "Li\"nux")]);
+    metrics
+        .http_request_duration
+        .observe(&[("method", "GET"), ("route", "/message/history")], 0.02);
+
+    let text = metrics.render(&[user(5, 100), user(500, 2000)]);
+
+    assert!(text.contains("# TYPE connect_any_clips_received_total counter\n"));
+    assert!(text.contains("connect_any_clips_received_total{type=\"Image\",route=\"/ws\"} 2\n"));
+    assert!(text.contains("connect_any_websocket_connections{device_type=\"Li\\\"nux\"} 1\n"));
+
+    // 桶是累加的
+    let route = "method=\"GET\",route=\"/message/history\"";
+    assert!(text.contains(&format!(
+        "connect_any_http_request_duration_seconds_bucket{{{},le=\"0.01\"}} 0\n",
+        route
+    )));
+    assert!(text.contains(&format!(
+        "connect_any_http_request_duration_seconds_bucket{{{},le=\"0.025\"}} 1\n",
+        route
+    )));
+    assert!(text.contains(&format!(
+        "connect_any_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 1\n",
+        route
+    )));
+
+    assert!(text.contains("connect_any_history_clips 505\n"));
+    assert!(text.contains("connect_any_history_bytes 2100\n"));
+    assert!(text.contains("connect_any_user_history_clips_bucket{le=\"100\"} 1\n"));
+    assert!(text.contains("connect_any_user_history_clips_bucket{le=\"1000\"} 2\n"));
+}
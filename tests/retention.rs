@@ -0,0 +1,101 @@
+use chrono::Utc;
+use connect_any_server::datalayer::clipboard::{save_clipboard, Clipboard, ClipboardDataType};
+use connect_any_server::datalayer::memory::MemoryStorage;
+use connect_any_server::datalayer::storage::Storage;
+use connect_any_server::datalayer::{DeviceType, User};
+use connect_any_server::retention::{plan_purge, PurgeReason, RetentionPolicy};
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+// 按顺序保存, 返回每条剪切板的 id
+fn save(storage: &dyn Storage, user: &User, clips: &[(&str, u64, bool)]) -> Vec<u64> {
+    clips
+        .iter()
+        .map(|(data, received_at, sensitive)| {
+            let mut clipboard = Clipboard::new(data.to_string(), ClipboardDataType::Text);
+            clipboard.received_at = *received_at;
+            clipboard.sensitive = *sensitive;
+            save_clipboard(storage, user.id, &user.devices[0], clipboard)
+                .unwrap()
+                .id
+        })
+        .collect()
+}
+
+fn plan(storage: &dyn Storage, user: &User, policy: &RetentionPolicy) -> Vec<(u64, PurgeReason)> {
+    plan_purge(storage, user.id, policy)
+        .unwrap()
+        .into_iter()
+        .map(|candidate| (candidate.id, candidate.reason))
+        .collect()
+}
+
+#[test]
+fn keeps_pinned_and_expires_sensitive() {
+    let storage = MemoryStorage::new();
+    let user = User::register(&storage, "liz", "laptop", DeviceType::Linux, "").unwrap();
+    let now = Utc::now().timestamp_millis() as u64;
+
+    let ids = save(
+        &storage,
+        &user,
+        &[
+            ("oldest", now - 10 * DAY, false),
+            ("pinned", now - 20 * DAY, false),
+            ("password", now - 120_000, true),
+            ("recent", now - 1_000, false),
+            ("token", now, true),
+        ],
+    );
+    storage
+        .update_clip_flags(user.id, ids[1], Some(true), None)
+        .unwrap();
+
+    // 从新到旧保留, 固定的剪切板不算在内
+    let policy = RetentionPolicy {
+        max_count: Some(2),
+        sensitive_ttl_secs: Some(60),
+        ..Default::default()
+    };
+    assert_eq!(
+        plan(&storage, &user, &policy),
+        vec![
+            (ids[2], PurgeReason::Sensitive),
+            (ids[0], PurgeReason::Count)
+        ]
+    );
+
+    let policy = RetentionPolicy {
+        max_age_days: Some(7),
+        ..Default::default()
+    };
+    assert_eq!(
+        plan(&storage, &user, &policy),
+        vec![(ids[0], PurgeReason::Age)]
+    );
+
+    // 没有限制时什么都不清除
+    assert!(plan(&storage, &user, &RetentionPolicy::default()).is_empty());
+}
+
+#[test]
+fn merges_user_policy_with_default() {
+    let user = RetentionPolicy {
+        max_count: Some(10),
+        ..Default::default()
+    };
+    let default = RetentionPolicy {
+        max_count: Some(100),
+        sensitive_ttl_secs: Some(300),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        user.merge(&default),
+        RetentionPolicy {
+            max_count: Some(10),
+            sensitive_ttl_secs: Some(300),
+            ..Default::default()
+        }
+    );
+}
@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use connect_any_server::datalayer::clipboard::{
+    fts_match_query, parse_search_query, save_clipboard, search_clipboards, Clipboard,
+    ClipboardDataType, SearchTerm,
+};
+use connect_any_server::datalayer::storage::SqliteStorage;
+use connect_any_server::datalayer::{DeviceType, User};
+use connect_any_server::utils::database::Database;
+use uuid::Uuid;
+
+fn term(text: &str, prefix: bool) -> SearchTerm {
+    SearchTerm {
+        text: text.to_string(),
+        prefix,
+    }
+}
+
+#[test]
+fn parses_phrases_and_prefixes() {
+    assert_eq!(
+        parse_search_query(r#""example com" git*"#),
+        vec![term("example com", false), term("git", true)]
+    );
+    assert_eq!(
+        parse_search_query(r#""foo bar"*"#),
+        vec![term("foo bar", true)]
+    );
+    // 没有结束的引号到输入结尾为止
+    assert_eq!(
+        parse_search_query(r#""abc def"#),
+        vec![term("abc def", false)]
+    );
+    assert!(parse_search_query(r#"  "" * "#).is_empty());
+}
+
+#[test]
+fn quotes_fts_syntax() {
+    let terms = parse_search_query(r#"a-b OR NOT (x) col:y ^z"#);
+
+    assert_eq!(
+        fts_match_query(&terms),
+        r#""a-b" "OR" "NOT" "(x)" "col:y" "^z""#
+    );
+    assert_eq!(fts_match_query(&[term("git", true)]), r#""git"*"#);
+}
+
+#[test]
+fn searches_special_characters_in_sqlite() {
+    let path: PathBuf = std::env::temp_dir()
+        .join(format!("connect-any-{}", Uuid::now_v7()))
+        .join("data.db");
+    let storage = SqliteStorage::new(Database::open(&path).unwrap());
+
+    let user = User::register(&storage, "liz", "laptop", DeviceType::Linux, "").unwrap();
+    let laptop = user.devices[0].clone();
+    for data in ["git status", "user@example.com", "NOT a keyword"] {
+        let clipboard = Clipboard::new(data.to_string(), ClipboardDataType::Text);
+        save_clipboard(&storage, user.id, &laptop, clipboard).unwrap();
+    }
+
+    let search = |query: &str| -> Vec<String> {
+        search_clipboards(&storage, user.id, query, 20, 0)
+            .unwrap()
+            .results
+            .into_iter()
+            .map(|result| result.clip.data)
+            .collect()
+    };
+
+    // FTS5 的关键字和符号只当作普通的词
+    assert_eq!(search("NOT"), vec!["NOT a keyword"]);
+    assert_eq!(search("example.com"), vec!["user@example.com"]);
+    assert_eq!(search("gi*"), vec!["git status"]);
+    assert!(search("(status OR").is_empty());
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::routing::get;
+use axum::Router;
+use connect_any_protocol::{close_code, DeviceIdentity, WsMessage, PROTOCOL_VERSION};
+use connect_any_server::config::Config;
+use connect_any_server::datalayer::clipboard::{Clipboard, ClipboardDataType};
+use connect_any_server::datalayer::memory::MemoryStorage;
+use connect_any_server::datalayer::storage::Storage;
+use connect_any_server::datalayer::{DeviceType, User};
+use connect_any_server::state::{AppState, WsSession};
+use connect_any_server::websocket::ws_handler;
+use tokio::sync::{mpsc, oneshot};
+use tungstenite::Message;
+
+#[tokio::test]
+async fn shutdown_closes_sessions() {
+    let storage = Arc::new(MemoryStorage::new());
+    storage.ping().unwrap();
+
+    let state = AppState::new(Config::default(), storage);
+    assert!(!state.is_shutting_down());
+
+    let (close_tx, mut close_rx) = mpsc::channel(1);
+    state.ws_sessions.lock().await.insert(
+        "liz-laptop".to_string(),
+        WsSession {
+            user_id: 1,
+            device_id: 1,
+            addr: "127.0.0.1:40000".parse().unwrap(),
+            connected_at: 0,
+            close_tx,
+            groups: HashMap::new(),
+        },
+    );
+
+    assert_eq!(state.begin_shutdown().await, 1);
+    assert!(state.is_shutting_down());
+    assert_eq!(close_rx.recv().await, Some(close_code::GOING_AWAY));
+
+    // 连接收到 close code 后把自己从列表里移除
+    let sessions = state.ws_sessions.clone();
+    tokio::spawn(async move {
+        sessions.lock().await.clear();
+    });
+    assert!(state.wait_sessions_closed(Duration::from_secs(1)).await);
+}
+
+// 读到 close 帧为止, 返回收到的消息和 close code
+fn read_until_close<S: Read + Write>(
+    socket: &mut tungstenite::WebSocket<S>,
+) -> (Vec<WsMessage>, Option<u16>) {
+    let mut messages = Vec::new();
+
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => messages.push(serde_json::from_str(&text).unwrap()),
+            Ok(Message::Close(frame)) => {
+                return (messages, frame.map(|frame| u16::from(frame.code)));
+            }
+            Ok(_) => {}
+            Err(err) => panic!("websocket error before close: {}", err),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_closes_live_websockets() {
+    let storage = Arc::new(MemoryStorage::new());
+    User::register(storage.as_ref(), "liz", "laptop", DeviceType::Linux, "").unwrap();
+    let user = User::register(storage.as_ref(), "liz", "phone", DeviceType::Android, "").unwrap();
+    let phone = user.devices[1].clone();
+
+    let mut config = Config::default();
+    config.shutdown.timeout_secs = 5;
+    let state = AppState::new(config, storage);
+
+    let app = Router::new()
+        .route("/ws", get(ws_handler))
+        .with_state(state.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async {
+            let _ = stop_rx.await;
+        })
+        .await
+        .unwrap();
+    });
+
+    let client = tokio::task::spawn_blocking(move || {
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}/ws", addr)).unwrap();
+        let hello = WsMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: None,
+            device: DeviceIdentity {
+                name: "laptop".to_string(),
+                device_type: "Linux".to_string(),
+            },
+            groups: Vec::new(),
+        };
+        socket
+            .send(Message::Text(serde_json::to_string(&hello).unwrap()))
+            .unwrap();
+
+        read_until_close(&mut socket)
+    });
+
+    // 等连接登记之后再开始关闭
+    while state.ws_sessions.lock().await.is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // 关闭前推送的剪切板也要送到
+    state
+        .add_clipboard(
+            state.find_user(&phone).await.unwrap(),
+            &phone,
+            Clipboard::new("last".to_string(), ClipboardDataType::Text),
+        )
+        .await
+        .unwrap();
+
+    let started = Instant::now();
+    assert_eq!(state.begin_shutdown().await, 1);
+    let _ = stop_tx.send(());
+
+    assert!(state.wait_sessions_closed(Duration::from_secs(5)).await);
+    server.await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let (messages, code) = client.await.unwrap();
+    assert_eq!(code, Some(close_code::GOING_AWAY));
+    assert!(messages
+        .iter()
+        .any(|message| matches!(message, WsMessage::ClipPush { clip, .. } if clip.data == "last")));
+}
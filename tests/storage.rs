@@ -0,0 +1,558 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use connect_any_protocol::{DeviceIdentity, WsMessage};
+use connect_any_server::config::Config;
+use connect_any_server::datalayer::channel::{
+    create_channel, set_device_channels, DEFAULT_CHANNEL,
+};
+use connect_any_server::datalayer::clipboard::{
+    clipboard_hash, current_clipboard, dedup_clipboard, find_clipboards, get_clipboard,
+    latest_clipboard, save_clipboard, ClipFilter, Clipboard, ClipboardDataType, CurrentClipboard,
+};
+use connect_any_server::datalayer::group::{create_group, set_group_member, GroupRole};
+use connect_any_server::datalayer::memory::MemoryStorage;
+use connect_any_server::datalayer::storage::Storage;
+use connect_any_server::datalayer::{Device, DeviceType, InputDevice, User};
+use connect_any_server::state::{AppState, ClipboardData, WsBroadcast, WsSession};
+use connect_any_server::utils::{error_code, CONFLICT_CODE};
+use tokio::sync::mpsc;
+
+fn register(storage: &dyn Storage, user: &str, device: &str, device_type: DeviceType) -> Device {
+    User::register(storage, user, device, device_type, "")
+        .unwrap()
+        .devices
+        .into_iter()
+        .find(|item| item.name == device)
+        .unwrap()
+}
+
+fn text(data: &str, received_at: u64) -> Clipboard {
+    let mut clipboard = Clipboard::new(data.to_string(), ClipboardDataType::Text);
+    clipboard.received_at = received_at;
+    clipboard
+}
+
+fn history(storage: &dyn Storage, user_id: u64, filter: ClipFilter) -> Vec<Clipboard> {
+    find_clipboards(
+        storage,
+        user_id,
+        &ClipFilter {
+            limit: 20,
+            ..filter
+        },
+    )
+    .unwrap()
+    .clips
+}
+
+#[test]
+fn registers_users_and_devices() {
+    let storage = MemoryStorage::new();
+
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+    let phone = register(&storage, "liz", "phone", DeviceType::Android);
+    register(&storage, "bob", "desktop", DeviceType::Windows);
+
+    let user = User::find_user_from_device(&storage, &phone).unwrap();
+    assert_eq!(user.name, "liz");
+    assert_eq!(user.devices, vec![laptop.clone(), phone]);
+
+    let device = InputDevice::from(DeviceIdentity {
+        name: "laptop".to_string(),
+        device_type: "Linux".to_string(),
+    })
+    .parse(&storage)
+    .unwrap();
+    assert_eq!(device, laptop);
+
+    let unknown = InputDevice::from(DeviceIdentity {
+        name: "laptop".to_string(),
+        device_type: "Mac".to_string(),
+    });
+    assert!(unknown.parse(&storage).is_err());
+
+    assert!(storage.delete_device(user.id, laptop.id).unwrap());
+    assert!(User::find_user_from_device(&storage, &laptop).is_err());
+}
+
+#[test]
+fn rejects_device_registered_to_another_user() {
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+
+    // 重复注册同一个设备返回原来的设备
+    let user = User::register(&storage, "liz", "laptop", DeviceType::Linux, "").unwrap();
+    assert_eq!(user.devices, vec![laptop]);
+
+    let err = User::register(&storage, "bob", "laptop", DeviceType::Linux, "")
+        .err()
+        .unwrap();
+    assert_eq!(error_code(&err), CONFLICT_CODE);
+    assert!(storage.find_user("bob").unwrap().is_none());
+}
+
+#[test]
+fn orders_and_deduplicates_clips() {
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+    let user = User::find_user_from_device(&storage, &laptop).unwrap();
+
+    let first = save_clipboard(&storage, user.id, &laptop, text("first", 1_000)).unwrap();
+    let second = save_clipboard(&storage, user.id, &laptop, text("second", 2_000)).unwrap();
+    assert!(second.seq > first.seq);
+
+    // 窗口内再次复制相同的内容, 原来的剪切板移到最前面
+    let (repeated, duplicate) =
+        dedup_clipboard(&storage, user.id, &laptop, text("first", 3_000), 60).unwrap();
+    assert!(duplicate);
+    assert_eq!(repeated.id, first.id);
+    assert_eq!(repeated.copy_count, 2);
+
+    let clips = history(&storage, user.id, ClipFilter::default());
+    let ids: Vec<u64> = clips.iter().map(|clip| clip.id).collect();
+    assert_eq!(ids, vec![first.id, second.id]);
+    assert_eq!(clips[0].source, Some(laptop.identity()));
+
+    // 超过窗口时不去重, 保存成新的剪切板
+    let (late, duplicate) =
+        dedup_clipboard(&storage, user.id, &laptop, text("first", 200_000), 60).unwrap();
+    assert!(!duplicate);
+    assert_ne!(late.id, first.id);
+    assert_eq!(late.copy_count, 1);
+}
+
+#[test]
+fn deduplicates_by_content_hash() {
+    let image = Clipboard::new("hello".to_string(), ClipboardDataType::Image);
+
+    // 类型和内容一起计算, 和时间等其他字段无关
+    assert_eq!(
+        clipboard_hash(&text("hello", 1_000)),
+        "8cff7adff2258357d405b9a30cdb8d23327a440c50aa53d5ac9cd6dcc61fff30"
+    );
+    assert_eq!(
+        clipboard_hash(&text("hello", 1_000)),
+        clipboard_hash(&text("hello", 9_000))
+    );
+    assert_ne!(
+        clipboard_hash(&text("hello", 1_000)),
+        clipboard_hash(&image)
+    );
+    assert_ne!(
+        clipboard_hash(&text("hello", 1_000)),
+        clipboard_hash(&text("hello ", 1_000))
+    );
+
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+    let phone = register(&storage, "liz", "phone", DeviceType::Android);
+    let user = User::find_user_from_device(&storage, &laptop).unwrap();
+    save_clipboard(&storage, user.id, &laptop, text("hello", 1_000)).unwrap();
+
+    let dedup = |clipboard: Clipboard, window_secs: u64| {
+        let (clip, duplicate) =
+            dedup_clipboard(&storage, user.id, &laptop, clipboard, window_secs).unwrap();
+        duplicate.then_some(clip.copy_count)
+    };
+
+    assert_eq!(dedup(text("hello", 2_000), 60), Some(2));
+    // 不同的类型, 频道, 定向发送或者关闭去重时不算重复
+    let mut other_type = Clipboard::new("hello".to_string(), ClipboardDataType::None);
+    other_type.received_at = 2_000;
+    assert_eq!(dedup(other_type, 60), None);
+    let mut other_channel = text("hello", 2_000);
+    other_channel.channel = Some("work".to_string());
+    assert_eq!(dedup(other_channel, 60), None);
+    let mut targeted = text("hello", 2_000);
+    targeted.targets = Some(vec![phone.id]);
+    assert_eq!(dedup(targeted, 60), None);
+    assert_eq!(dedup(text("hello", 2_000), 0), None);
+}
+
+#[test]
+fn filters_targeted_clips_by_recipient() {
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+    let phone = register(&storage, "liz", "phone", DeviceType::Android);
+    let tablet = register(&storage, "liz", "tablet", DeviceType::Ios);
+    let user = User::find_user_from_device(&storage, &laptop).unwrap();
+
+    let mut targeted = text("for phone", 1_000);
+    targeted.targets = Some(vec![phone.id]);
+    let targeted = save_clipboard(&storage, user.id, &laptop, targeted).unwrap();
+
+    let for_phone = history(
+        &storage,
+        user.id,
+        ClipFilter {
+            recipient: Some(phone.id),
+            ..Default::default()
+        },
+    );
+    assert_eq!(for_phone.len(), 1);
+
+    let for_tablet = history(
+        &storage,
+        user.id,
+        ClipFilter {
+            recipient: Some(tablet.id),
+            ..Default::default()
+        },
+    );
+    assert!(for_tablet.is_empty());
+
+    // 删除设备后保留剪切板, 但是没有来源
+    assert!(storage.delete_device(user.id, laptop.id).unwrap());
+    let clip = get_clipboard(&storage, user.id, targeted.id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(clip.source, None);
+}
+
+#[tokio::test]
+async fn app_state_uses_storage() {
+    let storage = Arc::new(MemoryStorage::new());
+    let laptop = register(storage.as_ref(), "liz", "laptop", DeviceType::Linux);
+
+    let state = AppState::new(Config::default(), storage);
+
+    let user = state.find_user(&laptop).await.unwrap();
+    assert_eq!(user.name, "liz");
+
+    let device = state
+        .parse_device(InputDevice::from(laptop.identity()))
+        .await
+        .unwrap();
+    assert_eq!(device, laptop);
+}
+
+// 取出推送的剪切板和接收的设备
+fn pushed(broadcast: WsBroadcast) -> (String, HashSet<u64>) {
+    match broadcast.message {
+        WsMessage::ClipPush { clip, .. } => (clip.data, broadcast.recipients.unwrap()),
+        message => panic!("unexpected message {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn add_clipboard_uses_storage() {
+    let storage = Arc::new(MemoryStorage::new());
+    let laptop = register(storage.as_ref(), "liz", "laptop", DeviceType::Linux);
+    let phone = register(storage.as_ref(), "liz", "phone", DeviceType::Android);
+    let tablet = register(storage.as_ref(), "liz", "tablet", DeviceType::Ios);
+    let desktop = register(storage.as_ref(), "bob", "desktop", DeviceType::Windows);
+
+    let state = AppState::new(Config::default(), storage.clone());
+    let liz = state.find_user(&laptop).await.unwrap();
+    let bob = state.find_user(&desktop).await.unwrap();
+
+    let mut user_rx = state
+        .clipboard_datas
+        .lock()
+        .await
+        .entry(liz.id)
+        .or_insert(ClipboardData::new())
+        .ws_tx
+        .subscribe();
+
+    state
+        .add_clipboard(
+            state.find_user(&laptop).await.unwrap(),
+            &laptop,
+            text("hello", 0),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        pushed(user_rx.try_recv().unwrap()),
+        ("hello".to_string(), HashSet::from([phone.id, tablet.id]))
+    );
+
+    // 只推送给订阅了频道的设备
+    create_channel(storage.as_ref(), liz.id, "work").unwrap();
+    set_device_channels(storage.as_ref(), liz.id, phone.id, &["work".to_string()]).unwrap();
+
+    let mut clipboard = text("report", 0);
+    clipboard.channel = Some("work".to_string());
+    state
+        .add_clipboard(state.find_user(&laptop).await.unwrap(), &laptop, clipboard)
+        .await
+        .unwrap();
+    assert_eq!(
+        pushed(user_rx.try_recv().unwrap()),
+        ("report".to_string(), HashSet::from([phone.id]))
+    );
+
+    let clips = history(
+        storage.as_ref(),
+        liz.id,
+        ClipFilter {
+            channels: Some(vec!["work".to_string()]),
+            ..Default::default()
+        },
+    );
+    assert_eq!(clips.len(), 1);
+    assert_eq!(clips[0].data, "report");
+
+    // 群组的剪切板推送给所有成员的设备
+    let group = create_group(storage.as_ref(), liz.id, "team").unwrap();
+    set_group_member(
+        storage.as_ref(),
+        group.id,
+        liz.id,
+        bob.id,
+        GroupRole::Member,
+    )
+    .unwrap();
+
+    let mut group_rx = state
+        .group_datas
+        .lock()
+        .await
+        .entry(group.id)
+        .or_insert(ClipboardData::new())
+        .ws_tx
+        .subscribe();
+
+    let mut clipboard = text("shared", 0);
+    clipboard.group_id = Some(group.id);
+    let clipboard = state
+        .add_clipboard(state.find_user(&laptop).await.unwrap(), &laptop, clipboard)
+        .await
+        .unwrap();
+    assert_eq!(
+        pushed(group_rx.try_recv().unwrap()),
+        (
+            "shared".to_string(),
+            HashSet::from([phone.id, tablet.id, desktop.id])
+        )
+    );
+    assert!(get_clipboard(storage.as_ref(), bob.id, clipboard.id)
+        .unwrap()
+        .is_some());
+
+    // 轮询的设备可以取到群组的剪切板
+    assert!(state.group_datas.lock().await[&group.id]
+        .devices
+        .contains(&desktop));
+    assert_eq!(
+        latest_clipboard(storage.as_ref(), bob.id, Some(group.id), desktop.id)
+            .unwrap()
+            .map(|clip| clip.data),
+        Some("shared".to_string())
+    );
+
+    // viewer 不能发布到群组
+    set_group_member(
+        storage.as_ref(),
+        group.id,
+        liz.id,
+        bob.id,
+        GroupRole::Viewer,
+    )
+    .unwrap();
+    let mut clipboard = text("denied", 0);
+    clipboard.group_id = Some(group.id);
+    assert!(state.add_clipboard(bob, &desktop, clipboard).await.is_err());
+}
+
+#[tokio::test]
+async fn add_clipboard_delivers_to_targets() {
+    let storage = Arc::new(MemoryStorage::new());
+    let laptop = register(storage.as_ref(), "liz", "laptop", DeviceType::Linux);
+    let phone = register(storage.as_ref(), "liz", "phone", DeviceType::Android);
+    register(storage.as_ref(), "liz", "tablet", DeviceType::Ios);
+    let desktop = register(storage.as_ref(), "bob", "desktop", DeviceType::Windows);
+
+    let state = AppState::new(Config::default(), storage.clone());
+    let liz = state.find_user(&laptop).await.unwrap();
+
+    let mut user_rx = state
+        .clipboard_datas
+        .lock()
+        .await
+        .entry(liz.id)
+        .or_insert(ClipboardData::new())
+        .ws_tx
+        .subscribe();
+
+    // 重复的目标和自己会被去掉
+    let mut clipboard = text("for phone", 0);
+    clipboard.targets = Some(vec![phone.id, laptop.id, phone.id]);
+    let clipboard = state
+        .add_clipboard(state.find_user(&laptop).await.unwrap(), &laptop, clipboard)
+        .await
+        .unwrap();
+    assert_eq!(clipboard.targets, Some(vec![phone.id]));
+    assert_eq!(
+        pushed(user_rx.try_recv().unwrap()),
+        ("for phone".to_string(), HashSet::from([phone.id]))
+    );
+
+    // 定向发送的剪切板不是当前剪切板
+    assert!(current_clipboard(storage.as_ref(), liz.id, None, None)
+        .unwrap()
+        .is_none());
+
+    // 只能发送给自己其他的设备
+    for targets in [vec![desktop.id], vec![laptop.id]] {
+        let mut clipboard = text("denied", 0);
+        clipboard.targets = Some(targets);
+        assert!(state
+            .add_clipboard(state.find_user(&laptop).await.unwrap(), &laptop, clipboard)
+            .await
+            .is_err());
+    }
+    assert!(user_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn add_clipboard_skips_stale_seq() {
+    let storage = Arc::new(MemoryStorage::new());
+    let laptop = register(storage.as_ref(), "liz", "laptop", DeviceType::Linux);
+    register(storage.as_ref(), "liz", "phone", DeviceType::Android);
+
+    let state = AppState::new(Config::default(), storage.clone());
+    let liz = state.find_user(&laptop).await.unwrap();
+
+    // 模拟另一个并发上传已经推送了序号更大的剪切板
+    let mut user_rx = {
+        let mut clipboard_datas = state.clipboard_datas.lock().await;
+        let clipboard_data = clipboard_datas
+            .entry(liz.id)
+            .or_insert(ClipboardData::new());
+        clipboard_data.current.insert(
+            DEFAULT_CHANNEL.to_string(),
+            CurrentClipboard {
+                id: 1000,
+                seq: u64::MAX,
+                device_id: None,
+            },
+        );
+        clipboard_data.ws_tx.subscribe()
+    };
+
+    let clipboard = state
+        .add_clipboard(
+            state.find_user(&laptop).await.unwrap(),
+            &laptop,
+            text("stale", 0),
+        )
+        .await
+        .unwrap();
+
+    let broadcast = user_rx.try_recv().unwrap();
+    assert_eq!(broadcast.recipients, Some(HashSet::from([laptop.id])));
+    match broadcast.message {
+        WsMessage::ClipSuperseded {
+            clip_id,
+            current_id,
+            ..
+        } => assert_eq!((clip_id, current_id), (clipboard.id, 1000)),
+        message => panic!("unexpected message {:?}", message),
+    }
+    assert!(user_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn add_clipboard_orders_by_server_seq() {
+    let storage = Arc::new(MemoryStorage::new());
+    let laptop = register(storage.as_ref(), "liz", "laptop", DeviceType::Linux);
+    register(storage.as_ref(), "liz", "phone", DeviceType::Android);
+
+    let state = AppState::new(Config::default(), storage.clone());
+    let liz = state.find_user(&laptop).await.unwrap();
+    let tomorrow = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+        + 24 * 60 * 60 * 1000;
+
+    // 客户端时钟超前时只做标记, 顺序仍然按照服务器收到的先后
+    let mut clipboard = text("ahead", 0);
+    clipboard.date = tomorrow;
+    let ahead = state
+        .add_clipboard(state.find_user(&laptop).await.unwrap(), &laptop, clipboard)
+        .await
+        .unwrap();
+    assert!(ahead.future_date);
+
+    let mut clipboard = text("behind", 0);
+    clipboard.date = 1;
+    let behind = state
+        .add_clipboard(state.find_user(&laptop).await.unwrap(), &laptop, clipboard)
+        .await
+        .unwrap();
+    assert!(!behind.future_date);
+    assert!(behind.seq > ahead.seq);
+    assert!(behind.received_at >= ahead.received_at);
+
+    let clips = history(storage.as_ref(), liz.id, ClipFilter::default());
+    assert_eq!(
+        clips
+            .iter()
+            .map(|clip| (clip.data.as_str(), clip.date, clip.future_date))
+            .collect::<Vec<_>>(),
+        vec![("behind", 1, false), ("ahead", tomorrow, true)]
+    );
+    assert_eq!(
+        current_clipboard(storage.as_ref(), liz.id, None, None)
+            .unwrap()
+            .map(|current| current.id),
+        Some(behind.id)
+    );
+
+    // 配置了拒绝时, 超前的剪切板不会保存
+    let mut config = Config::default();
+    config.clock.reject_future = true;
+    let state = AppState::new(config, storage.clone());
+
+    let mut clipboard = text("rejected", 0);
+    clipboard.date = tomorrow;
+    let liz = state.find_user(&laptop).await.unwrap();
+    let liz_id = liz.id;
+    assert!(state.add_clipboard(liz, &laptop, clipboard).await.is_err());
+    assert_eq!(
+        history(storage.as_ref(), liz_id, ClipFilter::default()).len(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn leave_group_stops_forwarding() {
+    let state = AppState::new(Config::default(), Arc::new(MemoryStorage::new()));
+
+    let forward = |user_id: u64| {
+        let task = tokio::spawn(std::future::pending::<()>());
+        let (close_tx, _) = mpsc::channel(1);
+        let session = WsSession {
+            user_id,
+            device_id: user_id,
+            addr: "127.0.0.1:40000".parse().unwrap(),
+            connected_at: 0,
+            close_tx,
+            groups: HashMap::from([(7, task.abort_handle())]),
+        };
+        (task, session)
+    };
+    let (bob_task, bob) = forward(2);
+    let (liz_task, liz) = forward(1);
+    state
+        .ws_sessions
+        .lock()
+        .await
+        .insert("bob".to_string(), bob);
+    state
+        .ws_sessions
+        .lock()
+        .await
+        .insert("liz".to_string(), liz);
+
+    state.leave_group(7, 2).await;
+
+    assert!(bob_task.await.unwrap_err().is_cancelled());
+    assert!(!liz_task.is_finished());
+    assert!(state.ws_sessions.lock().await["bob"].groups.is_empty());
+}
@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request};
+use axum::routing::{get, post};
+use axum::{middleware, Router};
+use connect_any_server::api::{check_client_cert, limit_request};
+use connect_any_server::config::{ClientCertConfig, Config, TlsConfig};
+use connect_any_server::datalayer::memory::MemoryStorage;
+use connect_any_server::datalayer::DeviceType;
+use connect_any_server::state::AppState;
+use connect_any_server::tls::{load_server_config, ClientCertificate};
+use tower::ServiceExt;
+
+fn config() -> TlsConfig {
+    TlsConfig {
+        client_certs: vec![ClientCertConfig {
+            fingerprint: "AB:CD:01".to_string(),
+            name: "laptop".to_string(),
+            device_type: DeviceType::Linux,
+        }],
+        ..TlsConfig::default()
+    }
+}
+
+fn cert(fingerprint: Option<&str>) -> ClientCertificate {
+    ClientCertificate {
+        fingerprint: fingerprint.map(str::to_string),
+    }
+}
+
+#[test]
+fn binds_client_certificate_to_device() {
+    let config = config();
+
+    assert!(cert(Some("abcd01"))
+        .check(&config, "laptop", "Linux")
+        .is_ok());
+    assert!(cert(Some("abcd01"))
+        .check(&config, "laptop", "Windows")
+        .is_err());
+    assert!(cert(Some("abcd01"))
+        .check(&config, "phone", "Linux")
+        .is_err());
+
+    // 没有绑定设备的证书不能访问任何设备
+    assert!(cert(Some("ffff"))
+        .check(&config, "laptop", "Linux")
+        .is_err());
+
+    // 没有证书时按原来的方式认证, 绑定了证书的设备必须提供证书
+    assert!(cert(None).check(&config, "phone", "Android").is_ok());
+    assert!(cert(None).check(&config, "laptop", "Linux").is_err());
+}
+
+async fn send(state: &AppState, request: Request<Body>, fingerprint: Option<&str>) -> String {
+    let app = Router::new()
+        .route("/message/history", get(|| async { "ok" }))
+        .route("/message/addmessage", post(|| async { "ok" }))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_client_cert,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), limit_request))
+        .with_state(state.clone());
+
+    let mut request = request;
+    if fingerprint.is_some() {
+        request.extensions_mut().insert(cert(fingerprint));
+    }
+
+    let response = app.oneshot(request).await.unwrap();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+fn post_device(uri: &str, content_type: &str, name: &str, device_type: &str) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(format!(
+            r#"{{"device": {{"name": "{}", "type": "{}"}}}}"#,
+            name, device_type
+        )))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn checks_device_from_handler_source() {
+    let mut config = Config::default();
+    config.tls = self::config();
+    let state = AppState::new(config, Arc::new(MemoryStorage::new()));
+
+    // json 的设备在 body 里, 类型大小写不同或者 `+json` 也一样检查
+    for content_type in [
+        "application/json",
+        "Application/JSON; charset=utf-8",
+        "application/vnd.api+json",
+    ] {
+        let request = post_device("/message/addmessage", content_type, "laptop", "Linux");
+        assert!(send(&state, request, None)
+            .await
+            .contains("requires a client certificate"));
+    }
+
+    let request = post_device(
+        "/message/addmessage",
+        "application/json",
+        "phone",
+        "Android",
+    );
+    assert_eq!(send(&state, request, None).await, "ok");
+
+    let request = post_device("/message/addmessage", "application/json", "laptop", "Linux");
+    assert_eq!(send(&state, request, Some("abcd01")).await, "ok");
+
+    // query 和 body 里的设备不一致
+    let request = post_device(
+        "/message/addmessage?name=laptop&type=Linux",
+        "application/json",
+        "phone",
+        "Android",
+    );
+    assert!(send(&state, request, Some("abcd01"))
+        .await
+        .contains("do not match"));
+
+    // GET 的设备在 query 里
+    let request = Request::get("/message/history?name=laptop&type=Linux")
+        .body(Body::empty())
+        .unwrap();
+    assert!(send(&state, request, None)
+        .await
+        .contains("requires a client certificate"));
+
+    let request = Request::get("/message/history?name=laptop&type=Linux")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(&state, request, Some("abcd01")).await, "ok");
+}
+
+#[test]
+fn reports_missing_certificate_files() {
+    let config = TlsConfig {
+        cert: PathBuf::from("./tests/missing-cert.pem"),
+        ..config()
+    };
+
+    let err = load_server_config(&config).unwrap_err();
+    assert!(err.to_string().contains("missing-cert.pem"));
+}
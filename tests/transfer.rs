@@ -0,0 +1,212 @@
+use connect_any_server::config::Config;
+use connect_any_server::datalayer::clipboard::{
+    current_clipboard, save_clipboard, user_clipboards, Clipboard, ClipboardDataType,
+};
+use connect_any_server::datalayer::memory::MemoryStorage;
+use connect_any_server::datalayer::storage::Storage;
+use connect_any_server::datalayer::{Device, DeviceType, User};
+use connect_any_server::sensitive::Classifier;
+use connect_any_server::transfer::{
+    export_csv, export_jsonl, import_clipboards, parse_import, ImportReport, TransferFormat,
+};
+
+fn register(storage: &dyn Storage, user: &str, device: &str, device_type: DeviceType) -> Device {
+    User::register(storage, user, device, device_type, "")
+        .unwrap()
+        .devices
+        .into_iter()
+        .find(|item| item.name == device)
+        .unwrap()
+}
+
+fn text(data: &str, date: u64) -> Clipboard {
+    let mut clipboard = Clipboard::new(data.to_string(), ClipboardDataType::Text);
+    clipboard.date = date as u128;
+    clipboard.received_at = date;
+    clipboard
+}
+
+fn import(
+    storage: &dyn Storage,
+    device: &Device,
+    format: TransferFormat,
+    data: &str,
+) -> ImportReport {
+    import_with(storage, device, format, data, &Config::default())
+}
+
+fn import_with(
+    storage: &dyn Storage,
+    device: &Device,
+    format: TransferFormat,
+    data: &str,
+    config: &Config,
+) -> ImportReport {
+    let user = User::find_user_from_device(storage, device).unwrap();
+
+    import_clipboards(
+        storage,
+        &user,
+        device,
+        &[],
+        &Classifier::from_patterns(&[]).unwrap(),
+        &config.limits,
+        &config.retention.default,
+        parse_import(format, data).unwrap(),
+    )
+    .unwrap()
+}
+
+fn history(storage: &dyn Storage, device: &Device) -> Vec<(String, u128)> {
+    let user = User::find_user_from_device(storage, device).unwrap();
+
+    user_clipboards(storage, user.id)
+        .unwrap()
+        .into_iter()
+        .map(|clip| (clip.data, clip.date))
+        .collect()
+}
+
+#[test]
+fn round_trips_json_lines() {
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+    let user = User::find_user_from_device(&storage, &laptop).unwrap();
+
+    save_clipboard(&storage, user.id, &laptop, text("first", 1_000)).unwrap();
+    let second = save_clipboard(&storage, user.id, &laptop, text("second", 2_000)).unwrap();
+    storage
+        .update_clip_flags(user.id, second.id, Some(true), None)
+        .unwrap();
+
+    let exported = export_jsonl(&storage, &user).unwrap();
+    assert_eq!(exported.lines().count(), 4);
+
+    let other = MemoryStorage::new();
+    let desktop = register(&other, "liz", "desktop", DeviceType::Windows);
+    let report = import(&other, &desktop, TransferFormat::Jsonl, &exported);
+    assert_eq!(report.imported, 2);
+
+    assert_eq!(
+        history(&other, &desktop),
+        vec![("first".to_string(), 1_000), ("second".to_string(), 2_000)]
+    );
+    let user = User::find_user_from_device(&other, &desktop).unwrap();
+    let clips = user_clipboards(&other, user.id).unwrap();
+    assert!(clips[1].pinned);
+    // 原来的设备不是这个用户的设备, 记为导入的设备
+    assert_eq!(clips[0].source, Some(desktop.identity()));
+
+    // 再导入一次全部重复
+    let report = import(&other, &desktop, TransferFormat::Jsonl, &exported);
+    assert_eq!(report.imported, 0);
+    assert_eq!(report.duplicates, 2);
+}
+
+#[test]
+fn keeps_current_clipboard_after_import() {
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+    let user = User::find_user_from_device(&storage, &laptop).unwrap();
+
+    let current = save_clipboard(&storage, user.id, &laptop, text("current", 5_000)).unwrap();
+
+    let csv = "data,date\n\"multi\nline, quoted \"\"text\"\"\",1000\ncurrent,2000\nold,\n";
+    let report = import(&storage, &laptop, TransferFormat::Csv, csv);
+    assert_eq!(report.imported, 2);
+    assert_eq!(report.duplicates, 1);
+
+    let found = current_clipboard(&storage, user.id, None, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.id, current.id);
+
+    let exported = export_csv(&storage, &user).unwrap();
+    assert!(exported.contains("\"multi\nline, quoted \"\"text\"\"\""));
+}
+
+#[test]
+fn imports_clipboard_manager_dumps() {
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Mac);
+
+    // CopyQ 从新到旧
+    let copyq = r#"[
+        {"text/plain": "newest"},
+        {"text/plain": "older", "time": 1000},
+        {"application/x-unknown": "skipped"}
+    ]"#;
+    let report = import(&storage, &laptop, TransferFormat::Copyq, copyq);
+    assert_eq!(report.imported, 2);
+    assert_eq!(report.skipped, 1);
+
+    let clipy = r#"<?xml version="1.0" encoding="UTF-8"?>
+<folders>
+  <folder>
+    <title>Work</title>
+    <snippets>
+      <snippet><title>a</title><content>git status</content></snippet>
+      <snippet><title>b</title><content>newest</content></snippet>
+      <snippet><title>c</title><content>&lt;br&gt;</content></snippet>
+    </snippets>
+  </folder>
+</folders>"#;
+    let report = import(&storage, &laptop, TransferFormat::Clipy, clipy);
+    assert_eq!(report.imported, 2);
+    assert_eq!(report.duplicates, 1);
+
+    // 再导入一次, 时间更早的也排在所有已有的剪切板前面
+    let report = import(
+        &storage,
+        &laptop,
+        TransferFormat::Csv,
+        "data,date\nancient,500\n",
+    );
+    assert_eq!(report.imported, 1);
+
+    let data: Vec<String> = history(&storage, &laptop)
+        .into_iter()
+        .map(|(data, _)| data)
+        .collect();
+    // 每次导入的排在已有的剪切板前面, 第一次导入后的当前剪切板还在最后
+    assert_eq!(
+        data,
+        vec!["ancient", "git status", "<br>", "older", "newest"]
+    );
+}
+
+#[test]
+fn imports_below_existing_history() {
+    let storage = MemoryStorage::new();
+    let laptop = register(&storage, "liz", "laptop", DeviceType::Linux);
+    let user = User::find_user_from_device(&storage, &laptop).unwrap();
+
+    let live = save_clipboard(&storage, user.id, &laptop, text("live", 9_000)).unwrap();
+
+    let mut config = Config::default();
+    config.limits.max_text_bytes = 8;
+    config.retention.default.max_count = Some(3);
+
+    // 超过大小上限的跳过, 导入后只保留最新的 3 条
+    let csv = "data,date\nfirst,1000\nsecond,2000\nthird,3000\ntoo large text,4000\n";
+    let report = import_with(&storage, &laptop, TransferFormat::Csv, csv, &config);
+    assert_eq!(report.imported, 3);
+    assert_eq!(report.skipped, 1);
+
+    let clips = user_clipboards(&storage, user.id).unwrap();
+    assert_eq!(
+        clips
+            .iter()
+            .map(|clip| clip.data.as_str())
+            .collect::<Vec<&str>>(),
+        vec!["second", "third", "live"]
+    );
+
+    // 已有的剪切板的序号不变, 仍然是当前的剪切板
+    assert_eq!(clips[2].seq, live.seq);
+    assert!(clips[1].seq < live.seq);
+    let found = current_clipboard(&storage, user.id, None, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!((found.id, found.seq), (live.id, live.seq));
+}
@@ -0,0 +1,403 @@
+// 网页版客户端: 以 Web 类型的设备登录, 通过 websocket 接收实时的剪切板
+"use strict";
+
+const DEVICE_TYPE = "Web";
+const HISTORY_PAGE = 30;
+
+const state = {
+  user: null,
+  device: null,
+  ws: null,
+  clips: [],
+  nextCursor: null,
+  devices: [],
+  presence: {},
+  // 上传后等待 clip_ack 的消息 id
+  pending: new Set(),
+  image: null,
+};
+
+const $ = (id) => document.getElementById(id);
+
+function uuid() {
+  if (window.crypto && crypto.randomUUID) {
+    return crypto.randomUUID();
+  }
+  return Date.now().toString(16) + Math.random().toString(16).slice(2);
+}
+
+function notice(text) {
+  $("notice").textContent = text;
+}
+
+function deviceQuery() {
+  return "name=" + encodeURIComponent(state.device) + "&type=" + DEVICE_TYPE;
+}
+
+function deviceBody() {
+  return { name: state.device, type: DEVICE_TYPE };
+}
+
+// 接口总是返回 200, 通过 code 判断是否成功
+async function api(path, body) {
+  const options = body === undefined
+    ? {}
+    : { method: "POST", headers: { "content-type": "application/json" }, body: JSON.stringify(body) };
+  const res = await fetch(path, options);
+  const json = await res.json();
+  if (json.code !== 200) {
+    throw new Error(json.msg);
+  }
+  return json.data;
+}
+
+// ---------- 登录 ----------
+
+async function login(user, device) {
+  await api("/user/adduser", {
+    name: user,
+    device: { name: device, type: DEVICE_TYPE, notification: "" },
+  });
+
+  state.user = user;
+  state.device = device;
+  localStorage.setItem("connect-any", JSON.stringify({ user, device }));
+
+  $("login-view").hidden = true;
+  $("app-view").hidden = false;
+  $("logout").hidden = false;
+
+  await Promise.all([loadDevices(), loadHistory(true)]);
+  connect();
+}
+
+function logout() {
+  localStorage.removeItem("connect-any");
+  if (state.ws) {
+    state.ws.onclose = null;
+    state.ws.close();
+  }
+  location.reload();
+}
+
+// ---------- websocket ----------
+
+function connect() {
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  const ws = new WebSocket(scheme + "//" + location.host + "/ws");
+  state.ws = ws;
+
+  ws.onopen = () => {
+    send({ type: "hello", protocol_version: 1, device: deviceBody() });
+  };
+
+  ws.onmessage = (event) => handleMessage(JSON.parse(event.data));
+
+  ws.onclose = (event) => {
+    setStatus(false);
+    // 被服务器踢掉或者设备被删除时不再重连
+    if (event.code === 4003 || event.code === 4004) {
+      notice("Disconnected by server: " + (event.reason || event.code));
+      return;
+    }
+    setTimeout(connect, 3000);
+  };
+}
+
+function send(message) {
+  if (state.ws && state.ws.readyState === WebSocket.OPEN) {
+    state.ws.send(JSON.stringify(message));
+    return true;
+  }
+  return false;
+}
+
+function setStatus(online) {
+  $("status").textContent = online ? "online" : "offline";
+  $("status").classList.toggle("online", online);
+}
+
+async function handleMessage(message) {
+  switch (message.type) {
+    case "welcome":
+      setStatus(true);
+      // 断线期间可能错过了推送
+      loadHistory(true);
+      break;
+    case "clip_push":
+      send({ type: "clip_ack", id: message.id });
+      addClip(message.clip);
+      break;
+    case "clip_ack":
+      if (state.pending.delete(message.id) && message.clip_id) {
+        addClip(await api("/message/" + message.clip_id + "?" + deviceQuery()));
+      }
+      break;
+    case "clip_updated":
+      addClip(message.clip);
+      break;
+    case "clip_deleted":
+      state.clips = state.clips.filter((clip) => clip.id !== message.id);
+      renderHistory();
+      break;
+    case "clip_superseded":
+      notice("Another device copied something newer.");
+      break;
+    case "presence":
+      state.presence = {};
+      for (const device of message.devices) {
+        state.presence[device.type + "/" + device.name] = device.online;
+      }
+      renderDevices();
+      break;
+    case "error":
+      notice("Error: " + message.message);
+      break;
+  }
+}
+
+// ---------- 历史记录 ----------
+
+async function loadHistory(reset) {
+  let path = "/message/history?" + deviceQuery() + "&limit=" + HISTORY_PAGE;
+  if (!reset && state.nextCursor) {
+    path += "&before=" + state.nextCursor;
+  }
+
+  const page = await api(path);
+  state.clips = reset ? page.clips : state.clips.concat(page.clips);
+  state.nextCursor = page.next_cursor;
+  renderHistory();
+}
+
+// 按服务器序号从新到旧排列, 相同 id 的剪切板只保留最新的状态
+function addClip(clip) {
+  state.clips = state.clips.filter((item) => item.id !== clip.id);
+  state.clips.push(clip);
+  state.clips.sort((a, b) => b.seq - a.seq);
+  renderHistory();
+}
+
+function clipContent(clip) {
+  const node = document.createElement("div");
+  node.className = "clip-data";
+
+  if (clip.type === "Image" && !clip.sensitive) {
+    const img = document.createElement("img");
+    img.src = "data:image/png;base64," + clip.data;
+    node.appendChild(img);
+  } else {
+    node.textContent = clip.data;
+  }
+  return node;
+}
+
+// 历史列表里的敏感内容被隐藏了, 需要单独获取
+async function fullClip(clip) {
+  if (!clip.sensitive) {
+    return clip;
+  }
+  return api("/message/" + clip.id + "?" + deviceQuery());
+}
+
+function button(text, onclick) {
+  const node = document.createElement("button");
+  node.className = "link";
+  node.textContent = text;
+  node.onclick = onclick;
+  return node;
+}
+
+function renderHistory() {
+  const list = $("history");
+  list.replaceChildren();
+
+  for (const clip of state.clips) {
+    const item = document.createElement("li");
+    item.className = "clip" + (clip.pinned ? " pinned" : "");
+
+    const body = document.createElement("div");
+    body.className = "clip-body";
+    body.appendChild(clipContent(clip));
+
+    const meta = document.createElement("div");
+    meta.className = "clip-meta";
+    const source = clip.source ? clip.source.name + " (" + clip.source.type + ")" : "removed device";
+    const date = new Date(clip.received_at || clip.date).toLocaleString();
+    meta.textContent = source + " · " + date
+      + (clip.copy_count > 1 ? " · copied " + clip.copy_count + " times" : "")
+      + (clip.sensitive ? " · sensitive" : "");
+    body.appendChild(meta);
+    item.appendChild(body);
+
+    if (clip.type === "Text") {
+      item.appendChild(button("Copy", async () => {
+        const full = await fullClip(clip);
+        await navigator.clipboard.writeText(full.data);
+        notice("Copied.");
+      }));
+    }
+    if (clip.sensitive) {
+      item.appendChild(button("Reveal", async () => addClip(await fullClip(clip))));
+    }
+    item.appendChild(button(clip.pinned ? "Unpin" : "Pin", () => {
+      send({ type: "clip_update", id: clip.id, pinned: !clip.pinned });
+    }));
+    item.appendChild(button("Delete", () => send({ type: "clip_delete", id: clip.id })));
+
+    list.appendChild(item);
+  }
+
+  $("more").hidden = !state.nextCursor;
+}
+
+// ---------- 发送 ----------
+
+function selectedTargets() {
+  return Array.from(document.querySelectorAll("#target-list input:checked"))
+    .map((input) => Number(input.value));
+}
+
+function sendClip() {
+  const clip = state.image
+    ? { data: state.image, type: "Image", date: Date.now() }
+    : { data: $("compose").value, type: "Text", date: Date.now() };
+
+  if (!clip.data) {
+    notice("Nothing to send.");
+    return;
+  }
+
+  const targets = selectedTargets();
+  if (targets.length > 0) {
+    clip.targets = targets;
+  }
+
+  const id = uuid();
+  if (!send({ type: "clip_push", id, clip })) {
+    notice("Not connected.");
+    return;
+  }
+
+  state.pending.add(id);
+  $("compose").value = "";
+  setImage(null);
+  notice("Sent.");
+}
+
+async function pasteFromClipboard() {
+  try {
+    $("compose").value = await navigator.clipboard.readText();
+  } catch (err) {
+    notice("Clipboard access denied, paste with Ctrl+V instead.");
+  }
+}
+
+function setImage(data) {
+  state.image = data;
+  $("compose-image").hidden = !data;
+  $("compose-preview").src = data ? "data:image/png;base64," + data : "";
+}
+
+// 粘贴图片时转成 base64 发送
+function onPaste(event) {
+  const file = Array.from(event.clipboardData.items)
+    .find((item) => item.type.startsWith("image/"));
+  if (!file) {
+    return;
+  }
+
+  event.preventDefault();
+  const reader = new FileReader();
+  reader.onload = () => setImage(reader.result.split(",")[1]);
+  reader.readAsDataURL(file.getAsFile());
+}
+
+// ---------- 设备 ----------
+
+async function loadDevices() {
+  const user = await api("/user/devices?name=" + encodeURIComponent(state.user));
+  state.devices = user.devices;
+  renderDevices();
+}
+
+function isSelf(device) {
+  return device.name === state.device && device.type === DEVICE_TYPE;
+}
+
+function renderDevices() {
+  const list = $("devices");
+  const targets = $("target-list");
+  const checked = new Set(selectedTargets());
+  list.replaceChildren();
+  targets.replaceChildren();
+
+  for (const device of state.devices) {
+    const online = state.presence[device.type + "/" + device.name];
+
+    const item = document.createElement("li");
+    const name = document.createElement("span");
+    name.className = "device-name";
+    const dot = document.createElement("span");
+    dot.className = "dot" + (online ? " online" : "");
+    name.appendChild(dot);
+    name.appendChild(document.createTextNode(
+      device.name + " (" + device.type + ")" + (isSelf(device) ? " · this browser" : "")));
+    item.appendChild(name);
+    item.appendChild(button("Remove", async () => {
+      if (!confirm("Remove device " + device.name + "? It will need to register again.")) {
+        return;
+      }
+      await api("/user/deletedevice", { device: deviceBody(), device_id: device.id });
+      if (isSelf(device)) {
+        logout();
+      } else {
+        loadDevices();
+      }
+    }));
+    list.appendChild(item);
+
+    if (!isSelf(device)) {
+      const label = document.createElement("label");
+      label.className = "target";
+      const input = document.createElement("input");
+      input.type = "checkbox";
+      input.value = device.id;
+      input.checked = checked.has(device.id);
+      label.appendChild(input);
+      label.appendChild(document.createTextNode(" " + device.name));
+      targets.appendChild(label);
+    }
+  }
+}
+
+// ---------- 启动 ----------
+
+function init() {
+  $("login-form").onsubmit = async (event) => {
+    event.preventDefault();
+    const form = event.target;
+    try {
+      await login(form.user.value.trim(), form.device.value.trim());
+    } catch (err) {
+      $("login-error").textContent = err.message;
+    }
+  };
+  $("logout").onclick = logout;
+  $("send").onclick = sendClip;
+  $("paste").onclick = pasteFromClipboard;
+  $("compose").addEventListener("paste", onPaste);
+  $("compose-clear").onclick = () => setImage(null);
+  $("more").onclick = () => loadHistory(false);
+
+  const saved = JSON.parse(localStorage.getItem("connect-any") || "null");
+  if (saved) {
+    login(saved.user, saved.device).catch(() => {
+      $("login-view").hidden = false;
+    });
+  } else {
+    $("login-view").hidden = false;
+  }
+}
+
+init();